@@ -1,3 +1,7 @@
 fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+    // defmt needs its own linker script for the log string table.
+    if std::env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    }
 }
@@ -0,0 +1,286 @@
+// Higher level processing for analog mux channels. The mux driver only reports raw readings through its analog
+// callback; the types here turn those readings into musical events.
+
+use embassy_time::{Duration, Instant};
+
+/// Thresholds for one force-sensing resistor key, in raw ADC units.
+/// - `onset`: a reading at or above this triggers note-on.
+/// - `release`: a reading at or below this triggers note-off. Keep it below `onset` so the key doesn't chatter.
+/// - `full_velocity_slope`: the rise between two sweeps that maps to velocity 127.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnalogKeyCalibration {
+    pub onset: u16,
+    pub release: u16,
+    pub full_velocity_slope: u16,
+}
+
+impl AnalogKeyCalibration {
+    /// Reasonable starting point for an FSR in a 10k divider read by the 12 bit ADC.
+    pub const DEFAULT: Self = Self {
+        onset: 600,
+        release: 300,
+        full_velocity_slope: 1200,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalogKeyEvent {
+    NoteOn { velocity: u8 },
+    NoteOff,
+}
+
+/// A key with a force-sensing resistor under it, used for velocity-from-pressure "piano" mode.
+///
+/// The key triggers note-on when the pressure crosses `onset`, with a velocity proportional to how much the reading
+/// rose since the previous sweep. Velocity resolution is therefore limited by the scan rate: a sweep of 8 channels
+/// takes roughly 0.5ms plus the ADC conversions, and a hard strike reaches the onset within a couple of sweeps, so
+/// the slope is measured from only a handful of samples. Expect a usable but coarse velocity range rather than
+/// 127 distinct steps, and tune `full_velocity_slope` per key to taste.
+#[derive(Debug, Clone, Copy)]
+pub struct AnalogKey {
+    pub calibration: AnalogKeyCalibration,
+    pressed: bool,
+    last: u16, //The reading from the previous sweep.
+}
+
+impl AnalogKey {
+    pub const fn new(calibration: AnalogKeyCalibration) -> Self {
+        Self {
+            calibration,
+            pressed: false,
+            last: 0,
+        }
+    }
+
+    /// Feeds the next reading for this key and returns a note event if the key crossed a threshold.
+    pub fn update(&mut self, value: u16) -> Option<AnalogKeyEvent> {
+        let slope = value.saturating_sub(self.last);
+        self.last = value;
+        if !self.pressed && value >= self.calibration.onset {
+            self.pressed = true;
+            let full = self.calibration.full_velocity_slope.max(1) as u32;
+            let velocity = (slope as u32 * 127 / full).clamp(1, 127) as u8;
+            Some(AnalogKeyEvent::NoteOn { velocity })
+        } else if self.pressed && value <= self.calibration.release {
+            self.pressed = false;
+            Some(AnalogKeyEvent::NoteOff)
+        } else {
+            None
+        }
+    }
+}
+
+/// Settings for one piezo drum pad, raw ADC units and times.
+/// - `threshold`: a reading at or above this starts a hit. Set it above the noise and the pickup from other pads.
+/// - `full_scale`: the peak that maps to velocity 127.
+/// - `peak_window`: how long after crossing the threshold the peak is looked for.
+/// - `retrigger`: after a hit, readings are ignored for this long, so the pad ringing out or bouncing back is not
+///   heard as another hit. Counted from the start of the hit; the next hit also waits for the note-off.
+/// - `gate`: how long the note plays. Pads have no release, so the note-off follows after a fixed time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PiezoCalibration {
+    pub threshold: u16,
+    pub full_scale: u16,
+    pub peak_window: Duration,
+    pub retrigger: Duration,
+    pub gate: Duration,
+}
+
+impl PiezoCalibration {
+    /// A starting point for a 25mm piezo disc with a 1M resistor across it, read by the 12 bit ADC.
+    pub const DEFAULT: Self = Self {
+        threshold: 200,
+        full_scale: 3500,
+        peak_window: Duration::from_millis(2),
+        retrigger: Duration::from_millis(40),
+        gate: Duration::from_millis(50),
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PiezoState {
+    Idle,
+    Peaking { since: Instant, peak: u16 }, //Crossed the threshold at `since`, highest reading so far.
+    Sounding { since: Instant },           //Hit started at `since`, note on.
+    Lockout { until: Instant },            //Note off, still ignoring the pad until the retrigger time is over.
+}
+
+/// A piezo drum pad on an analog channel: a strike gives a voltage spike whose peak is the velocity.
+///
+/// Unlike a switch this isn't about when an edge comes but how high the spike goes. A strike peaks within about a
+/// millisecond and then rings down over tens of milliseconds, and the pad is only read once per mux sweep, so the
+/// highest reading in `peak_window` is the velocity: the sweep must read the pad several times in that window,
+/// i.e. every 0.5ms or faster (8 channels on the analog chip with the default settle time, no oversampling, and no
+/// idle wait between sweeps). Slower scans miss the top of sharp hits and play quieter and more unevenly; a peak hold
+/// circuit (a diode into a capacitor with a bleed resistor) in front of the ADC helps there.
+#[derive(Debug, Clone, Copy)]
+pub struct PiezoPad {
+    pub calibration: PiezoCalibration,
+    state: PiezoState,
+}
+
+impl PiezoPad {
+    pub const fn new(calibration: PiezoCalibration) -> Self {
+        Self { calibration, state: PiezoState::Idle }
+    }
+
+    /// Feeds the next reading for this pad. Returns the note-on once the peak window of a hit has passed, and the
+    /// note-off once its gate has.
+    pub fn update(&mut self, value: u16, now: Instant) -> Option<AnalogKeyEvent> {
+        let calibration = self.calibration;
+        match self.state {
+            PiezoState::Idle if value >= calibration.threshold => {
+                self.state = PiezoState::Peaking { since: now, peak: value };
+                None
+            }
+            PiezoState::Idle => None,
+            PiezoState::Peaking { since, peak } if now < since + calibration.peak_window => {
+                self.state = PiezoState::Peaking { since, peak: peak.max(value) };
+                None
+            }
+            PiezoState::Peaking { since, peak } => {
+                self.state = PiezoState::Sounding { since };
+                let range = calibration.full_scale.saturating_sub(calibration.threshold).max(1) as u32;
+                let above = peak.max(value).saturating_sub(calibration.threshold) as u32;
+                Some(AnalogKeyEvent::NoteOn { velocity: (above * 127 / range).clamp(1, 127) as u8 })
+            }
+            PiezoState::Sounding { since } if now >= since + calibration.peak_window + calibration.gate => {
+                self.state = PiezoState::Lockout { until: since + calibration.retrigger };
+                Some(AnalogKeyEvent::NoteOff)
+            }
+            PiezoState::Sounding { .. } => None,
+            PiezoState::Lockout { until } => {
+                if now >= until {
+                    self.state = PiezoState::Idle;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Full scale of a raw reading from the 12 bit ADC.
+pub const ADC_FULL_SCALE: u16 = 4095;
+
+/// Scales a raw reading to a 7 bit MIDI value. 0 maps to 0 and `full_scale` (or anything above) to 127.
+#[cfg(feature = "analog-keys")]
+pub fn to_7bit(raw: u16, full_scale: u16) -> u8 {
+    let full_scale = full_scale.max(1) as u32;
+    ((raw as u32).min(full_scale) * 127 / full_scale) as u8
+}
+
+/// Travel of an expression pedal (a pot on an analog channel), in raw ADC units.
+/// - `min`/`max`: the lowest and highest reading the pedal actually reaches.
+/// - `invert`: for pedals that read highest at the heel.
+/// - `deadzone`: readings this close to either end already count as fully up or down, so the pedal reliably reaches
+///   0 and 127 even when its pot drifts a little.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PedalCalibration {
+    pub min: u16,
+    pub max: u16,
+    pub invert: bool,
+    pub deadzone: u16,
+}
+
+impl PedalCalibration {
+    /// The whole ADC range, until the pedal has been calibrated.
+    pub const DEFAULT: Self = Self {
+        min: 0,
+        max: ADC_FULL_SCALE,
+        invert: false,
+        deadzone: 40,
+    };
+
+    /// Maps a raw reading across the calibrated travel to a 7 bit value, heel 0 and toe 127.
+    pub fn to_7bit(self, raw: u16) -> u8 {
+        let low = self.min.saturating_add(self.deadzone);
+        let high = self.max.saturating_sub(self.deadzone).max(low + 1);
+        let value = (raw.clamp(low, high) - low) as u32 * 127 / (high - low) as u32;
+        if self.invert {
+            127 - value as u8
+        } else {
+            value as u8
+        }
+    }
+}
+
+/// Finds an expression pedal's travel. Call `record` with every reading while the pedal is swept fully up and down a
+/// few times, then `apply` to the pedal's calibration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PedalRangeCalibration {
+    lowest: Option<u16>,
+    highest: Option<u16>,
+}
+
+impl PedalRangeCalibration {
+    pub const fn new() -> Self {
+        Self {
+            lowest: None,
+            highest: None,
+        }
+    }
+
+    pub fn record(&mut self, raw: u16) {
+        self.lowest = Some(self.lowest.map_or(raw, |lowest| lowest.min(raw)));
+        self.highest = Some(self.highest.map_or(raw, |highest| highest.max(raw)));
+    }
+
+    /// Writes the recorded travel into `pedal`, keeping its invert flag and deadzone. Leaves it unchanged if the pedal
+    /// didn't move further than the deadzones, which would leave no usable range.
+    pub fn apply(&self, pedal: &mut PedalCalibration) {
+        if let (Some(lowest), Some(highest)) = (self.lowest, self.highest) {
+            if highest.saturating_sub(lowest) > 2 * pedal.deadzone {
+                pedal.min = lowest;
+                pedal.max = highest;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(micros: u64) -> Instant {
+        Instant::from_micros(1_000_000 + micros)
+    }
+
+    fn note_on(event: Option<AnalogKeyEvent>) -> Option<u8> {
+        match event {
+            Some(AnalogKeyEvent::NoteOn { velocity }) => Some(velocity),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn a_hit_plays_its_peak() {
+        let mut pad = PiezoPad::new(PiezoCalibration::DEFAULT);
+        assert_eq!(pad.update(100, at(0)), None, "below the threshold");
+        assert_eq!(pad.update(300, at(100)), None);
+        assert_eq!(pad.update(2000, at(600)), None);
+        assert_eq!(pad.update(1500, at(1100)), None);
+        // The peak window is over: the highest reading in it, not the last, is the velocity.
+        assert_eq!(note_on(pad.update(800, at(2100))), Some(((2000 - 200) * 127 / 3300) as u8));
+        let full_scale = PiezoCalibration::DEFAULT.full_scale;
+        let mut pad = PiezoPad::new(PiezoCalibration::DEFAULT);
+        pad.update(4095, at(0));
+        assert_eq!(note_on(pad.update(full_scale, at(2000))), Some(127));
+    }
+
+    #[test]
+    fn ringing_inside_the_retrigger_time_is_not_a_hit() {
+        let calibration = PiezoCalibration { gate: Duration::from_millis(10), ..PiezoCalibration::DEFAULT };
+        let mut pad = PiezoPad::new(calibration);
+        pad.update(3000, at(0));
+        assert!(note_on(pad.update(3000, at(2000))).is_some());
+        assert_eq!(pad.update(0, at(12_000)), Some(AnalogKeyEvent::NoteOff));
+        // Until 40ms after the hit started, even a hard reading is ignored.
+        for millis in [15, 20, 30, 37] {
+            assert_eq!(pad.update(3500, at(millis * 1000)), None);
+            assert_eq!(pad.update(3500, at(millis * 1000 + 2000)), None);
+        }
+        pad.update(0, at(40_000));
+        pad.update(3000, at(41_000));
+        assert!(note_on(pad.update(3000, at(43_000))).is_some(), "a hit after it plays");
+    }
+}
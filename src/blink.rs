@@ -0,0 +1,125 @@
+// Octave LED blink timing, kept apart from the LEDs themselves so it can be tested on the host. The main loop ticks
+// every 1ms: the up LED blinks above the home octave, the down LED below it, faster the further away. A blink is lit for
+// one period, then dark for one, and its level is a triangle peaking halfway through the lit ticks.
+//
+// Example, every main loop tick:
+//    let period = blink::blink_period(octave, home, min_octave, max_octave);
+//    let (up, down, timers) = blink::led_phase(octave, home, period, up_timer, down_timer);
+//    (up_timer, down_timer) = timers;
+
+/// Number of 1ms main loop ticks between LED toggles for an octave. One octave from home blinks every 350 ticks, the
+/// edge of the range every 200, scaled to the configured range.
+pub fn blink_period(octave: i32, home: i32, min_octave: i32, max_octave: i32) -> i32 {
+    let distance = (octave - home).abs();
+    let span = if octave > home { max_octave - home } else { home - min_octave };
+    if span == 0 {
+        return 400;
+    }
+    400 - (200 * distance) / span
+}
+
+/// The ticks a blink of `period` stays lit.
+pub fn lit_ticks(period: i32) -> i32 {
+    period.max(1) + 1
+}
+
+/// One tick of the octave LEDs: the up and down LED levels, and the timers for the next tick. Only the LED that blinks
+/// advances its timer, the other is left where it was.
+pub fn led_phase(octave: i32, home: i32, period: i32, up_timer: i32, down_timer: i32) -> (u8, u8, (i32, i32)) {
+    let lit_ticks = lit_ticks(period);
+    if octave > home {
+        let up_timer = (up_timer + 1) % (2 * lit_ticks);
+        (pulse_level(up_timer, lit_ticks), 0, (up_timer, down_timer))
+    } else if octave < home {
+        let down_timer = (down_timer + 1) % (2 * lit_ticks);
+        (0, pulse_level(down_timer, lit_ticks), (up_timer, down_timer))
+    } else {
+        (0, 0, (up_timer, down_timer))
+    }
+}
+
+// The LED level `tick` ticks into a blink: a triangle peaking halfway through the lit ticks, dark after them. Never 0
+// while lit, so a plain LED is on for all of them.
+fn pulse_level(tick: i32, lit_ticks: i32) -> u8 {
+    if tick >= lit_ticks {
+        return 0;
+    }
+    let from_peak = (2 * tick - lit_ticks).abs();
+    (255 * (lit_ticks - from_peak) / lit_ticks).clamp(1, 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOME: i32 = 4;
+
+    // How often the LEDs light up over `ticks` ticks at `octave`, counting dark to lit changes: (up, down).
+    fn blinks(octave: i32, ticks: usize) -> (usize, usize) {
+        let period = blink_period(octave, HOME, 0, 8);
+        let (mut timers, mut last) = ((0, 0), (0, 0));
+        let mut count = (0, 0);
+        for _ in 0..ticks {
+            let (up, down, next) = led_phase(octave, HOME, period, timers.0, timers.1);
+            count.0 += (last.0 == 0 && up > 0) as usize;
+            count.1 += (last.1 == 0 && down > 0) as usize;
+            (timers, last) = (next, (up, down));
+        }
+        count
+    }
+
+    #[test]
+    fn home_octave_is_dark() {
+        let period = blink_period(HOME, HOME, 0, 8);
+        let mut timers = (0, 0);
+        for _ in 0..2000 {
+            let (up, down, next) = led_phase(HOME, HOME, period, timers.0, timers.1);
+            assert_eq!((up, down), (0, 0));
+            timers = next;
+        }
+    }
+
+    #[test]
+    fn higher_octaves_blink_faster() {
+        assert!(blink_period(5, HOME, 0, 8) > blink_period(6, HOME, 0, 8));
+        assert!(blink_period(6, HOME, 0, 8) > blink_period(8, HOME, 0, 8));
+        let (five, six, eight) = (blinks(5, 10_000).0, blinks(6, 10_000).0, blinks(8, 10_000).0);
+        assert!(five < six && six < eight, "{five} {six} {eight}");
+    }
+
+    #[test]
+    fn lower_octaves_blink_faster() {
+        let (three, two, zero) = (blinks(3, 10_000).1, blinks(2, 10_000).1, blinks(0, 10_000).1);
+        assert!(three < two && two < zero, "{three} {two} {zero}");
+    }
+
+    #[test]
+    fn only_the_led_on_the_octaves_side_blinks() {
+        assert_eq!(blinks(6, 2000).1, 0);
+        assert_eq!(blinks(2, 2000).0, 0);
+    }
+
+    #[test]
+    fn range_ends_match_the_documented_periods() {
+        assert_eq!(blink_period(5, HOME, 0, 8), 350);
+        assert_eq!(blink_period(8, HOME, 0, 8), 200);
+        assert_eq!(blink_period(0, HOME, 0, 8), 200);
+        assert_eq!(blink_period(5, HOME, 4, 4), 400);
+    }
+
+    #[test]
+    fn lit_for_one_period_then_dark_for_one() {
+        let period = 10;
+        let mut timers = (0, 0);
+        let mut levels = [0u8; 21];
+        for level in levels.iter_mut() {
+            let (up, _, next) = led_phase(5, HOME, period, timers.0, timers.1);
+            *level = up;
+            timers = next;
+        }
+        // The timer runs 1..=10 lit (of 0..=10), then 11..=21 dark.
+        assert!(levels[..10].iter().all(|&level| level > 0));
+        assert!(levels[10..].iter().all(|&level| level == 0));
+        assert!(levels[4] > levels[0] && levels[4] > levels[9]);
+    }
+}
@@ -0,0 +1,66 @@
+// Chord memory ("one-finger chords"), kept apart from the global state so it can be tested on the host. A chord is
+// learned from the notes held at the time as their intervals above the lowest one, and played from any key as that
+// key's note plus the same intervals.
+
+use heapless::Vec;
+
+/// Semitone intervals above the pressed note for chord memory, e.g. [4, 7] for a major triad.
+pub type Chord = Vec<i8, 4>;
+
+pub const NO_CHORD: Chord = Vec::new();
+
+/// The intervals of `notes` above the lowest of them, or None without any notes. Up to 4 intervals, the rest are
+/// dropped.
+pub fn template(notes: impl Iterator<Item = i32> + Clone) -> Option<Chord> {
+    let root = notes.clone().min()?;
+    let mut chord = NO_CHORD;
+    for note in notes.filter(|&note| note != root) {
+        chord.push((note - root) as i8).ok();
+    }
+    Some(chord)
+}
+
+/// The notes `chord` plays from `root`: the root, then every interval above it. Chord notes outside the MIDI note range
+/// are skipped.
+pub fn voicing(root: i32, chord: &Chord) -> Vec<i32, 5> {
+    let mut notes: Vec<i32, 5> = Vec::new();
+    notes.push(root).ok();
+    for &interval in chord.iter() {
+        let note = root + interval as i32;
+        if (0..=127).contains(&note) {
+            notes.push(note).ok();
+        }
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learns_the_intervals_above_the_lowest_note() {
+        // E major, held in any order.
+        let chord = template([68, 64, 71].into_iter()).unwrap();
+        assert_eq!(chord.as_slice(), &[4, 7]);
+        assert_eq!(template([60].into_iter()).unwrap().as_slice(), &[] as &[i8]);
+        assert_eq!(template(core::iter::empty()), None);
+        // Octave doublings are kept, past 4 intervals the rest is dropped.
+        let chord = template([48, 52, 55, 60, 64, 67].into_iter()).unwrap();
+        assert_eq!(chord.as_slice(), &[4, 7, 12, 16]);
+    }
+
+    #[test]
+    fn plays_the_learned_chord_from_any_key() {
+        let chord = template([60, 64, 67].into_iter()).unwrap();
+        assert_eq!(voicing(62, &chord).as_slice(), &[62, 66, 69]); // D major.
+        assert_eq!(voicing(45, &chord).as_slice(), &[45, 49, 52]);
+        assert_eq!(voicing(62, &NO_CHORD).as_slice(), &[62]);
+    }
+
+    #[test]
+    fn chord_notes_past_the_midi_range_are_skipped() {
+        let chord = template([60, 64, 67].into_iter()).unwrap();
+        assert_eq!(voicing(122, &chord).as_slice(), &[122, 126]);
+    }
+}
@@ -0,0 +1,330 @@
+// Compile-time helpers for writing the KEYS table in `main`. `keys!` takes one entry per mux channel, either a key
+// function or a note name, and expands to the same `[KeyFunction; N]` table written out by hand:
+//    const KEYS: [KeyFunction; 5] = config::keys![OctaveUp, "C", "C#", "C1", Transport(TransportMsg::Start)];
+// is
+//    const KEYS: [KeyFunction; 5] = [KeyFunction::OctaveUp, KeyFunction::Note(0), KeyFunction::Note(1),
+//                                    KeyFunction::Note(12), KeyFunction::Transport(TransportMsg::Start)];
+//Note names are relative to the keybed, not MIDI note names: "C" (or "C0") is the lowest key, which plays the C of the
+//current octave, and the digit counts octaves up the keybed from there, so on a 25 key board the top key is "C2". Sharps
+//and flats are both accepted ("C#1", "Db1"). A name that isn't a note fails the build.
+//
+// `ControllerConfig` is every setting that can change at run time, in one compact binary layout shared by the flash
+// slot and the SysEx dump. Every byte is 7 bit so it can go into SysEx as is. The layout, in order:
+//    version          1 byte, `VERSION`.
+//    octave limits    2 bytes: lowest and highest octave, each + 64.
+//    debounce         1 byte: debounce interval in milliseconds, up to 127.
+//    velocity curve   1 byte: 0 linear, 1 soft, 2 hard.
+//    channel          1 byte: the unsplit channel, 0..=15.
+//    key map          NUM_KEYS bytes: the mux channel of each note key, lowest note first.
+//    zone count       1 byte, up to MAX_ZONES, then per zone 7 bytes: first key, last key, channel, transpose + 64,
+//                     fixed velocity (0 for none), own octave + 1 (0 for none), enabled (0 or 1).
+//A later version only ever appends fields, so `from_bytes` reads the fields it knows from any version and ignores the
+//rest: an older firmware can still load a newer firmware's config, minus the new settings.
+
+use heapless::Vec;
+use midi_convert::midi_types::Channel;
+
+use crate::{velocity, Zone, NUM_KEYS};
+
+/// The note key index of a keybed note name, see the top of this file. Panics (at compile time in a const) on anything
+/// else.
+pub const fn note_index(name: &str) -> u8 {
+    let bytes = name.as_bytes();
+    assert!(!bytes.is_empty(), "empty note name");
+    let mut semitone: i32 = match bytes[0] {
+        b'C' => 0,
+        b'D' => 2,
+        b'E' => 4,
+        b'F' => 5,
+        b'G' => 7,
+        b'A' => 9,
+        b'B' => 11,
+        _ => panic!("note names start with A to G"),
+    };
+    let mut rest = 1;
+    if bytes.len() > 1 && bytes[1] == b'#' {
+        semitone += 1;
+        rest = 2;
+    } else if bytes.len() > 1 && bytes[1] == b'b' {
+        semitone -= 1;
+        rest = 2;
+    }
+    let mut octave: i32 = 0;
+    while rest < bytes.len() {
+        assert!(bytes[rest].is_ascii_digit(), "note names end in an octave number");
+        octave = octave * 10 + (bytes[rest] - b'0') as i32;
+        rest += 1;
+    }
+    let index = octave * 12 + semitone;
+    assert!(index >= 0 && index < 256, "note below the keybed"); // "Cb" on its own.
+    index as u8
+}
+
+/// One KEYS entry: a note name, or a `KeyFunction` variant with its arguments (in parentheses or braces) if it has
+/// any. Used by `keys!`.
+macro_rules! key_entry {
+    ($name:literal) => {
+        $crate::KeyFunction::Note($crate::config::note_index($name))
+    };
+    ($function:ident $($arguments:tt)?) => {
+        $crate::KeyFunction::$function $($arguments)?
+    };
+}
+
+/// The KEYS table from note names and key functions, see the top of this file.
+macro_rules! keys {
+    ($($entry:tt $(($($arguments:tt)*))? $({$($fields:tt)*})?),* $(,)?) => {
+        [$($crate::config::key_entry!($entry $(($($arguments)*))? $({$($fields)*})?)),*]
+    };
+}
+
+pub(crate) use key_entry;
+pub(crate) use keys;
+
+// The names resolve to the same indices as a hand-written table.
+const _: () = {
+    assert!(note_index("C") == 0);
+    assert!(note_index("C0") == 0);
+    assert!(note_index("C#") == 1);
+    assert!(note_index("Db") == 1);
+    assert!(note_index("B") == 11);
+    assert!(note_index("C1") == 12);
+    assert!(note_index("F#1") == 18);
+    assert!(note_index("C2") == 24);
+};
+
+/// The layout version `to_bytes` writes.
+pub const VERSION: u8 = 1;
+
+/// The most zones a config holds, as many as the global state has.
+pub const MAX_ZONES: usize = 4;
+
+const ZONE_LEN: usize = 7;
+
+/// The length of a config with every zone, the most `to_bytes` writes.
+pub const MAX_LEN: usize = 7 + NUM_KEYS + MAX_ZONES * ZONE_LEN;
+
+/// Why `to_bytes` or `from_bytes` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    TooShort, // The buffer ends before the last field this version knows.
+    Version,  // Version 0, which no firmware writes.
+    BadValue, // A value out of range, or a key map that doesn't fit this build's KEYS.
+}
+
+/// Every setting that can change at run time, see the top of this file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerConfig {
+    pub min_octave: i32,
+    pub max_octave: i32,
+    pub debounce_ms: u8,
+    pub velocity_curve: velocity::Curve,
+    pub channel: u8, // 0..=15.
+    pub key_channels: [u8; NUM_KEYS], // The mux channel of each note key, as saved by key learn.
+    pub zones: Vec<Zone, MAX_ZONES>,
+}
+
+impl ControllerConfig {
+    /// Writes the config into `buf` and returns its length. `buf` must hold `MAX_LEN` bytes, or as many as the zones
+    /// in use need.
+    pub fn to_bytes(&self, buf: &mut [u8]) -> Result<usize, ConfigError> {
+        let len = 7 + NUM_KEYS + self.zones.len() * ZONE_LEN;
+        if buf.len() < len {
+            return Err(ConfigError::TooShort);
+        }
+        let curve = match self.velocity_curve {
+            velocity::Curve::Linear => 0,
+            velocity::Curve::Soft => 1,
+            velocity::Curve::Hard => 2,
+        };
+        buf[..6].copy_from_slice(&[
+            VERSION,
+            (self.min_octave + 64) as u8,
+            (self.max_octave + 64) as u8,
+            self.debounce_ms.min(127),
+            curve,
+            self.channel.min(15),
+        ]);
+        buf[6..6 + NUM_KEYS].copy_from_slice(&self.key_channels);
+        buf[6 + NUM_KEYS] = self.zones.len() as u8;
+        for (zone, bytes) in self.zones.iter().zip(buf[7 + NUM_KEYS..len].chunks_mut(ZONE_LEN)) {
+            bytes.copy_from_slice(&[
+                zone.first_key,
+                zone.last_key,
+                u8::from(zone.channel),
+                (zone.transpose as i32 + 64).clamp(0, 127) as u8,
+                zone.velocity.map_or(0, |velocity| velocity.clamp(1, 127)),
+                zone.octave.map_or(0, |octave| octave as u8 + 1),
+                zone.enabled as u8,
+            ]);
+        }
+        Ok(len)
+    }
+
+    /// Reads a config written by `to_bytes`, of this or any later version. Every value is checked; nothing is
+    /// returned unless all of them are valid.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConfigError> {
+        if bytes.len() < 7 + NUM_KEYS {
+            return Err(ConfigError::TooShort);
+        }
+        if bytes[0] == 0 {
+            return Err(ConfigError::Version);
+        }
+        let (min_octave, max_octave) = (bytes[1] as i32 - 64, bytes[2] as i32 - 64);
+        if !(0..=10).contains(&min_octave) || !(min_octave..=10).contains(&max_octave) {
+            return Err(ConfigError::BadValue);
+        }
+        let velocity_curve = match bytes[4] {
+            0 => velocity::Curve::Linear,
+            1 => velocity::Curve::Soft,
+            2 => velocity::Curve::Hard,
+            _ => return Err(ConfigError::BadValue),
+        };
+        if bytes[3] > 127 || bytes[5] > 15 {
+            return Err(ConfigError::BadValue);
+        }
+        let mut key_channels = [0u8; NUM_KEYS];
+        key_channels.copy_from_slice(&bytes[6..6 + NUM_KEYS]);
+        if crate::key_map_from_channels(&key_channels).is_none() {
+            return Err(ConfigError::BadValue);
+        }
+        let zone_count = bytes[6 + NUM_KEYS] as usize;
+        if zone_count > MAX_ZONES {
+            return Err(ConfigError::BadValue);
+        }
+        let zone_bytes = bytes.get(7 + NUM_KEYS..7 + NUM_KEYS + zone_count * ZONE_LEN).ok_or(ConfigError::TooShort)?;
+        let mut zones: Vec<Zone, MAX_ZONES> = Vec::new();
+        for zone in zone_bytes.chunks(ZONE_LEN) {
+            let &[first_key, last_key, channel, transpose, velocity, octave, enabled] = zone else {
+                return Err(ConfigError::TooShort);
+            };
+            if first_key > last_key || last_key as usize >= NUM_KEYS || channel > 15 || transpose > 127 {
+                return Err(ConfigError::BadValue);
+            }
+            if velocity > 127 || octave > 11 || enabled > 1 {
+                return Err(ConfigError::BadValue);
+            }
+            zones
+                .push(Zone {
+                    first_key,
+                    last_key,
+                    channel: Channel::from(channel),
+                    transpose: (transpose as i32 - 64) as i8,
+                    velocity: (velocity > 0).then_some(velocity),
+                    octave: (octave > 0).then(|| octave as i32 - 1),
+                    enabled: enabled == 1,
+                })
+                .ok();
+        }
+        // Anything after the zones is from a later version.
+        Ok(Self {
+            min_octave,
+            max_octave,
+            debounce_ms: bytes[3],
+            velocity_curve,
+            channel: bytes[5],
+            key_channels,
+            zones,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ControllerConfig {
+        let mut zones = Vec::new();
+        let bass = Zone {
+            first_key: 0,
+            last_key: 11,
+            channel: Channel::C2,
+            transpose: -12,
+            velocity: Some(100),
+            octave: Some(2),
+            enabled: true,
+        };
+        let lead = Zone {
+            first_key: 12,
+            last_key: 24,
+            channel: Channel::C3,
+            transpose: 5,
+            velocity: None,
+            octave: None,
+            enabled: false,
+        };
+        zones.push(bass).unwrap();
+        zones.push(lead).unwrap();
+        ControllerConfig {
+            min_octave: 1,
+            max_octave: 7,
+            debounce_ms: 12,
+            velocity_curve: velocity::Curve::Soft,
+            channel: 9,
+            key_channels: crate::key_map_channels(&crate::DEFAULT_KEY_MAP),
+            zones,
+        }
+    }
+
+    fn bytes(config: &ControllerConfig) -> Vec<u8, { MAX_LEN + 4 }> {
+        let mut buf = [0u8; MAX_LEN];
+        let len = config.to_bytes(&mut buf).unwrap();
+        Vec::from_slice(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn keys_expands_to_the_table_written_out() {
+        use crate::{KeyFunction, TransportMsg};
+        const KEYS: [KeyFunction; 5] = keys![OctaveUp, "C", "C#", "C1", Transport(TransportMsg::Start)];
+        let written_out = [
+            KeyFunction::OctaveUp,
+            KeyFunction::Note(0),
+            KeyFunction::Note(1),
+            KeyFunction::Note(12),
+            KeyFunction::Transport(TransportMsg::Start),
+        ];
+        assert_eq!(KEYS, written_out);
+        // Arguments in braces, flats and sharps.
+        let keys = keys![PatchSelect { bank_msb: 1, bank_lsb: 2, program: 3 }, SetOctave(4), "Db1", "B1"];
+        let written_out = [
+            KeyFunction::PatchSelect { bank_msb: 1, bank_lsb: 2, program: 3 },
+            KeyFunction::SetOctave(4),
+            KeyFunction::Note(13),
+            KeyFunction::Note(23),
+        ];
+        assert_eq!(keys, written_out);
+    }
+
+    #[test]
+    fn round_trip_with_zones() {
+        let bytes = bytes(&config());
+        assert_eq!(bytes.len(), 7 + NUM_KEYS + 2 * ZONE_LEN);
+        assert!(bytes.iter().all(|&byte| byte <= 127), "every byte fits SysEx");
+        assert_eq!(ControllerConfig::from_bytes(&bytes), Ok(config()));
+    }
+
+    #[test]
+    fn fields_of_a_later_version_are_ignored() {
+        let mut bytes = bytes(&config());
+        bytes[0] = VERSION + 1;
+        bytes.extend_from_slice(&[0x11, 0x22, 0x33]).unwrap();
+        assert_eq!(ControllerConfig::from_bytes(&bytes), Ok(config()));
+    }
+
+    #[test]
+    fn version_0_is_rejected() {
+        let mut bytes = bytes(&config());
+        bytes[0] = 0;
+        assert_eq!(ControllerConfig::from_bytes(&bytes), Err(ConfigError::Version));
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let bytes = bytes(&config());
+        // Cut inside the zones, and before the zone count.
+        assert_eq!(ControllerConfig::from_bytes(&bytes[..bytes.len() - 1]), Err(ConfigError::TooShort));
+        assert_eq!(ControllerConfig::from_bytes(&bytes[..6 + NUM_KEYS]), Err(ConfigError::TooShort));
+        let mut buf = [0u8; MAX_LEN];
+        assert_eq!(config().to_bytes(&mut buf[..bytes.len() - 1]), Err(ConfigError::TooShort));
+    }
+}
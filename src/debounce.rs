@@ -0,0 +1,286 @@
+// Debounce and edge detection shared by the input drivers (mux, shift registers, touch pads), kept apart from the pins
+// so it can be tested on the host. Readings go in once per sweep, accepted edges come out.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::scan::CHANNELS;
+
+const SELF_TEST_SWEEPS: usize = 4; //Number of full sweeps the power-on self-test reads before reporting stuck channels.
+
+/// How a raw reading is accepted as a new stable state.
+/// - `TimeLockout`: accept a change only once `debounce_interval` has elapsed since the last accepted change (default).
+/// - `Integrator`: accept a change only after `threshold` consecutive reads agree on the new state. More robust for noisy switches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DebounceMode {
+    #[default]
+    TimeLockout,
+    Integrator { threshold: u8 },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SwitchState {
+    High,
+    Low,
+}
+
+/// Debounce and edge detection for up to `CH` switches (64 by default), shared by the input drivers.
+/// Feed it one raw reading per channel per sweep with `update`; it returns the new state when an edge is accepted.
+pub struct Debouncer<const CH: usize = CHANNELS> {
+    states: Vec<SwitchState, CH>, //The stable state of all channels.
+    last_change: [Instant; CH], //The last time each channel changed state.
+    interval: Duration, //The debounce interval after a press, for all channels.
+    release_interval: Duration, //The debounce interval after a release, for all channels.
+    mode: DebounceMode, //The debounce algorithm used for all channels.
+    integrator: [u8; CH], //Consecutive reads that disagreed with the stable state, per channel. Only used in Integrator mode.
+    #[cfg(feature = "bounce-stats")]
+    bounce_count: [u32; CH], //Changes rejected by the debounce, per channel.
+    stuck: [bool; CH], //Channels found pressed by the self-test. They report no edges until released.
+    min_press: Option<Duration>, //Presses shorter than this report no edges at all. Off by default.
+    unconfirmed: [bool; CH], //Presses accepted by the debounce but not yet held for min_press.
+}
+
+/// A starting point for `Debouncer::set_min_press`: long enough to hide the ghost taps of a flaky membrane switch,
+/// short enough not to be felt when playing.
+pub const DEFAULT_MIN_PRESS: Duration = Duration::from_millis(2);
+
+impl<const CH: usize> Debouncer<CH> {
+    pub fn new() -> Self {
+        // Initialize the stable state for all channels.
+        let mut states: Vec<SwitchState, CH> = Vec::new();
+        for _ in 0..CH {
+            states.push(SwitchState::High).ok();
+        }
+        debug_assert!(states.len() == CH);
+        // Default debounce interval is 20ms.
+        let interval = Duration::from_millis(20);
+        let now = Instant::now();
+        // Initialize each channel's last-change timestamp to allow immediate changes, even right after boot.
+        let last_change = [now.checked_sub(interval).unwrap_or(Instant::MIN); CH];
+
+        Self {
+            states,
+            last_change,
+            interval,
+            release_interval: interval,
+            mode: DebounceMode::default(),
+            integrator: [0; CH],
+            #[cfg(feature = "bounce-stats")]
+            bounce_count: [0; CH],
+            stuck: [false; CH],
+            min_press: None,
+            unconfirmed: [false; CH],
+        }
+    }
+
+    /// Sets the debounce interval after both presses and releases.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        self.release_interval = interval;
+    }
+
+    /// Sets the press and release debounce intervals separately, for switches that bounce differently on make and on
+    /// break. In TimeLockout mode `press` is how long a channel ignores changes after a press was accepted, `release`
+    /// after a release was accepted, e.g. a short press interval and a longer release one to hide lift-off chatter.
+    /// Integrator mode ignores both.
+    pub fn set_intervals(&mut self, press: Duration, release: Duration) {
+        self.interval = press;
+        self.release_interval = release;
+    }
+
+    /// Changes the debounce algorithm. Resets any partially integrated reads.
+    pub fn set_mode(&mut self, mode: DebounceMode) {
+        self.mode = mode;
+        self.integrator = [0; CH];
+    }
+
+    /// Ignores presses that release again within `min_press` (None turns it off). The falling edge is held back until the
+    /// channel has been pressed that long, so every press is reported that much later; a shorter press reports neither
+    /// edge. Works on top of either debounce algorithm.
+    pub fn set_min_press(&mut self, min_press: Option<Duration>) {
+        self.min_press = min_press;
+        self.unconfirmed = [false; CH];
+    }
+
+    /// The debounced state of every channel.
+    pub fn states(&self) -> &[SwitchState] {
+        &self.states
+    }
+
+    /// Returns true if the debounced state of the channel is low (pressed). Out of range indices read as not pressed.
+    pub fn is_pressed(&self, index: usize) -> bool {
+        self.states.get(index) == Some(&SwitchState::Low)
+    }
+
+    /// Clears `buf` and fills it with the indices of all currently pressed channels. Stops early if `buf` is full.
+    pub fn pressed_indices<const N: usize>(&self, buf: &mut Vec<usize, N>) {
+        buf.clear();
+        for (index, &state) in self.states.iter().enumerate() {
+            if state == SwitchState::Low && buf.push(index).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Sweeps a self-test needs for either debounce algorithm to settle on the real state.
+    pub fn settle_sweeps(&self) -> usize {
+        match self.mode {
+            DebounceMode::TimeLockout => SELF_TEST_SWEEPS,
+            DebounceMode::Integrator { threshold } => SELF_TEST_SWEEPS.max(threshold as usize + 1),
+        }
+    }
+
+    /// Flags a channel as stuck: it reports no edges until it has been released once. A press it had waiting for
+    /// min_press is dropped too.
+    pub fn mark_stuck(&mut self, index: usize) {
+        if let Some(stuck) = self.stuck.get_mut(index) {
+            *stuck = true;
+            self.unconfirmed[index] = false;
+        }
+    }
+
+    /// Debounces one raw reading (true if pressed) and returns the new stable state if the channel changed and should
+    /// fire its edge callback. Channels past `CH` are ignored.
+    pub fn update(&mut self, index: usize, reading: bool) -> Option<SwitchState> {
+        self.update_at(index, reading, Instant::now())
+    }
+
+    /// `update` for a reading taken at `now`.
+    pub fn update_at(&mut self, index: usize, reading: bool, now: Instant) -> Option<SwitchState> {
+        if index >= self.states.len() {
+            return None; // No state for this channel, e.g. a chip beyond the supported count.
+        }
+        let current_state = self.states[index];
+        // Map the raw reading into our stable state.
+        // (true means the input is low/pressed → Low state;
+        //  false means not pressed → High state)
+        let expected_state = if reading { SwitchState::Low } else { SwitchState::High };
+
+        let accept = match self.mode {
+            DebounceMode::TimeLockout => {
+                // Only accept the change if the debounce interval has elapsed.
+                let changed = current_state != expected_state;
+                let lockout = match current_state {
+                    SwitchState::Low => self.interval, // Last accepted edge was a press.
+                    SwitchState::High => self.release_interval,
+                };
+                let accept = changed && now.duration_since(self.last_change[index]) >= lockout;
+                #[cfg(feature = "bounce-stats")]
+                if changed && !accept {
+                    self.bounce_count[index] = self.bounce_count[index].saturating_add(1);
+                }
+                accept
+            }
+            DebounceMode::Integrator { threshold } => {
+                if current_state == expected_state {
+                    // Any agreeing read restarts the count. An unfinished count was a bounce.
+                    #[cfg(feature = "bounce-stats")]
+                    if self.integrator[index] > 0 {
+                        self.bounce_count[index] = self.bounce_count[index].saturating_add(1);
+                    }
+                    self.integrator[index] = 0;
+                    false
+                } else {
+                    self.integrator[index] = self.integrator[index].saturating_add(1);
+                    self.integrator[index] >= threshold
+                }
+            }
+        };
+
+        if !accept {
+            // A held back press is reported once it has been held for the minimum time.
+            let min_press = self.min_press.unwrap_or_default();
+            if self.unconfirmed[index] && reading && now.duration_since(self.last_change[index]) >= min_press {
+                self.unconfirmed[index] = false;
+                return Some(SwitchState::Low);
+            }
+            return None;
+        }
+        self.states[index] = expected_state;
+        self.last_change[index] = now;
+        self.integrator[index] = 0;
+        if self.stuck[index] {
+            // A stuck channel stays silent until it releases, then behaves normally.
+            if expected_state == SwitchState::High {
+                self.stuck[index] = false;
+            }
+            return None;
+        }
+        match expected_state {
+            SwitchState::Low if self.min_press.is_some() => {
+                self.unconfirmed[index] = true;
+                None
+            }
+            // Released before the press was reported: a ghost tap, so no release either.
+            SwitchState::High if self.unconfirmed[index] => {
+                self.unconfirmed[index] = false;
+                None
+            }
+            _ => Some(expected_state),
+        }
+    }
+
+    /// How many state changes were rejected on each channel. Needs the "bounce-stats" feature.
+    #[cfg(feature = "bounce-stats")]
+    pub fn bounce_stats(&self) -> &[u32; CH] {
+        &self.bounce_count
+    }
+
+    #[cfg(feature = "bounce-stats")]
+    pub fn reset_bounce_stats(&mut self) {
+        self.bounce_count = [0; CH];
+    }
+}
+
+impl<const CH: usize> Default for Debouncer<CH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(1000 + millis)
+    }
+
+    #[test]
+    fn press_and_release_have_their_own_interval() {
+        let mut debouncer: Debouncer<8> = Debouncer::new();
+        debouncer.set_intervals(Duration::from_millis(5), Duration::from_millis(30));
+        assert_eq!(debouncer.update_at(0, true, at(0)), Some(SwitchState::Low));
+        // 5ms after a press before a release counts.
+        assert_eq!(debouncer.update_at(0, false, at(4)), None);
+        assert_eq!(debouncer.update_at(0, false, at(5)), Some(SwitchState::High));
+        // 30ms after a release before the next press counts.
+        assert_eq!(debouncer.update_at(0, true, at(34)), None);
+        assert_eq!(debouncer.update_at(0, true, at(35)), Some(SwitchState::Low));
+        assert!(debouncer.is_pressed(0));
+    }
+
+    #[test]
+    fn lift_off_chatter_is_one_release() {
+        let mut debouncer: Debouncer<8> = Debouncer::new();
+        debouncer.set_intervals(Duration::from_millis(5), Duration::from_millis(30));
+        debouncer.update_at(3, true, at(0));
+        let edges = [(20, false), (21, true), (23, false), (26, true), (28, false), (40, false)]
+            .iter()
+            .filter_map(|&(millis, reading)| debouncer.update_at(3, reading, at(millis)))
+            .count();
+        assert_eq!(edges, 1);
+        assert!(!debouncer.is_pressed(3));
+    }
+
+    #[test]
+    fn one_interval_sets_both() {
+        let mut debouncer: Debouncer<8> = Debouncer::new();
+        debouncer.set_intervals(Duration::from_millis(5), Duration::from_millis(30));
+        debouncer.set_interval(Duration::from_millis(10));
+        debouncer.update_at(0, true, at(0));
+        assert_eq!(debouncer.update_at(0, false, at(10)), Some(SwitchState::High));
+        assert_eq!(debouncer.update_at(0, true, at(19)), None);
+        assert_eq!(debouncer.update_at(0, true, at(20)), Some(SwitchState::Low));
+    }
+}
@@ -0,0 +1,82 @@
+// SSD1306 128x64 OLED showing the controller state, with the "display" feature. Wiring: SDA to D6/GPIO43, SCL to
+// D7/GPIO44, plus 3V3 and GND. Most modules have their own I2C pull-ups; add 4.7k ones to 3V3 if yours doesn't. These
+// are the only free pins on the XIAO, so the display can't be used together with `midi-thru` or zone LEDs on them.
+//
+// `main` takes a `Screen` snapshot of the global state and this module only draws it, so the state lock is never held
+// during the I2C transfer, which runs async at 400kHz and takes about 25ms for a full frame.
+
+use core::fmt::Write;
+use display_interface::DisplayError;
+use embedded_graphics::mono_font::ascii::{FONT_6X10, FONT_10X20};
+use embedded_graphics::mono_font::{MonoTextStyle, MonoTextStyleBuilder};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use embedded_hal_async::i2c::I2c;
+use heapless::String;
+use ssd1306::mode::BufferedGraphicsModeAsync;
+use ssd1306::prelude::*;
+use ssd1306::{I2CDisplayInterface, Ssd1306Async};
+
+use crate::notes;
+
+/// Everything the display shows. Redrawn whenever it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Screen {
+    pub octave: i32,
+    pub channel: u8, // 1..=16.
+    pub mode: &'static str,
+    pub last_note: Option<u8>,
+    pub active: bool, // A note started recently: the note is drawn inverted.
+}
+
+type Driver<I2C> = Ssd1306Async<I2CInterface<I2C>, DisplaySize128x64, BufferedGraphicsModeAsync<DisplaySize128x64>>;
+
+pub struct Display<I2C> {
+    driver: Driver<I2C>,
+    shown: Option<Screen>,
+}
+
+impl<I2C: embedded_hal::i2c::I2c + I2c> Display<I2C> {
+    /// Initializes the display. Fails if there is no display on the bus.
+    pub async fn new(i2c: I2C) -> Result<Self, DisplayError> {
+        let mut driver = Ssd1306Async::new(I2CDisplayInterface::new(i2c), DisplaySize128x64, DisplayRotation::Rotate0)
+            .into_buffered_graphics_mode();
+        driver.init().await?;
+        Ok(Self { driver, shown: None })
+    }
+
+    /// Draws `screen` if it differs from what is shown.
+    pub async fn show(&mut self, screen: Screen) -> Result<(), DisplayError> {
+        if self.shown == Some(screen) {
+            return Ok(());
+        }
+        self.driver.clear_buffer();
+        let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let mut line: String<24> = String::new();
+        write!(line, "Oct {}  Ch {}", screen.octave, screen.channel).ok();
+        Text::with_baseline(&line, Point::new(0, 0), small, Baseline::Top).draw(&mut self.driver)?;
+        Text::with_baseline(screen.mode, Point::new(0, 12), small, Baseline::Top).draw(&mut self.driver)?;
+        if let Some(note) = screen.last_note {
+            let (background, text) = if screen.active {
+                (BinaryColor::On, BinaryColor::Off)
+            } else {
+                (BinaryColor::Off, BinaryColor::On)
+            };
+            Rectangle::new(Point::new(0, 30), Size::new(128, 28))
+                .into_styled(PrimitiveStyle::with_fill(background))
+                .draw(&mut self.driver)?;
+            let large = MonoTextStyleBuilder::new().font(&FONT_10X20).text_color(text).build();
+            Text::with_baseline(notes::note_name(note), Point::new(4, 34), large, Baseline::Top)
+                .draw(&mut self.driver)?;
+            let small = MonoTextStyle::new(&FONT_6X10, text);
+            let mut frequency: String<12> = String::new();
+            write!(frequency, "{:.1}Hz", notes::note_frequency(note)).ok();
+            Text::with_baseline(&frequency, Point::new(60, 40), small, Baseline::Top).draw(&mut self.driver)?;
+        }
+        self.driver.flush().await?;
+        self.shown = Some(screen);
+        Ok(())
+    }
+}
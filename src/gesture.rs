@@ -0,0 +1,86 @@
+// Gesture detection on top of the mux sweep snapshots (`mux::SWEEP_COMPLETE`). Each key still fires its own edge
+// callbacks; the detectors here only add the higher level gestures formed by several keys together.
+//
+// Basic example, in its own task:
+//    let mut chords = gesture::ChordDetector::new();
+//    chords.set_window(Duration::from_millis(20));             // Optional, 15ms by default.
+//    chords.set_chord_callback(chord_handler);                 // fn chord_handler(channels: &[u8])
+//    loop {
+//        chords.update(&mux::SWEEP_COMPLETE.wait().await);
+//    }
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::mux::{Sweep, CHANNELS};
+
+/// How close together presses have to land to count as one chord.
+pub const DEFAULT_CHORD_WINDOW: Duration = Duration::from_millis(15);
+
+/// Groups near-simultaneous presses into chords. The first press opens a window; every channel pressed before it
+/// closes joins the chord. When the window closes, the channels of the chord that are still held are reported, if
+/// there are at least two. A press after the window closed opens a new one, so a roll slower than the window is a
+/// series of single presses and never a chord.
+#[derive(Debug)]
+pub struct ChordDetector {
+    window: Duration,
+    previous: u64, //Pressed channels in the previous sweep.
+    collecting: Option<(Instant, u64)>, //When the open window started and the channels pressed within it.
+    chord_callback: Option<fn(&[u8])>, //Called with the channel indices of every detected chord, lowest first.
+}
+
+impl ChordDetector {
+    pub const fn new() -> Self {
+        Self {
+            window: DEFAULT_CHORD_WINDOW,
+            previous: 0,
+            collecting: None,
+            chord_callback: None,
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    pub fn set_chord_callback(&mut self, callback: fn(&[u8])) {
+        self.chord_callback = Some(callback);
+    }
+
+    /// Feeds the next sweep snapshot. Missed sweeps only make the timing coarser, no press is lost.
+    pub fn update(&mut self, sweep: &Sweep) {
+        if let Some((start, channels)) = self.collecting {
+            if sweep.at.duration_since(start) >= self.window {
+                self.collecting = None;
+                self.report(channels & sweep.pressed);
+            }
+        }
+        let pressed = sweep.pressed & !self.previous;
+        self.previous = sweep.pressed;
+        if pressed != 0 {
+            match self.collecting.as_mut() {
+                Some((_, channels)) => *channels |= pressed,
+                None => self.collecting = Some((sweep.at, pressed)),
+            }
+        }
+    }
+
+    fn report(&self, channels: u64) {
+        if channels.count_ones() < 2 {
+            return;
+        }
+        let mut chord: Vec<u8, CHANNELS> = Vec::new();
+        for index in (0..CHANNELS).filter(|&index| channels & (1 << index) != 0) {
+            chord.push(index as u8).ok();
+        }
+        if let Some(callback) = self.chord_callback {
+            callback(&chord);
+        }
+    }
+}
+
+impl Default for ChordDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
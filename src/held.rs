@@ -0,0 +1,367 @@
+// Which note every note key is sounding, kept apart from the global state so it can be tested on the host. A key's
+// note is stored when it starts, so its release stops that note even if the octave, transpose or layout has changed
+// while the key was down. In mono mode `MonoStack` picks the one held key that sounds, and `Sostenuto` tracks the keys
+// the sostenuto pedal holds.
+
+use heapless::Vec;
+
+/// The note each of `N` keys started, or none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyNotes<const N: usize> {
+    notes: [Option<i32>; N],
+}
+
+impl<const N: usize> KeyNotes<N> {
+    pub const fn new() -> Self {
+        Self { notes: [None; N] }
+    }
+
+    /// Stores the note `key` started. Out of range keys are ignored.
+    pub fn press(&mut self, key: usize, note: i32) {
+        if let Some(stored) = self.notes.get_mut(key) {
+            *stored = Some(note);
+        }
+    }
+
+    /// The note `key` is sounding, if any.
+    pub fn note(&self, key: usize) -> Option<i32> {
+        self.notes.get(key).copied().flatten()
+    }
+
+    pub fn is_held(&self, key: usize) -> bool {
+        self.note(key).is_some()
+    }
+
+    /// Forgets `key`'s note and returns it, for its note-off.
+    pub fn release(&mut self, key: usize) -> Option<i32> {
+        self.notes.get_mut(key).and_then(Option::take)
+    }
+
+    /// Every key with a note and the note, lowest key first.
+    pub fn held(&self) -> impl Iterator<Item = (usize, i32)> + '_ {
+        self.notes.iter().enumerate().filter_map(|(key, note)| note.map(|note| (key, note)))
+    }
+
+    /// The keys that have a note, lowest first, e.g. to stop them all while releasing each one.
+    pub fn held_keys(&self) -> Vec<usize, N> {
+        self.held().map(|(key, _)| key).collect()
+    }
+}
+
+impl<const N: usize> Default for KeyNotes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which held key sounds in mono mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotePriority {
+    Last, // The most recently pressed key.
+    High, // The highest held note.
+    Low,  // The lowest held note.
+}
+
+/// What a mono mode press or release changes: the key whose note stops, then the key whose note starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MonoChange {
+    pub stop: Option<usize>,
+    pub start: Option<usize>,
+}
+
+/// The held note keys in press order and the one sounding, for mono mode.
+#[derive(Debug, Clone)]
+pub struct MonoStack<const N: usize> {
+    held: Vec<usize, N>,
+    sounding: Option<usize>,
+}
+
+impl<const N: usize> MonoStack<N> {
+    pub const fn new() -> Self {
+        Self { held: Vec::new(), sounding: None }
+    }
+
+    /// The key whose note is sounding, if any.
+    pub fn sounding(&self) -> Option<usize> {
+        self.sounding
+    }
+
+    /// A key with its note in `notes` is pressed: it joins the stack, and if it wins on priority it takes over from the
+    /// sounding key.
+    pub fn press(&mut self, key: usize, priority: NotePriority, notes: &KeyNotes<N>) -> MonoChange {
+        self.held.push(key).ok();
+        let winner = self.winner(priority, notes);
+        if winner == self.sounding {
+            return MonoChange::default();
+        }
+        let stop = core::mem::replace(&mut self.sounding, winner);
+        MonoChange { stop, start: winner }
+    }
+
+    /// A key is released: if it was sounding, the next held key by priority takes over (legato fallback).
+    pub fn release(&mut self, key: usize, priority: NotePriority, notes: &KeyNotes<N>) -> MonoChange {
+        self.held.retain(|&held| held != key);
+        if self.sounding != Some(key) {
+            return MonoChange::default();
+        }
+        self.sounding = self.winner(priority, notes);
+        MonoChange { stop: Some(key), start: self.sounding }
+    }
+
+    /// Forgets every key, without any note changes.
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.sounding = None;
+    }
+
+    fn winner(&self, priority: NotePriority, notes: &KeyNotes<N>) -> Option<usize> {
+        let held = self.held.iter().copied();
+        match priority {
+            NotePriority::Last => self.held.last().copied(),
+            NotePriority::High => held.max_by_key(|&key| notes.note(key)),
+            NotePriority::Low => held.min_by_key(|&key| notes.note(key)),
+        }
+    }
+}
+
+impl<const N: usize> Default for MonoStack<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The keys the sostenuto pedal holds: the ones that were down when it went down, and which of them were released
+/// since, whose note-offs wait for the pedal to lift. Keys pressed while it's down play and release as usual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sostenuto<const N: usize> {
+    set: [bool; N],     // Down when the pedal went down.
+    pending: [bool; N], // Of those, released since.
+}
+
+impl<const N: usize> Sostenuto<N> {
+    pub const fn new() -> Self {
+        Self { set: [false; N], pending: [false; N] }
+    }
+
+    /// Pedal down: holds the keys that have a note in `notes` right now.
+    pub fn capture(&mut self, notes: &KeyNotes<N>) {
+        self.set = core::array::from_fn(|key| notes.is_held(key));
+        self.pending = [false; N];
+    }
+
+    /// `key` is released: true if the pedal holds its note, whose note-off then waits for `pedal_up`.
+    pub fn hold_release(&mut self, key: usize) -> bool {
+        let held = self.set.get(key).copied().unwrap_or(false);
+        if held {
+            self.pending[key] = true;
+        }
+        held
+    }
+
+    /// True if `key`'s note is only kept by the pedal, its key is up.
+    pub fn is_pending(&self, key: usize) -> bool {
+        self.pending.get(key).copied().unwrap_or(false)
+    }
+
+    /// True if the pedal holds `key`, down or up.
+    pub fn holds(&self, key: usize) -> bool {
+        self.set.get(key).copied().unwrap_or(false) || self.is_pending(key)
+    }
+
+    /// Takes `key`'s held note-off, e.g. when the key is struck again: true if it was waiting, so the caller stops the
+    /// old note. The key stays held by the pedal.
+    pub fn take_pending(&mut self, key: usize) -> bool {
+        self.pending.get_mut(key).is_some_and(core::mem::take)
+    }
+
+    /// Lets go of `key` without waiting for the pedal.
+    pub fn forget(&mut self, key: usize) {
+        if key < N {
+            self.set[key] = false;
+            self.pending[key] = false;
+        }
+    }
+
+    /// Pedal up: the keys whose note-offs waited, lowest first. Nothing is held any more.
+    pub fn pedal_up(&mut self) -> Vec<usize, N> {
+        let released = (0..N).filter(|&key| self.pending[key]).collect();
+        *self = Self::new();
+        released
+    }
+}
+
+impl<const N: usize> Default for Sostenuto<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octave;
+
+    #[test]
+    fn release_stops_the_note_of_the_press_octave() {
+        let mut notes: KeyNotes<25> = KeyNotes::new();
+        let (key, semitone) = (7, 7);
+        let at_four = octave::chromatic_note(semitone, 4, 0);
+        notes.press(key, at_four);
+        // The octave goes up to 6 while the key is down: the key still stops what it started, not the note at 6.
+        let at_six = octave::chromatic_note(semitone, 6, 0);
+        assert_eq!(notes.release(key), Some(at_four));
+        assert_ne!(at_four, at_six);
+        assert!(!notes.is_held(key));
+        assert_eq!(notes.release(key), None, "a second release stops nothing");
+    }
+
+    #[test]
+    fn keys_keep_their_own_notes() {
+        let mut notes: KeyNotes<25> = KeyNotes::new();
+        notes.press(0, 48);
+        notes.press(24, 84);
+        assert_eq!(notes.note(0), Some(48));
+        assert_eq!(notes.note(24), Some(84));
+        assert_eq!(notes.note(1), None);
+        notes.press(25, 60); // Past the keys.
+        assert_eq!(notes.note(25), None);
+    }
+
+    #[test]
+    fn held_notes_follow_presses_and_releases() {
+        let mut notes: KeyNotes<25> = KeyNotes::new();
+        notes.press(4, 52);
+        notes.press(0, 48);
+        notes.press(9, 57);
+        notes.release(4);
+        notes.press(12, 60);
+        notes.release(12);
+        notes.press(4, 64); // Struck again, an octave up.
+        let held: Vec<(usize, i32), 25> = notes.held().collect();
+        assert_eq!(held.as_slice(), &[(0, 48), (4, 64), (9, 57)]);
+    }
+
+    #[test]
+    fn releasing_every_held_key_stops_each_note_once() {
+        // What `force_release_all` does: a note-off for every held key, then nothing is left.
+        let mut notes: KeyNotes<25> = KeyNotes::new();
+        for (key, note) in [(2, 50), (3, 51), (20, 68)] {
+            notes.press(key, note);
+        }
+        let mut note_offs: Vec<i32, 25> = Vec::new();
+        for key in notes.held_keys() {
+            note_offs.push(notes.release(key).unwrap()).unwrap();
+        }
+        assert_eq!(note_offs.as_slice(), &[50, 51, 68]);
+        assert_eq!(notes.held().count(), 0);
+        assert!(notes.held_keys().is_empty());
+    }
+
+    // Presses `key` with `note` in mono mode, like `start_note`.
+    fn mono_press(
+        mono: &mut MonoStack<25>,
+        notes: &mut KeyNotes<25>,
+        key: usize,
+        note: i32,
+        priority: NotePriority,
+    ) -> MonoChange {
+        notes.press(key, note);
+        mono.press(key, priority, notes)
+    }
+
+    // Releases `key` in mono mode, like `release_note`.
+    fn mono_release(
+        mono: &mut MonoStack<25>,
+        notes: &mut KeyNotes<25>,
+        key: usize,
+        priority: NotePriority,
+    ) -> MonoChange {
+        let change = mono.release(key, priority, notes);
+        notes.release(key);
+        change
+    }
+
+    fn change(stop: Option<usize>, start: Option<usize>) -> MonoChange {
+        MonoChange { stop, start }
+    }
+
+    #[test]
+    fn last_note_priority() {
+        let (mut mono, mut notes) = (MonoStack::new(), KeyNotes::new());
+        let last = NotePriority::Last;
+        assert_eq!(mono_press(&mut mono, &mut notes, 5, 65, last), change(None, Some(5)));
+        assert_eq!(mono_press(&mut mono, &mut notes, 2, 62, last), change(Some(5), Some(2)));
+        assert_eq!(mono_press(&mut mono, &mut notes, 9, 69, last), change(Some(2), Some(9)));
+        // A key that isn't sounding goes quietly, the sounding one falls back to the latest key still held.
+        assert_eq!(mono_release(&mut mono, &mut notes, 2, last), change(None, None));
+        assert_eq!(mono_release(&mut mono, &mut notes, 9, last), change(Some(9), Some(5)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 5, last), change(Some(5), None));
+        assert_eq!(mono.sounding(), None);
+    }
+
+    #[test]
+    fn high_note_priority() {
+        let (mut mono, mut notes) = (MonoStack::new(), KeyNotes::new());
+        let high = NotePriority::High;
+        assert_eq!(mono_press(&mut mono, &mut notes, 5, 65, high), change(None, Some(5)));
+        assert_eq!(mono_press(&mut mono, &mut notes, 2, 62, high), change(None, None)); // Lower, it waits.
+        assert_eq!(mono_press(&mut mono, &mut notes, 9, 69, high), change(Some(5), Some(9)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 9, high), change(Some(9), Some(5)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 5, high), change(Some(5), Some(2)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 2, high), change(Some(2), None));
+    }
+
+    #[test]
+    fn low_note_priority() {
+        let (mut mono, mut notes) = (MonoStack::new(), KeyNotes::new());
+        let low = NotePriority::Low;
+        assert_eq!(mono_press(&mut mono, &mut notes, 5, 65, low), change(None, Some(5)));
+        assert_eq!(mono_press(&mut mono, &mut notes, 9, 69, low), change(None, None)); // Higher, it waits.
+        assert_eq!(mono_press(&mut mono, &mut notes, 2, 62, low), change(Some(5), Some(2)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 9, low), change(None, None));
+        assert_eq!(mono_release(&mut mono, &mut notes, 2, low), change(Some(2), Some(5)));
+        assert_eq!(mono_press(&mut mono, &mut notes, 0, 60, low), change(Some(5), Some(0)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 0, low), change(Some(0), Some(5)));
+        assert_eq!(mono_release(&mut mono, &mut notes, 5, low), change(Some(5), None));
+    }
+
+    #[test]
+    fn sostenuto_holds_the_keys_down_when_pressed() {
+        let (mut sostenuto, mut notes) = (Sostenuto::<25>::new(), KeyNotes::<25>::new());
+        notes.press(0, 48);
+        notes.press(4, 52);
+        sostenuto.capture(&notes);
+        // Released under the pedal: their note-offs wait.
+        assert!(sostenuto.hold_release(0));
+        assert!(sostenuto.hold_release(4));
+        assert!(sostenuto.is_pending(0) && sostenuto.is_pending(4));
+        assert_eq!(sostenuto.pedal_up().as_slice(), &[0, 4]);
+        assert!(!sostenuto.holds(0) && !sostenuto.holds(4));
+    }
+
+    #[test]
+    fn keys_pressed_after_the_pedal_release_normally() {
+        let (mut sostenuto, mut notes) = (Sostenuto::<25>::new(), KeyNotes::<25>::new());
+        notes.press(0, 48);
+        sostenuto.capture(&notes);
+        notes.press(7, 55); // After the pedal went down.
+        assert!(!sostenuto.hold_release(7));
+        assert!(!sostenuto.is_pending(7));
+        // A key still down when the pedal lifts has nothing waiting: it stops on its own release.
+        assert!(sostenuto.pedal_up().is_empty());
+        assert!(!sostenuto.hold_release(0));
+    }
+
+    #[test]
+    fn restruck_keys_stop_their_held_note() {
+        let (mut sostenuto, mut notes) = (Sostenuto::<25>::new(), KeyNotes::<25>::new());
+        notes.press(2, 50);
+        sostenuto.capture(&notes);
+        assert!(sostenuto.hold_release(2));
+        assert!(sostenuto.take_pending(2), "the old note stops before the new one starts");
+        assert!(!sostenuto.take_pending(2));
+        // Still held by the pedal, so its next release waits again.
+        assert!(sostenuto.hold_release(2));
+        assert_eq!(sostenuto.pedal_up().as_slice(), &[2]);
+    }
+}
@@ -0,0 +1,117 @@
+// Octave LED output, either a plain GPIO (on or off) or, with the "led-pwm" feature, a PWM channel of the LEDC
+// peripheral so the LEDs can be dimmed and the octave blink becomes a smooth pulse. Any output-capable GPIO can be
+// routed to an LEDC channel, so the LEDs stay on the same pins and resistors. The S3 has 8 low speed LEDC channels,
+// sharing timers: every PWM LED takes one channel, all of them share LEDC timer 0. Other LEDs (e.g. zone LEDs) can stay
+// plain GPIOs.
+//
+//Example, in `main`:
+//    let ledc = led::ledc(peripherals.LEDC);
+//    let timer = led::pwm_timer(&ledc);
+//    let mut down_led = led::Led::pwm(&ledc, &timer, channel::Number::Channel0, peripherals.GPIO8);
+//    led::set_led_brightness(128); // Half brightness.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use esp_hal::gpio::Output;
+#[cfg(feature = "led-pwm")]
+use esp_hal::gpio::interconnect::PeripheralOutput;
+#[cfg(feature = "led-pwm")]
+use esp_hal::ledc::channel::{self, ChannelHW, ChannelIFace};
+#[cfg(feature = "led-pwm")]
+use esp_hal::ledc::timer::{self, TimerIFace};
+#[cfg(feature = "led-pwm")]
+use esp_hal::ledc::{LSGlobalClkSource, Ledc, LowSpeed};
+#[cfg(feature = "led-pwm")]
+use esp_hal::peripheral::Peripheral;
+#[cfg(feature = "led-pwm")]
+use esp_hal::time::RateExtU32;
+
+// Brightness of every PWM LED when lit, 255 is full on.
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(255);
+
+/// Sets the brightness of all PWM LEDs, 0..=255. Takes effect on their next update. Plain LEDs are on or off whatever
+/// the brightness.
+pub fn set_led_brightness(brightness: u8) {
+    BRIGHTNESS.store(brightness, Ordering::Relaxed);
+}
+
+pub fn led_brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+// PWM resolution and frequency. 10 bits keeps the lowest steps of a pulse smooth; 20kHz is above what a camera or eye
+// picks up as flicker.
+#[cfg(feature = "led-pwm")]
+const PWM_BITS: u32 = 10;
+#[cfg(feature = "led-pwm")]
+const PWM_FREQUENCY_KHZ: u32 = 20;
+
+/// Takes the LEDC peripheral, clocked from APB.
+#[cfg(feature = "led-pwm")]
+pub fn ledc<'d>(peripheral: impl Peripheral<P = esp_hal::peripherals::LEDC> + 'd) -> Ledc<'d> {
+    let mut ledc = Ledc::new(peripheral);
+    ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+    ledc
+}
+
+/// Configures LEDC timer 0 for the LEDs.
+#[cfg(feature = "led-pwm")]
+pub fn pwm_timer<'d>(ledc: &Ledc<'d>) -> timer::Timer<'d, LowSpeed> {
+    let mut timer = ledc.timer::<LowSpeed>(timer::Number::Timer0);
+    timer
+        .configure(timer::config::Config {
+            duty: timer::config::Duty::Duty10Bit,
+            clock_source: timer::LSClockSource::APBClk,
+            frequency: PWM_FREQUENCY_KHZ.kHz(),
+        })
+        .unwrap(); // Only fails for a frequency the clock can't divide down to, fixed above.
+    timer
+}
+
+pub enum Led<'d> {
+    Plain(Output<'d>),
+    #[cfg(feature = "led-pwm")]
+    Pwm(channel::Channel<'d, LowSpeed>),
+}
+
+impl<'d> Led<'d> {
+    /// An LED on LEDC channel `number`, driven by `timer`. Starts dark.
+    #[cfg(feature = "led-pwm")]
+    pub fn pwm(
+        ledc: &Ledc<'d>,
+        timer: &'d timer::Timer<'d, LowSpeed>,
+        number: channel::Number,
+        pin: impl Peripheral<P = impl PeripheralOutput> + 'd,
+    ) -> Self {
+        let mut channel = ledc.channel(number, pin);
+        channel
+            .configure(channel::config::Config { timer, duty_pct: 0, pin_config: channel::config::PinConfig::PushPull })
+            .unwrap(); // Only fails without a configured timer, see `pwm_timer`.
+        Led::Pwm(channel)
+    }
+
+    /// Lights the LED at the set brightness, or turns it off.
+    pub fn set(&mut self, on: bool) {
+        self.set_level(if on { 255 } else { 0 });
+    }
+
+    /// Lights the LED at `level` (0..=255) of the set brightness, for fades. A plain LED is on for any level above 0.
+    pub fn set_level(&mut self, level: u8) {
+        match self {
+            Led::Plain(pin) => {
+                if level > 0 {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+            #[cfg(feature = "led-pwm")]
+            Led::Pwm(channel) => {
+                let level = level as u32 * led_brightness() as u32 / 255;
+                // Squared, as the eye sees brightness roughly logarithmically: a linear duty ramp looks like it jumps
+                // up and then stays bright.
+                let max = (1 << PWM_BITS) - 1;
+                channel.set_duty_hw(level * level * max / (255 * 255));
+            }
+        }
+    }
+}
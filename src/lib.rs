@@ -0,0 +1,12 @@
+// The hardware drivers: the 4051 multiplexer and its alternatives (74HC165 shift registers, capacitive touch pads) with
+// the debounce and scan order they share, and the LEDs. They're built as the package's library so their whole API is
+// there for any controller wired differently from this one; the firmware in main.rs uses the parts its wiring needs.
+#![no_std]
+
+pub mod debounce;
+pub mod led;
+pub mod mux;
+pub mod scan;
+pub mod shift_register;
+#[cfg(feature = "touch")]
+pub mod touch;
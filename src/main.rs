@@ -1,107 +1,2463 @@
 #![no_std]
 #![no_main]
 
-mod mux;
+mod analog;
+mod blink;
+mod chord;
+mod config;
+#[cfg(feature = "display")]
+mod display;
+mod gesture;
+mod held;
+mod messages;
+mod notes;
+mod octave;
+mod performance;
+mod queue;
+#[cfg(feature = "midi-thru")]
+mod serial_midi;
+mod sequencer;
+mod storage;
+mod sysex;
+mod utils;
+mod velocity;
 
 use core::cell::RefCell;
 use core::ptr::addr_of_mut;
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::blocking_mutex::Mutex;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 use esp_backtrace as _;
 use esp_hal::{
     clock::CpuClock,
     gpio::{Input, Level, Output, Pull},
     otg_fs,
-    timer::timg::TimerGroup,
+    time::ExtU64,
+    timer::timg::{MwdtStage, TimerGroup},
     Config,
 };
 use esp_hal_embassy::main;
+use chord::{Chord, NO_CHORD};
 use heapless::Vec;
+use held::NotePriority;
+use octave::OctavePolicy;
+use queue::NoteEvent;
+use rs_esp32s3_midi_controller::led::{self, Led};
+use rs_esp32s3_midi_controller::mux;
 use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+use midi_convert::parse::MidiTryParseSlice;
 use midi_convert::render_slice::MidiRenderSlice;
 use usb_device::prelude::*;
-use usbd_midi::{CableNumber, UsbMidiClass, UsbMidiEventPacket};
+use usbd_midi::{CableNumber, UsbMidiClass, UsbMidiEventPacket, UsbMidiPacketReader};
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
 
-// Key mapping for the 4051 multiplexer. "255" and "254" are the octave up and down buttons respectively. If you do not wire your buttons in this order, you can adjust this array.
-const KEYS: [i32; 27] = [
-    255, 254, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-    24,
+#[cfg(all(feature = "display", feature = "midi-thru"))]
+compile_error!("the display and midi-thru both use GPIO44, enable only one of them");
+#[cfg(all(feature = "analog-keys", feature = "piezo-pads"))]
+compile_error!("analog-keys and piezo-pads both read the fourth mux chip, enable only one of them");
+
+// With the "analog-keys" or "piezo-pads" feature the fourth mux chip (common on D8/GPIO7, ADC1) reads analog levels
+// instead of switches. The mux numbers analog channels from 0, so the handler maps them through KEYS from the channel the chip has
+// as a switch chip: its 8 channels stay the same KEYS entries.
+#[cfg(any(feature = "analog-keys", feature = "piezo-pads"))]
+const ANALOG_FIRST_CHANNEL: usize = 24;
+
+// The ADC and the fourth chip's common pin, for the poll task.
+#[cfg(any(feature = "analog-keys", feature = "piezo-pads"))]
+static ADC: static_cell::StaticCell<RefCell<esp_hal::analog::adc::Adc<'static, esp_hal::peripherals::ADC1>>> =
+    static_cell::StaticCell::new();
+#[cfg(any(feature = "analog-keys", feature = "piezo-pads"))]
+static ANALOG_SOURCE: static_cell::StaticCell<mux::AdcSource<'static, esp_hal::gpio::GpioPin<7>>> =
+    static_cell::StaticCell::new();
+
+// Debug logging over RTT with the "defmt" feature. Without it the whole statement, arguments included, compiles out.
+macro_rules! midi_log {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::info!($($arg)*);
+    };
+}
+
+/// What a mux channel does when its switch is pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFunction {
+    Note(u8),                 // Note key 0..NUM_KEYS-1, played at the current octave.
+    OctaveUp,                 // Octave up button.
+    OctaveDown,               // Octave down button.
+    Split,                    // The next note key pressed after it becomes the split point.
+    Mute,                     // Mute toggle, see `toggle_mute`.
+    Transport(TransportMsg),  // Sends a MIDI transport message to a DAW or drum machine.
+    Sostenuto,                // Sostenuto pedal, see `press_sostenuto`. Put it in place of an unused entry to wire one.
+    ChordMemory,              // Learns the held chord for one-finger chords, or turns them off, see `press_chord_memory`.
+    ChordCapture,             // The next chord played becomes the chord memory template, silently, see `chord_handler`.
+    SeqStartStop,             // Starts the step sequencer from its first step, or stops it.
+    SeqClear,                 // Switches every sequencer step off.
+    SeqEdit,                  // Toggles step editing: note keys 0..15 switch their sequencer step on or off.
+    KeyLearn,                 // Starts learning which channel plays which note, or aborts it, see `begin_key_learn`.
+    ExpressionPedal,          // An expression pedal on an analog channel, sent as CC 11. See `expression_pedal`.
+    CalibratePedal,           // Starts calibrating the expression pedal's travel, see `start_pedal_calibration`.
+    Accent,                   // While held, new notes play at the accent velocity instead of their own.
+    TapTempo,                 // Sets the sequencer tempo from the spacing of the taps, see `sequencer::TapTempo`.
+    ZoneOctaveUp(u8),         // Shifts only this zone (index into `zones`) an octave up, see `shift_zone_octave`.
+    ZoneOctaveDown(u8),       // Shifts only this zone an octave down.
+    Sustain,                  // Sustain pedal or button, sent as CC 64 while held.
+    ChannelUp,                // Moves the unsplit channel up one, new notes only. Stops at channel 16.
+    ChannelDown,              // Moves the unsplit channel down one. Stops at channel 1.
+    Glide,                    // Turns the synth's portamento on while held, see `update_portamento`.
+    PortamentoTime,           // A pot on an analog channel setting the portamento time (CC 5).
+    SetOctave(i8),            // Jumps straight to this octave, within the octave limits. E.g. a long press, see `LONG_PRESS`.
+    FullVelocity,             // While held, every new note plays at 127, zones with a fixed velocity too. See `velocity::resolve`.
+    PatchSelect { bank_msb: u8, bank_lsb: u8, program: u8 }, // Selects a patch past the first 128, see `queue_patch_select`.
+    Macro(u8),                // Plays this entry of MACROS, or cancels it if it's still playing. See `src/performance.rs`.
+    System(SystemMsg),        // Sends a MIDI system message, e.g. a System Reset. See `SystemMsg`.
+    SecondContact(u8),        // The second contact of note key n on a dual-contact keybed, see `press_contact`.
+    ThirdContact(u8),         // The bottom contact of note key n on a three-contact keybed, see `press_bottom_contact`.
+    CalibrateVelocity,        // Records the slowest and fastest strikes, see `start_velocity_calibration`.
+}
+
+/// The MIDI real-time transport messages a transport button can send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportMsg {
+    Start,
+    Stop,
+    Continue,
+}
+
+/// The MIDI system messages a system key can send, each a single byte without a channel.
+/// - `Reset` (FF) asks every receiver to return to its power-on state: notes off, controllers and patch reset. Many
+///   DAWs ignore it, hardware synths and some sound modules act on it; it is a reset button, not a panic.
+/// - `ActiveSensing` (FE) once. Sent on its own it just starts a receiver's disconnect timeout, see "active_sensing"
+///   in `GlobalState` for sending it periodically.
+/// - `TuneRequest` (F6) asks analog synths to retune their oscillators.
+///
+/// SysEx start (F0) and end (F7) aren't messages of their own: a whole SysEx message is sent through `sysex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemMsg {
+    Reset,
+    ActiveSensing,
+    TuneRequest,
+}
+
+impl SystemMsg {
+    pub fn message(self) -> MidiMessage {
+        match self {
+            SystemMsg::Reset => MidiMessage::Reset,
+            SystemMsg::ActiveSensing => MidiMessage::ActiveSensing,
+            SystemMsg::TuneRequest => MidiMessage::TuneRequest,
+        }
+    }
+}
+
+// Number of note keys on the keybed. Sizes all per-key state; raise it for a 3 or 4 octave build and map the extra
+// keys in KEYS.
+pub const NUM_KEYS: usize = 25;
+
+// Number of mux channels with a function in KEYS. Must fit on the mux; channels past it do nothing.
+const NUM_MAPPED: usize = 32;
+
+// Key mapping for the 4051 multiplexer, one entry per mux channel. If you do not wire your buttons in this order, you can adjust this array.
+// The two octave buttons are ordinary entries too: give them e.g. `Sustain` or `ChannelUp` instead. With no octave button
+// mapped at all, the LEDs stay dark in octave LED mode.
+// Note keys are named by their place on the keybed, see `config::keys!`: "C" is the lowest key, "C1" the next C up.
+#[cfg(not(any(feature = "analog-keys", feature = "piezo-pads")))]
+const KEYS: [KeyFunction; NUM_MAPPED] = config::keys![
+    OctaveUp,
+    OctaveDown,
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    "C1", "C#1", "D1", "D#1", "E1", "F1", "F#1", "G1", "G#1", "A1", "A#1", "B1",
+    "C2",
+    Split,
+    Mute,
+    Transport(TransportMsg::Start),
+    Transport(TransportMsg::Stop),
+    Transport(TransportMsg::Continue),
+];
+
+// With "analog-keys" channels 24-31 are analog (see ANALOG_FIRST_CHANNEL): the top three keys are FSRs, channel 27 is
+// the expression pedal and 28 the portamento time pot. Switch functions can't go on them.
+#[cfg(feature = "analog-keys")]
+const KEYS: [KeyFunction; NUM_MAPPED] = config::keys![
+    OctaveUp,
+    OctaveDown,
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    "C1", "C#1", "D1", "D#1", "E1", "F1", "F#1", "G1", "G#1", "A1", "A#1", "B1",
+    "C2",
+    ExpressionPedal,
+    PortamentoTime,
+    Transport(TransportMsg::Start),
+    Transport(TransportMsg::Stop),
+    Transport(TransportMsg::Continue),
+];
+
+// With "piezo-pads" channels 24-31 are analog (see ANALOG_FIRST_CHANNEL): the top eight keys move onto them as drum
+// pads, and the function buttons move down to the switch channels below them.
+#[cfg(feature = "piezo-pads")]
+const KEYS: [KeyFunction; NUM_MAPPED] = config::keys![
+    OctaveUp,
+    OctaveDown,
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    "C1", "C#1", "D1", "D#1", "E1",
+    Split,
+    Mute,
+    Transport(TransportMsg::Start),
+    Transport(TransportMsg::Stop),
+    Transport(TransportMsg::Continue),
+    "F1", "F#1", "G1", "G#1", "A1", "A#1", "B1", "C2",
+];
+
+// Every mapped channel must exist on the mux, and every note key needs its per-key state.
+const _: () = {
+    assert!(NUM_MAPPED <= mux::CHANNELS);
+    let mut index = 0;
+    while index < NUM_MAPPED {
+        if let KeyFunction::Note(key) = KEYS[index] {
+            assert!((key as usize) < NUM_KEYS);
+        }
+        index += 1;
+    }
+};
+
+const DEFAULT_KEY_MAP: [Option<KeyFunction>; NUM_MAPPED] = {
+    let mut map = [None; NUM_MAPPED];
+    let mut index = 0;
+    while index < NUM_MAPPED {
+        map[index] = Some(KEYS[index]);
+        index += 1;
+    }
+    map
+};
+
+// A long press action per mux channel, for keys with two functions: a tap plays the channel's function from KEYS on
+// release, holding it for LONG_PRESS plays this one while still held. Only meant for function buttons, a note key with
+// one would sound on release. For example, tap to go an octave up, hold to jump back to octave 4:
+//    map[0] = Some(KeyFunction::SetOctave(4));
+#[cfg(not(feature = "analog-keys"))]
+const DEFAULT_LONG_PRESS_MAP: [Option<KeyFunction>; NUM_MAPPED] = [None; NUM_MAPPED];
+
+// With "analog-keys", holding octave down calibrates the expression pedal.
+#[cfg(feature = "analog-keys")]
+const DEFAULT_LONG_PRESS_MAP: [Option<KeyFunction>; NUM_MAPPED] = {
+    let mut map = [None; NUM_MAPPED];
+    map[1] = Some(KeyFunction::CalibratePedal);
+    map
+};
+
+// The performance macros `KeyFunction::Macro` keys play, by index. Each is a list of messages with the delay before
+// each, see `src/performance.rs` for the limits. Messages go out on the channels written here, through the channel
+// remap. For example, a program change and then a one second note:
+//    &[
+//        MacroStep { delay: Duration::from_millis(0), message: MidiMessage::ProgramChange(Channel::C1, 5.into()) },
+//        MacroStep { delay: Duration::from_millis(5), message: MidiMessage::NoteOn(Channel::C1, 60.into(), 100.into()) },
+//        MacroStep { delay: Duration::from_secs(1), message: MidiMessage::NoteOff(Channel::C1, 60.into(), 0.into()) },
+//    ],
+const MACROS: [performance::Macro; 0] = [];
+
+// How long a key with a long press action must be held for it.
+const LONG_PRESS: Duration = Duration::from_millis(500);
+
+/// A note key press held back by "octave_settle", started at "until" with its velocity.
+#[derive(Debug, Clone, Copy)]
+pub struct SettlingPress {
+    pub key: u8,
+    pub velocity: u8,
+    pub until: Instant,
+}
+
+/// A held key with a long press action. "long" is set once the long action fired.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingPress {
+    pub index: u8,
+    pub at: Instant,
+    pub long: bool,
+}
+
+/// What the LEDs acknowledge during a key learn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LearnFeedback {
+    Accepted, // The key was learned as the next note.
+    Rejected, // The key was already learned, it's ignored.
+    Complete, // Every note key was learned, the new map is in use and saved.
+    Aborted,  // The learn button was pressed again, the old map stays.
+}
+
+// How long the LEDs show a learn event.
+const LEARN_FEEDBACK_TIME: Duration = Duration::from_millis(1000);
+
+// Number of USB MIDI cables (virtual ports) the device enumerates with. The host shows one MIDI port per cable,
+// e.g. "rs-esp32s3-midi-controller Port 1", "... Port 2" depending on the OS. Events pick their cable through
+// GlobalState.cable; cables beyond NUM_CABLES are not visible to the host. Default is a single port on Cable0.
+const NUM_CABLES: u8 = 1;
+
+//Needed for MIDI out
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+/// Global state for keys and octave.
+/// "key_note" stores which note is currently being held on each key.
+/// This is done so releasing the key will play the correct not off if you change octave, see `held::KeyNotes`.
+/// "octave" stores the current octave and "transpose" shifts chromatic notes by semitones on top of it.
+/// "min_octave"/"max_octave" are the octave button limits and "octave_policy" decides what happens past them.
+/// "octave_repeat" makes a held octave button keep shifting, and "octave_hold" tracks the button being held.
+/// With "freeze_octave" set, octave shifts made while a note key is held only take effect once every key is up:
+/// "deferred_octave" is the octave they add up to until then.
+/// "mono" turns on monophonic mode with the given note priority. "mono_stack" lists the held note keys in press
+/// order and the key whose note is currently sounding, see `held::MonoStack`. "key_velocity" keeps each held key's
+/// velocity so a mono fallback retriggers it as it was played.
+/// "layout" decides whether keys play chromatic notes or fixed drum pads from "drum_map". "key_table" is the semitone
+/// each key plays in the chromatic layout.
+/// "led_mode" picks what the two LEDs show, and "last_beat" is when the clock last hit a quarter note.
+/// "channel" is the MIDI channel used when the keyboard isn't split.
+/// "split_point" splits the keyboard: notes below it play on "lower_channel", the split note and above on "upper_channel".
+/// "split_learn" is set by the split button and makes the next pressed note the new split point.
+/// "key_channel" remembers the channel each held key was triggered on, so the note-off matches even if the split moves.
+/// "next_seq" is the sequence number given to the next queued note event.
+/// "min_gate" holds back note-offs until the note has sounded at least that long, for synths that miss very short
+/// notes. "key_on_at" is when each key's last note-on was queued. See `queue_note_off`.
+/// "max_note_length" force-releases any key's note sounding longer than that, see `release_overlong_notes`. Off by default.
+/// "octave_settle" holds each note key press that long before its note starts, so an octave change seen within it
+/// still applies, see `press_note`. "settling_presses" are those presses, with when each one starts. Off by default.
+/// "coalesce" drops note-off/note-on pairs that retrigger a sounding note within one main loop pass, see
+/// `queue::coalesce_retriggers`. Off by default.
+/// "cable" is the USB MIDI cable (virtual port) new notes go out on and "key_cable" remembers it per held key.
+/// "note_repeat" retriggers held notes at a fixed rate. "repeat_at" is when each held key's next repeat step is due and
+/// "repeat_gate_open" whether its note is currently on.
+/// "muted" drops all note events before they are sent, and "resume_on_unmute" decides whether unmuting re-sounds the
+/// keys still held.
+/// "velocity_trim" is added to every key's velocity to even out the keybed. While "trim_calibration" is set, presses
+/// are recorded for it instead, see `start_trim_calibration`.
+/// "velocity_curve" shapes every played velocity before the accent, round robin and humanize. "velocity_floor" then
+/// raises any quieter velocity to it, so even a very slow strike is heard. 1 (no change) by default.
+/// "humanize" adds a random jitter of up to ± that much to every note-on velocity (0 is off), drawn from "rng".
+/// "round_robin" replaces the velocity of repeated strikes of a note with its cycle of velocities. Off by default; the
+/// accent key wins over it and humanize is applied on top.
+/// "sostenuto" is set while the sostenuto pedal is down. "sostenuto_keys" are the keys that were down when it was
+/// pressed and those of them released since, whose note-off waits for the pedal, see `held::Sostenuto`.
+/// "release_velocity" is the note-off velocity, for synths that respond to it. 0 by default.
+/// "note_off_as_zero_velocity_on" sends releases as note-ons with velocity 0 instead of note-offs, see
+/// `messages::note_off_message`.
+/// "accent_active" is set while the accent key is held: note-ons then play at "accent_velocity" (127 by default).
+/// Notes already sounding keep the velocity they started with. "full_velocity" is set while the full velocity key is held,
+/// see `velocity::resolve`.
+/// "chord_memory" turns on one-finger chords: every note key also plays these intervals above its note. "key_chord"
+/// remembers the intervals each held key played, so the whole chord stops on release even if the template changed.
+/// "chord_capture" makes the next chord played (see `gesture::ChordDetector`) the chord memory template without
+/// sounding it. Note keys are silent while it is set; it clears itself once a chord is captured.
+/// "zones" layers the keyboard: when set, each key plays in every enabled zone containing it (this replaces the split).
+/// "key_layers" remembers the extra zone notes each held key triggered so they all stop on release.
+/// "swell" ramps a CC up while notes are held. "swell_start" is when each held key started its swell and
+/// "swell_sent" the last value sent per channel (None while no swelling key is held on it).
+/// "count_pressure" sends channel pressure from the number of held notes, see `CountPressure`; "count_pressure_sent" is
+/// the last value sent and when.
+/// "mpe" turns on MPE mode: every held chromatic key gets its own member channel (this replaces zones and the split).
+/// "mpe_next" is where the round robin over the member channels continues and "key_pressure" the last pressure sent
+/// per key in FSR "piano" mode.
+/// "pedal" is the expression pedal's calibrated travel. While "pedal_calibration" is set its readings are recorded
+/// for it until the given time, see `start_pedal_calibration`. "pedal_sent" is the last CC 11 value sent.
+/// "contact_keys" times the strike of every key with a second contact and "contact_velocity" maps that time to
+/// velocity, "triple_keys" and "triple_velocity" do the same for keys with a third contact. While
+/// "velocity_calibration" is set the strikes are recorded for it until the given time, see
+/// `start_velocity_calibration`.
+/// "sequencer" is the step sequencer, with "seq_edit" set while note keys edit its steps instead of playing and
+/// "seq_sounding" the note it last started, until stopped. It plays on "channel" and "cable" and sets "last_beat".
+/// "tap_tempo" sets its BPM from the tap tempo key. "last_tap" is when that key was last tapped and "last_downbeat"
+/// when the sequencer last started its first step; the LEDs flash on both for a while after a tap.
+/// "key_map" is what each mux channel does: KEYS, or the learned note keys once a key learn finished. "key_learn"
+/// collects the channels pressed while learning, and "learn_feedback" is the last learn event the LEDs show.
+/// "debounce_ms" is the mux debounce interval, kept here so the SysEx config can read and save it, see `apply_debounce`.
+/// "release_debounce_ms" replaces it after a release, for switches that chatter on lift-off, e.g. `Some(30)` with a
+/// short "debounce_ms" so presses still get through fast. Off by default: "debounce_ms" after both.
+/// "cc_map" lists the incoming MIDI CCs that change these settings.
+/// "active_sensing" sends Active Sensing (FE) every `ACTIVE_SENSING_INTERVAL`, so the host or synth can tell when the
+/// controller is unplugged: after 300ms without it (or any message) a receiver turns its notes off. That costs one
+/// 4 byte USB packet every 270ms, next to nothing, but a receiver that has seen one expects them from then on, so
+/// turning it off again makes it time out once. Off by default.
+/// "portamento_time" is the CC 5 value sent whenever portamento is turned on. Portamento (CC 65) is on while the glide
+/// key is held ("glide_held") or, with "glide_in_mono" set, while mono mode is on. "portamento_on" is what was last sent.
+/// "mirror_velocity_to_cc" sends every note-on's velocity as this CC too (e.g. 2 for breath), on the note's channel
+/// and right before the note-on.
+/// "long_press_map" gives mux channels a second function, played when the key is held for `LONG_PRESS` (indexed like
+/// "key_map"); a shorter tap plays the key's own function on release. "pending_presses" are those keys while held.
+/// "last_note" is the last note-on queued and when, for the display.
+/// "macros" plays the MACROS started by macro keys.
+/// "channel_remap" translates every channel on the way out, indexed by the channel a message was made for (identity by
+/// default), e.g. to move the whole controller to other channels for one synth without touching zones or the split.
+/// "analog_keys" tracks the pressure and calibration of each key in FSR "piano" mode, "piezo_pads" the hits and
+/// calibration of each key in piezo drum pad mode.
+#[derive(Debug)]
+pub struct GlobalState {
+    pub key_note: held::KeyNotes<NUM_KEYS>,
+    pub key_cable: [CableNumber; NUM_KEYS],
+    pub key_channel: [Channel; NUM_KEYS],
+    pub key_velocity: [u8; NUM_KEYS],
+    pub octave: i32,
+    pub transpose: i32,
+    pub min_octave: i32,
+    pub max_octave: i32,
+    pub octave_policy: OctavePolicy,
+    pub octave_repeat: Option<OctaveRepeat>,
+    pub octave_hold: Option<OctaveHold>,
+    pub freeze_octave: bool,
+    pub deferred_octave: Option<i32>,
+    pub mono: Option<NotePriority>,
+    pub mono_stack: held::MonoStack<NUM_KEYS>,
+    pub layout: Layout,
+    pub drum_map: [DrumPad; NUM_KEYS],
+    pub key_table: KeyTable,
+    pub led_mode: LedMode,
+    pub last_beat: Option<Instant>,
+    pub last_note: Option<(u8, Instant)>,
+    pub cable: CableNumber,
+    pub channel: Channel,
+    pub split_point: Option<u8>,
+    pub lower_channel: Channel,
+    pub upper_channel: Channel,
+    pub split_learn: bool,
+    pub next_seq: u32,
+    pub coalesce: bool,
+    pub min_gate: Option<Duration>,
+    pub max_note_length: Option<Duration>,
+    pub octave_settle: Option<Duration>,
+    pub settling_presses: Vec<SettlingPress, 8>,
+    pub key_on_at: [Instant; NUM_KEYS],
+    pub analog_keys: [analog::AnalogKey; NUM_KEYS],
+    pub piezo_pads: [analog::PiezoPad; NUM_KEYS],
+    pub muted: bool,
+    pub resume_on_unmute: bool,
+    pub velocity_trim: velocity::VelocityTrim,
+    pub trim_calibration: Option<velocity::TrimCalibration>,
+    pub velocity_curve: velocity::Curve,
+    pub velocity_floor: u8,
+    pub humanize: u8,
+    pub rng: velocity::XorShift32,
+    pub round_robin: Option<velocity::RoundRobin>,
+    pub sostenuto: bool,
+    pub sostenuto_keys: held::Sostenuto<NUM_KEYS>,
+    pub release_velocity: Value7,
+    pub note_off_as_zero_velocity_on: bool,
+    pub accent_active: bool,
+    pub accent_velocity: u8,
+    pub full_velocity: bool,
+    pub chord_memory: Option<Chord>,
+    pub key_chord: [Chord; NUM_KEYS],
+    pub chord_capture: bool,
+    pub zones: Vec<Zone, { config::MAX_ZONES }>,
+    pub key_layers: [Vec<Voice, 3>; NUM_KEYS],
+    pub swell: Option<Swell>,
+    pub swell_start: [Option<Instant>; NUM_KEYS],
+    pub swell_sent: [Option<u8>; 16],
+    pub count_pressure: Option<CountPressure>,
+    pub count_pressure_sent: Option<(u8, Instant)>,
+    pub channel_remap: [Channel; 16],
+    pub mpe: Option<Mpe>,
+    pub mpe_next: u8,
+    pub key_pressure: [u8; NUM_KEYS],
+    pub pedal: analog::PedalCalibration,
+    pub pedal_calibration: Option<(analog::PedalRangeCalibration, Instant)>,
+    pub pedal_sent: Option<u8>,
+    pub contact_keys: [velocity::DualContactKey; NUM_KEYS],
+    pub contact_velocity: velocity::DualContactVelocity,
+    pub triple_keys: [velocity::TripleContactKey; NUM_KEYS],
+    pub triple_velocity: velocity::TripleContactVelocity,
+    pub velocity_calibration: Option<(velocity::VelocityCalibration, Instant)>,
+    pub sequencer: sequencer::SequencerState,
+    pub macros: performance::MacroPlayer,
+    pub seq_edit: bool,
+    pub seq_sounding: Option<(Voice, CableNumber)>,
+    pub tap_tempo: sequencer::TapTempo,
+    pub last_tap: Option<Instant>,
+    pub last_downbeat: Option<Instant>,
+    pub key_map: [Option<KeyFunction>; NUM_MAPPED],
+    pub key_learn: Option<Vec<u8, NUM_KEYS>>,
+    pub learn_feedback: Option<(LearnFeedback, Instant)>,
+    pub key_map_unsaved: bool,
+    pub debounce_ms: u8,
+    pub release_debounce_ms: Option<u8>,
+    pub cc_map: CcMap,
+    pub active_sensing: bool,
+    pub note_repeat: Option<NoteRepeat>,
+    pub repeat_at: [Option<Instant>; NUM_KEYS],
+    pub repeat_gate_open: [bool; NUM_KEYS],
+    pub portamento_time: u8,
+    pub glide_in_mono: bool,
+    pub glide_held: bool,
+    pub portamento_on: bool,
+    pub mirror_velocity_to_cc: Option<u8>,
+    pub long_press_map: [Option<KeyFunction>; NUM_MAPPED],
+    pub pending_presses: Vec<PendingPress, 4>,
+}
+
+/// What the note keys play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Chromatic, // Notes from the key position and octave (default).
+    Drums,     // Fixed drum pads on channel 10, unaffected by octave.
+}
+
+/// The note and fixed velocity a key plays in the drum layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrumPad {
+    pub note: u8,
+    pub velocity: u8,
+}
+
+// General MIDI drums are always on channel 10.
+const DRUM_CHANNEL: Channel = Channel::C10;
+
+const fn pad(note: u8) -> DrumPad {
+    DrumPad { note, velocity: 127 }
+}
+
+// The General MIDI kit from Bass Drum 1 (36) to Hi Bongo (60), one pad per key.
+const GM_DRUM_KIT: [DrumPad; 25] = [
+    pad(36), // Bass Drum 1
+    pad(37), // Side Stick
+    pad(38), // Acoustic Snare
+    pad(39), // Hand Clap
+    pad(40), // Electric Snare
+    pad(41), // Low Floor Tom
+    pad(42), // Closed Hi-Hat
+    pad(43), // High Floor Tom
+    pad(44), // Pedal Hi-Hat
+    pad(45), // Low Tom
+    pad(46), // Open Hi-Hat
+    pad(47), // Low-Mid Tom
+    pad(48), // Hi-Mid Tom
+    pad(49), // Crash Cymbal 1
+    pad(50), // High Tom
+    pad(51), // Ride Cymbal 1
+    pad(52), // Chinese Cymbal
+    pad(53), // Ride Bell
+    pad(54), // Tambourine
+    pad(55), // Splash Cymbal
+    pad(56), // Cowbell
+    pad(57), // Crash Cymbal 2
+    pad(58), // Vibraslap
+    pad(59), // Ride Cymbal 2
+    pad(60), // Hi Bongo
+];
+
+// Default drum map: the kit above, continuing up the General MIDI percussion notes on keybeds with more than 25 keys.
+const GM_DRUM_MAP: [DrumPad; NUM_KEYS] = {
+    let mut map = [pad(36); NUM_KEYS];
+    let mut key = 0;
+    while key < NUM_KEYS {
+        map[key] = if key < GM_DRUM_KIT.len() { GM_DRUM_KIT[key] } else { pad(36 + key as u8) };
+        key += 1;
+    }
+    map
+};
+
+/// The semitone each note key plays above the octave's C in the chromatic layout, i.e. `note = semitone + 12 * octave`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTable {
+    Chromatic,   // Key n plays semitone n, like a piano keybed (default).
+    WickiHayden, // Isomorphic rows of 5 keys: a whole tone to the right, a fourth up-left and a fifth up-right.
+}
+
+const CHROMATIC_SEMITONES: [i8; NUM_KEYS] = {
+    let mut semitones = [0; NUM_KEYS];
+    let mut key = 0;
+    while key < NUM_KEYS {
+        semitones[key] = key as i8;
+        key += 1;
+    }
+    semitones
+};
+
+// Key `row * 5 + column` of a grid whose rows each sit half a key right of the row below: 2 semitones per column,
+// 5 per row, so the key up-right is 7 semitones higher.
+const WICKI_HAYDEN_SEMITONES: [i8; NUM_KEYS] = {
+    let mut semitones = [0; NUM_KEYS];
+    let mut key = 0;
+    while key < NUM_KEYS {
+        semitones[key] = (2 * (key % 5) + 5 * (key / 5)) as i8;
+        key += 1;
+    }
+    semitones
+};
+
+impl KeyTable {
+    pub fn semitones(self) -> &'static [i8; NUM_KEYS] {
+        match self {
+            Self::Chromatic => &CHROMATIC_SEMITONES,
+            Self::WickiHayden => &WICKI_HAYDEN_SEMITONES,
+        }
+    }
+}
+
+/// What the two octave LEDs display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    Octave,    // Blink the up or down LED faster the further the octave is from home (default).
+    Activity,  // Pulse the up LED on every note-on sent and the down LED on every note-off.
+    ClockBeat, // Flash the up LED on every quarter note of the MIDI clock.
+}
+
+// How long an LED stays lit for an activity pulse or clock beat.
+const LED_PULSE: Duration = Duration::from_millis(30);
+
+// How long the LEDs show the taps and downbeats after the last tempo tap.
+const TAP_DISPLAY_TIME: Duration = Duration::from_secs(3);
+
+/// Why USB setup failed. Blinked on both octave LEDs as that many flashes, see `blink_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UsbSetupError {
+    MidiClass = 1, // UsbMidiClass rejected the cable count (more than 16).
+    Device = 2,    // UsbDeviceBuilder rejected the configuration, e.g. an invalid control packet size.
+}
+
+/// Signals an error before the main loop takes over the LEDs: both LEDs flash `code` times, then stay dark for a second.
+async fn blink_error(down_led: &mut Led<'_>, up_led: &mut Led<'_>, code: u8) {
+    for _ in 0..code {
+        set_led(down_led, true);
+        set_led(up_led, true);
+        Timer::after_millis(200).await;
+        set_led(down_led, false);
+        set_led(up_led, false);
+        Timer::after_millis(200).await;
+    }
+    Timer::after_millis(1000).await;
+}
+
+fn set_led(led: &mut Led<'_>, on: bool) {
+    led.set(on);
+}
+
+/// Blink state for a pair of octave LEDs, called every 1ms main loop tick: the up LED blinks above the home octave, the
+/// down LED below it, faster the further away. Each blink is a pulse ramping up and back down, which a plain LED shows
+/// as simply on.
+#[derive(Debug, Clone, Copy)]
+struct OctaveBlink {
+    up_timer: i32,
+    down_timer: i32,
+    last_octave: i32,
+}
+
+impl OctaveBlink {
+    const fn new() -> Self {
+        Self { up_timer: 0, down_timer: 0, last_octave: HOME_OCTAVE }
+    }
+
+    fn update(&mut self, up_led: &mut Led<'_>, down_led: &mut Led<'_>, octave: i32, home: i32, blink_period: i32) {
+        if octave != self.last_octave {
+            // Restart the blink at its brightest on every octave change so rapid repeats are still visible.
+            self.up_timer = blink::lit_ticks(blink_period) / 2;
+            self.down_timer = blink::lit_ticks(blink_period) / 2;
+            self.last_octave = octave;
+        }
+        let (up, down, (up_timer, down_timer)) =
+            blink::led_phase(octave, home, blink_period, self.up_timer, self.down_timer);
+        (self.up_timer, self.down_timer) = (up_timer, down_timer);
+        up_led.set_level(up);
+        down_led.set_level(down);
+    }
+}
+
+/// Auto-repeat for a held octave button, like a computer keyboard's key repeat.
+#[derive(Debug, Clone, Copy)]
+pub struct OctaveRepeat {
+    pub initial_delay: Duration, // Hold time before the first repeat.
+    pub repeat_interval: Duration, // Time between repeats after that.
+}
+
+/// A held octave button.
+#[derive(Debug, Clone, Copy)]
+pub struct OctaveHold {
+    pub delta: i32, // +1 for octave up, -1 for octave down.
+    pub next_repeat: Instant,
+}
+
+/// Note repeat ("drum roll"): a held key retriggers its note every `interval`, with the note on for `gate` of it.
+/// Only applies outside mono mode.
+#[derive(Debug, Clone, Copy)]
+pub struct NoteRepeat {
+    pub interval: Duration, // Time from one note-on to the next.
+    pub gate: Duration,     // How long each repeat stays on, shorter than the interval.
+}
+
+impl NoteRepeat {
+    /// Repeats at a fixed rate in Hz, with a 50% gate.
+    pub fn from_hz(hz: u32) -> Self {
+        let interval = Duration::from_micros(1_000_000 / hz.max(1) as u64);
+        NoteRepeat { interval, gate: interval / 2 }
+    }
+
+    /// Repeats `per_beat` times per quarter note at the given tempo, e.g. 4 for 1/16 notes or 2 for 1/8 notes.
+    pub fn at_tempo(bpm: u32, per_beat: u32) -> Self {
+        let interval = Duration::from_micros(60_000_000 / (bpm.max(1) * per_beat.max(1)) as u64);
+        NoteRepeat { interval, gate: interval / 2 }
+    }
+}
+
+/// A key zone: a range of note keys with its own channel, transpose and optional fixed velocity and octave. Zones may
+/// overlap, a key in several zones plays a layered note in each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zone {
+    pub first_key: u8, // Lowest note key (0..NUM_KEYS-1) in the zone.
+    pub last_key: u8,  // Highest note key in the zone, inclusive.
+    pub channel: Channel,
+    pub transpose: i8, // Semitones on top of the octave and global transpose.
+    pub velocity: Option<u8>, // Fixed velocity, or None to use the played velocity.
+    pub octave: Option<i32>, // Its own octave, shifted by the zone octave keys, or None to follow the global octave.
+    pub enabled: bool,
+}
+
+impl Zone {
+    pub fn contains(&self, key: usize) -> bool {
+        self.enabled && (self.first_key as usize..=self.last_key as usize).contains(&key)
+    }
+}
+
+/// One note a key plays on one channel.
+#[derive(Debug, Clone, Copy)]
+pub struct Voice {
+    pub note: i32,
+    pub channel: Channel,
+    pub velocity: u8,
+}
+
+const NO_LAYERS: Vec<Voice, 3> = Vec::new();
+
+/// Auto-swell: holding a note ramps a CC (e.g. CC 11 expression) from `start` to `ceiling` over `ramp_time` on the
+/// note's channel, like a volume swell without pressure sensors. Notes sharing a channel share one ramp, led by the
+/// longest held one. Nothing more is sent once the ceiling is reached.
+#[derive(Debug, Clone, Copy)]
+pub struct Swell {
+    pub cc: u8,
+    pub start: u8,
+    pub ceiling: u8,
+    pub ramp_time: Duration,
+}
+
+impl Swell {
+    /// The CC value after a note has been held for `held`.
+    pub fn value(&self, held: Duration) -> u8 {
+        let ramp = self.ramp_time.as_millis().max(1);
+        let held = held.as_millis().min(ramp);
+        let start = self.start.min(127) as u64;
+        let ceiling = (self.ceiling.min(127) as u64).max(start);
+        (start + (ceiling - start) * held / ramp) as u8
+    }
+}
+
+/// Key count pressure: the number of notes held drives channel pressure on the current channel, a crude "how hard you're
+/// playing" without pressure sensors. The pressure is `per_note` times the held note count, capped at 127, so with the
+/// default of 32 one note sends 32, two 64, three 96 and four or more 127; releasing the last note sends 0. A new value
+/// goes out at most every `min_interval`, the latest one once it has passed, so a chord landing key by key sends one
+/// or two updates rather than one per key.
+#[derive(Debug, Clone, Copy)]
+pub struct CountPressure {
+    pub per_note: u8,
+    pub min_interval: Duration,
+}
+
+impl CountPressure {
+    pub const DEFAULT: Self = Self { per_note: 32, min_interval: Duration::from_millis(20) };
+
+    pub fn pressure(&self, held: usize) -> u8 {
+        (held * self.per_note as usize).min(127) as u8
+    }
+}
+
+/// Which incoming MIDI CC numbers control which setting, e.g. from a foot controller on the host.
+/// CCs are accepted on any channel, unknown CCs and other messages are ignored. None turns a control off.
+/// Default map:
+/// - CC 20: octave, the value is the octave number (clamped to the octave range, deferred like the octave keys).
+/// - CC 21: transpose, 64 is no transpose and every step is a semitone (63 is one down, 66 two up).
+/// - CC 22: MIDI channel of unsplit notes, 0 is channel 1 through 15 for channel 16.
+///
+/// Off by default, as they act on more than this controller:
+/// - "system_reset": a value of 64 or more sends a System Reset (FF), see `SystemMsg`.
+/// - "active_sensing": 64 or more turns periodic Active Sensing on, below 64 turns it off.
+#[derive(Debug, Clone, Copy)]
+pub struct CcMap {
+    pub octave: Option<u8>,
+    pub transpose: Option<u8>,
+    pub channel: Option<u8>,
+    pub system_reset: Option<u8>,
+    pub active_sensing: Option<u8>,
+}
+
+const DEFAULT_CC_MAP: CcMap = CcMap {
+    octave: Some(20),
+    transpose: Some(21),
+    channel: Some(22),
+    system_reset: None,
+    active_sensing: None,
+};
+
+// How often Active Sensing goes out while on. The MIDI spec has receivers time out after 300ms without any message.
+const ACTIVE_SENSING_INTERVAL: Duration = Duration::from_millis(270);
+
+/// Which end of the channel range an MPE zone sits at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpeZone {
+    Lower, // Manager channel 1, members from channel 2 up.
+    Upper, // Manager channel 16, members from channel 15 down.
+}
+
+/// MPE (MIDI Polyphonic Expression) output. The member channel range is picked by the zone and the member count:
+/// a lower zone with 7 members plays on channels 2-8, an upper zone with 7 members on channels 9-15. "members" is
+/// clamped to 1..=15. Global messages such as the sostenuto CC still go out on "channel", so set it to the manager
+/// channel as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mpe {
+    pub zone: MpeZone,
+    pub members: u8,
+}
+
+impl Mpe {
+    pub fn member_count(&self) -> u8 {
+        self.members.clamp(1, 15)
+    }
+
+    pub fn manager(&self) -> Channel {
+        match self.zone {
+            MpeZone::Lower => Channel::C1,
+            MpeZone::Upper => Channel::C16,
+        }
+    }
+
+    /// The nth member channel, counting away from the manager channel.
+    pub fn member(&self, n: u8) -> Channel {
+        let n = n % self.member_count();
+        match self.zone {
+            MpeZone::Lower => Channel::from(1 + n),
+            MpeZone::Upper => Channel::from(14 - n),
+        }
+    }
+}
+
+// How long expression pedal calibration records the pedal's travel.
+const PEDAL_CALIBRATION_TIME: Duration = Duration::from_secs(5);
+
+// How long velocity calibration records the strikes of the contact keys.
+const VELOCITY_CALIBRATION_TIME: Duration = Duration::from_secs(10);
+
+// The mux poll task must finish a sweep within this time, or the hardware watchdog resets the chip. A sweep normally
+// takes well under 2ms.
+const WATCHDOG_TIMEOUT_SECS: u64 = 2;
+
+// No key touched for this long puts the mux into its slow idle scan with the LEDs off, see `set_idle_timeout`.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const IDLE_SCAN_INTERVAL: Duration = Duration::from_millis(10);
+
+// Octave the controller starts in. The LEDs are dark at this octave and blink faster the further away you go.
+const HOME_OCTAVE: i32 = 4;
+
+// Octave LED brightness, 0..=255, with the "led-pwm" feature. Dimmer LEDs draw less current on battery builds.
+const LED_BRIGHTNESS: u8 = 96;
+
+// How close together presses have to land to count as one chord, see `gesture::ChordDetector`. A wider window
+// catches sloppier chords, but a fast roll is then more likely to be taken for one.
+const CHORD_WINDOW: Duration = Duration::from_millis(15);
+
+impl GlobalState {
+    /// The channel a new note plays on, taking the split into account. The split note itself belongs to the upper zone.
+    pub fn channel_for(&self, note: i32) -> Channel {
+        match self.split_point {
+            Some(split) if note < split as i32 => self.lower_channel,
+            Some(_) => self.upper_channel,
+            None => self.channel,
+        }
+    }
+
+    /// The member channel for a new note in MPE mode: the first free one after the last handed out, round robin. With
+    /// more keys held than member channels the next channel in turn is shared, so its pressure then moves both notes.
+    pub fn mpe_channel(&mut self, mpe: Mpe) -> Channel {
+        let count = mpe.member_count();
+        for step in 0..count {
+            let channel = mpe.member(self.mpe_next + step);
+            let busy = self.key_note.held().any(|(key, _)| self.key_channel[key] == channel);
+            if !busy {
+                self.mpe_next = (self.mpe_next + step + 1) % count;
+                return channel;
+            }
+        }
+        let channel = mpe.member(self.mpe_next);
+        self.mpe_next = (self.mpe_next + 1) % count;
+        channel
+    }
+
+    /// Moves the octave up (+1) or down (-1), applying the range and policy. With "freeze_octave" set and a note key
+    /// held, the shift is deferred until the keys are released, see `apply_deferred_octave`.
+    pub fn shift_octave(&mut self, delta: i32) {
+        if self.freeze_octave && self.keys_held() {
+            let octave = self.deferred_octave.unwrap_or(self.octave);
+            self.deferred_octave = Some(self.shifted_octave(octave, delta));
+        } else {
+            self.octave = self.shifted_octave(self.octave, delta);
+        }
+    }
+
+    /// Jumps to `octave`, within the range. Deferred like `shift_octave` with "freeze_octave" set and a note key held.
+    pub fn set_octave(&mut self, octave: i32) {
+        let octave = octave.clamp(self.min_octave, self.max_octave);
+        if self.freeze_octave && self.keys_held() {
+            self.deferred_octave = Some(octave);
+        } else {
+            self.octave = octave;
+        }
+    }
+
+    /// Applies the octave shifts deferred while keys were held, once the last one is released.
+    pub fn apply_deferred_octave(&mut self) {
+        if !self.keys_held() {
+            if let Some(octave) = self.deferred_octave.take() {
+                self.octave = octave;
+            }
+        }
+    }
+
+    /// Clears `buf` and fills it with the note of every key that has one, lowest key first. Chord, zone layer and
+    /// sequencer notes aren't included, nor keys whose press set the split point.
+    pub fn held_notes(&self, buf: &mut Vec<u8, NUM_KEYS>) {
+        buf.clear();
+        for (_, note) in self.key_note.held() {
+            buf.push(note as u8).ok();
+        }
+    }
+
+    /// Sends the key count pressure if the held note count changed, see `CountPressure`. Called from the main loop.
+    pub fn update_count_pressure(&mut self, now: Instant) {
+        let Some(count_pressure) = self.count_pressure else {
+            return;
+        };
+        let mut held: Vec<u8, NUM_KEYS> = Vec::new();
+        self.held_notes(&mut held);
+        let pressure = count_pressure.pressure(held.len());
+        match self.count_pressure_sent {
+            Some((sent, _)) if sent == pressure => return,
+            Some((_, at)) if now < at + count_pressure.min_interval => return,
+            None if pressure == 0 => return, // Nothing sent yet, nothing to reset.
+            _ => {}
+        }
+        self.count_pressure_sent = Some((pressure, now));
+        queue_message(self.cable, MidiMessage::ChannelPressure(self.channel, pressure.into()));
+    }
+
+    /// Stops every note the controller is sounding and forgets it, so keys still held don't send a second note-off on
+    /// release: the internal half of a MIDI panic.
+    pub fn force_release_all(&mut self) {
+        release_all_keys(self);
+        seq_note_off(self);
+    }
+
+    /// Stuck-note safety net: stops the note of every key that has been sounding for longer than "max_note_length", as
+    /// if it had been released, in case a release was missed (a flaky switch). Called from the main loop. This also cuts
+    /// off intentionally long notes, a held pad or one kept by the sostenuto pedal, so set it well above the longest note
+    /// you play. A key still held when its note is cut stays silent until pressed again.
+    pub fn release_overlong_notes(&mut self, now: Instant) {
+        let Some(max) = self.max_note_length else {
+            return;
+        };
+        for key in 0..NUM_KEYS {
+            if !self.key_note.is_held(key) || now < self.key_on_at[key] + max {
+                continue;
+            }
+            if self.sostenuto_keys.holds(key) {
+                // Not released into the pedal, which would hold it on.
+                self.sostenuto_keys.forget(key);
+                stop_note(self, key);
+            } else {
+                release_note(self, key);
+            }
+        }
+    }
+
+    /// True while any note key is held down. Notes only kept by the sostenuto pedal don't count.
+    pub fn keys_held(&self) -> bool {
+        self.key_note.held().any(|(key, _)| !self.sostenuto_keys.is_pending(key))
+    }
+
+    /// Moves one zone's octave up or down within the same range and policy. A zone following the global octave starts
+    /// from it and keeps its own from then on. Notes already sounding stop at the octave they started in.
+    pub fn shift_zone_octave(&mut self, zone: usize, delta: i32) {
+        let global = self.octave;
+        let Some(octave) = self.zones.get(zone).map(|zone| zone.octave.unwrap_or(global)) else {
+            return;
+        };
+        let octave = self.shifted_octave(octave, delta);
+        self.zones[zone].octave = Some(octave);
+    }
+
+    fn shifted_octave(&self, octave: i32, delta: i32) -> i32 {
+        octave::shifted(octave, delta, self.min_octave, self.max_octave, self.octave_policy)
+    }
+
+    /// Returns the sequence number for a new event. Events are only created with the global state locked, so the
+    /// numbers are unique and increase in the order the edges were seen.
+    pub fn take_seq(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
+
+    /// Shifts the octave for an octave button press and starts its hold-to-repeat timer.
+    pub fn press_octave_button(&mut self, delta: i32) {
+        self.shift_octave(delta);
+        self.octave_hold = self.octave_repeat.map(|repeat| OctaveHold {
+            delta,
+            next_repeat: Instant::now() + repeat.initial_delay,
+        });
+    }
+
+    /// Fires any due octave repeats for a held octave button. Called from the main loop.
+    pub fn repeat_octave(&mut self, now: Instant) {
+        if let (Some(hold), Some(repeat)) = (self.octave_hold, self.octave_repeat) {
+            if now >= hold.next_repeat {
+                self.shift_octave(hold.delta);
+                self.octave_hold = Some(OctaveHold {
+                    delta: hold.delta,
+                    next_repeat: now + repeat.repeat_interval,
+                });
+            }
+        }
+    }
+
+    /// Starts velocity trim calibration: strike every key once at the same reference force, then call
+    /// `finish_trim_calibration`. Keys still play while calibrating, untrimmed.
+    pub fn start_trim_calibration(&mut self) {
+        self.trim_calibration = Some(velocity::TrimCalibration::new());
+    }
+
+    /// Ends trim calibration and stores the offsets for the keys that were struck.
+    pub fn finish_trim_calibration(&mut self) {
+        if let Some(calibration) = self.trim_calibration.take() {
+            calibration.apply(&mut self.velocity_trim);
+        }
+    }
+
+    /// Starts expression pedal calibration: sweep the pedal fully up and down a few times within the next 5 seconds.
+    /// The new travel is stored when the time is up. The pedal keeps sending CC 11 meanwhile, on its old calibration.
+    pub fn start_pedal_calibration(&mut self) {
+        self.pedal_calibration = Some((analog::PedalRangeCalibration::new(), Instant::now() + PEDAL_CALIBRATION_TIME));
+    }
+
+    /// Starts velocity calibration for keys with a second or third contact: within the next 10 seconds, strike keys as
+    /// softly and as hard as you play. The slowest and fastest strike become the ends of the velocity range when the
+    /// time is up. Keys play on the old range meanwhile.
+    pub fn start_velocity_calibration(&mut self) {
+        let until = Instant::now() + VELOCITY_CALIBRATION_TIME;
+        self.velocity_calibration = Some((velocity::VelocityCalibration::new(), until));
+    }
+
+    /// Records a contact key strike while velocity calibration runs, and stores the new range once its time is up. A
+    /// keybed has one kind of key, so the range goes to both the dual and the three-contact settings.
+    pub fn record_strike(&mut self, time: Duration, now: Instant) {
+        if let Some((calibration, until)) = self.velocity_calibration.as_mut() {
+            calibration.record(time);
+            if now >= *until {
+                calibration.apply(&mut self.contact_velocity);
+                calibration.apply(&mut self.triple_velocity.0);
+                self.velocity_calibration = None;
+            }
+        }
+    }
+
+    /// What the mux channel does, if anything.
+    pub fn key_function(&self, index: usize) -> Option<KeyFunction> {
+        self.key_map.get(index).copied().flatten()
+    }
+
+    /// Starts a key learn: press the keys that should play notes 0, 1, 2, ... in order, from the lowest. Only channels
+    /// that are note keys in KEYS can be learned, so the function buttons stay where they are. Once every note key
+    /// was pressed the new map is used and saved to flash; pressing the learn button again aborts.
+    /// While learning the up LED is lit and the down LED pulses for every key learned. A key pressed twice is
+    /// rejected with a fast blink of the down LED. Completion lights both LEDs for a second, an abort blinks both.
+    pub fn begin_key_learn(&mut self) {
+        release_all_keys(self);
+        self.key_learn = Some(Vec::new());
+        self.learn_feedback = None;
+    }
+
+    /// A channel pressed during a key learn.
+    fn learn_key(&mut self, index: usize, function: KeyFunction) {
+        let Some(learned) = self.key_learn.as_mut() else {
+            return;
+        };
+        let feedback = match function {
+            KeyFunction::KeyLearn => {
+                self.key_learn = None;
+                LearnFeedback::Aborted
+            }
+            KeyFunction::Note(_) if learned.contains(&(index as u8)) => LearnFeedback::Rejected,
+            KeyFunction::Note(_) => {
+                learned.push(index as u8).ok();
+                if learned.is_full() {
+                    self.key_map = learned_key_map(learned);
+                    self.key_map_unsaved = true;
+                    self.key_learn = None;
+                    LearnFeedback::Complete
+                } else {
+                    LearnFeedback::Accepted
+                }
+            }
+            _ => return, // Function buttons keep their place.
+        };
+        self.learn_feedback = Some((feedback, Instant::now()));
+    }
+
+    /// The LED states (up, down) a key learn shows, or None if the LEDs are free for the LED mode.
+    pub fn learn_leds(&self, now: Instant) -> Option<(bool, bool)> {
+        let learning = self.key_learn.is_some();
+        let feedback = self.learn_feedback.filter(|&(_, at)| now < at + LEARN_FEEDBACK_TIME);
+        let fast_blink = |at: Instant| ((now - at).as_millis() / 100) & 1 == 0;
+        match feedback {
+            Some((LearnFeedback::Accepted, at)) if learning => Some((true, now < at + LED_PULSE * 3)),
+            Some((LearnFeedback::Rejected, at)) if learning => Some((true, fast_blink(at))),
+            Some((LearnFeedback::Complete, _)) => Some((true, true)),
+            Some((LearnFeedback::Aborted, at)) => Some((fast_blink(at), fast_blink(at))),
+            _ if learning => Some((true, false)),
+            _ => None,
+        }
+    }
+
+    /// Records a tap of the tap tempo key and sets the sequencer tempo once there are enough taps.
+    pub fn tap(&mut self, now: Instant) {
+        if let Some(bpm) = self.tap_tempo.tap(now) {
+            self.sequencer.bpm = bpm.clamp(20, 300);
+        }
+        self.last_tap = Some(now);
+    }
+
+    /// The LED states (up, down) shown for a while after a tempo tap: the down LED flashes on every tap and the up LED
+    /// on every sequencer downbeat. None if the LEDs are free for the LED mode.
+    pub fn tap_leds(&self, now: Instant) -> Option<(bool, bool)> {
+        let last_tap = self.last_tap.filter(|&at| now < at + TAP_DISPLAY_TIME)?;
+        let downbeat = self.last_downbeat.is_some_and(|at| now < at + LED_PULSE);
+        Some((downbeat, now < last_tap + LED_PULSE))
+    }
+
+    /// True if an octave button is mapped anywhere, so the octave LEDs have something to show.
+    pub fn has_octave_buttons(&self) -> bool {
+        self.key_map
+            .iter()
+            .any(|function| matches!(function, Some(KeyFunction::OctaveUp | KeyFunction::OctaveDown)))
+    }
+
+    /// Moves the unsplit channel by `delta`, within channels 1..=16. Held notes keep their channel for the note-off.
+    pub fn shift_channel(&mut self, delta: i8) {
+        let channel = (u8::from(self.channel) as i8 + delta).clamp(0, 15);
+        self.channel = Channel::from(channel as u8);
+    }
+
+    /// The octave the LEDs treat as "centre", kept inside the configured range.
+    pub fn home_octave(&self) -> i32 {
+        HOME_OCTAVE.clamp(self.min_octave, self.max_octave)
+    }
+
+    /// Number of 1ms main loop ticks between LED toggles for the current octave, see `blink::blink_period`.
+    pub fn led_blink_period(&self) -> i32 {
+        self.led_blink_period_for(self.octave)
+    }
+
+    /// The blink period for any octave, e.g. a zone's own.
+    pub fn led_blink_period_for(&self, octave: i32) -> i32 {
+        blink::blink_period(octave, self.home_octave(), self.min_octave, self.max_octave)
+    }
+}
+
+static GLOBAL_STATE: Mutex<CriticalSectionRawMutex, RefCell<GlobalState>> =
+    Mutex::new(RefCell::new(GlobalState {
+        key_note: held::KeyNotes::new(),
+        key_cable: [CableNumber::Cable0; NUM_KEYS],
+        key_channel: [Channel::C1; NUM_KEYS],
+        key_velocity: [0; NUM_KEYS],
+        octave: HOME_OCTAVE,
+        transpose: 0,
+        min_octave: 0,
+        max_octave: 8,
+        octave_policy: OctavePolicy::Clamp,
+        octave_repeat: Some(OctaveRepeat {
+            initial_delay: Duration::from_millis(500),
+            repeat_interval: Duration::from_millis(200),
+        }),
+        octave_hold: None,
+        freeze_octave: false,
+        deferred_octave: None,
+        mono: None,
+        mono_stack: held::MonoStack::new(),
+        layout: Layout::Chromatic,
+        key_table: KeyTable::Chromatic,
+        drum_map: GM_DRUM_MAP,
+        led_mode: LedMode::Octave,
+        last_beat: None,
+        last_note: None,
+        cable: CableNumber::Cable0,
+        channel: Channel::C1,
+        split_point: None,
+        lower_channel: Channel::C2,
+        upper_channel: Channel::C1,
+        split_learn: false,
+        next_seq: 0,
+        coalesce: false,
+        min_gate: None,
+        max_note_length: None,
+        octave_settle: None,
+        settling_presses: Vec::new(),
+        key_on_at: [Instant::from_ticks(0); NUM_KEYS],
+        analog_keys: [analog::AnalogKey::new(analog::AnalogKeyCalibration::DEFAULT); NUM_KEYS],
+        piezo_pads: [analog::PiezoPad::new(analog::PiezoCalibration::DEFAULT); NUM_KEYS],
+        muted: false,
+        resume_on_unmute: false,
+        velocity_trim: velocity::VelocityTrim::NONE,
+        trim_calibration: None,
+        velocity_curve: velocity::Curve::Linear,
+        velocity_floor: 1,
+        humanize: 0,
+        rng: velocity::XorShift32::new(1), // Reseeded at startup.
+        round_robin: None,
+        sostenuto: false,
+        sostenuto_keys: held::Sostenuto::new(),
+        release_velocity: Value7::new(0),
+        note_off_as_zero_velocity_on: false,
+        accent_active: false,
+        accent_velocity: 127,
+        full_velocity: false,
+        chord_memory: None,
+        key_chord: [NO_CHORD; NUM_KEYS],
+        chord_capture: false,
+        zones: Vec::new(),
+        key_layers: [NO_LAYERS; NUM_KEYS],
+        swell: None,
+        count_pressure: None,
+        count_pressure_sent: None,
+        channel_remap: messages::IDENTITY_REMAP,
+        swell_start: [None; NUM_KEYS],
+        swell_sent: [None; 16],
+        mpe: None,
+        mpe_next: 0,
+        key_pressure: [0; NUM_KEYS],
+        pedal: analog::PedalCalibration::DEFAULT,
+        pedal_calibration: None,
+        pedal_sent: None,
+        contact_keys: [velocity::DualContactKey::new(); NUM_KEYS],
+        contact_velocity: velocity::DualContactVelocity::DEFAULT,
+        triple_keys: [velocity::TripleContactKey::new(); NUM_KEYS],
+        triple_velocity: velocity::TripleContactVelocity::DEFAULT,
+        velocity_calibration: None,
+        sequencer: sequencer::SequencerState::new(),
+        macros: performance::MacroPlayer::new(),
+        seq_edit: false,
+        seq_sounding: None,
+        tap_tempo: sequencer::TapTempo::new(),
+        last_tap: None,
+        last_downbeat: None,
+        key_map: DEFAULT_KEY_MAP,
+        key_learn: None,
+        learn_feedback: None,
+        key_map_unsaved: false,
+        debounce_ms: 20,
+        release_debounce_ms: None,
+        cc_map: DEFAULT_CC_MAP,
+        active_sensing: false,
+        note_repeat: None,
+        repeat_at: [None; NUM_KEYS],
+        repeat_gate_open: [false; NUM_KEYS],
+        portamento_time: 20,
+        glide_in_mono: false,
+        glide_held: false,
+        portamento_on: false,
+        mirror_velocity_to_cc: None,
+        long_press_map: DEFAULT_LONG_PRESS_MAP,
+        pending_presses: Vec::new(),
+    }));
+
+type EventQueue = Mutex<CriticalSectionRawMutex, RefCell<queue::Events>>;
+
+/// Puts a note-on that failed to send back in ON_EVENTS, in sequence order so it goes out before anything newer. A
+/// note-on that no longer fits is dropped, see `push_note_off`.
+fn requeue_note_on(event: NoteEvent) {
+    ON_EVENTS.lock(|on_events| queue::insert_in_order(&mut on_events.borrow_mut(), event).ok());
+}
+
+/// Puts a note-off that failed to send, or had to wait for its note-on, back in OFF_EVENTS in sequence order. Never
+/// dropped, like `push_note_off`.
+fn requeue_note_off(event: NoteEvent) {
+    if let Err(event) = OFF_EVENTS.lock(|off_events| queue::insert_in_order(&mut off_events.borrow_mut(), event)) {
+        push_note_off(event);
+    }
+}
+
+// Separate mutexes for note ON and note OFF events. Where both are locked, OFF_EVENTS is always locked first.
+static ON_EVENTS: EventQueue = Mutex::new(RefCell::new(Vec::new()));
+static OFF_EVENTS: EventQueue =
+    Mutex::new(RefCell::new(Vec::new()));
+
+// Note-offs that didn't fit in OFF_EVENTS: one bit per note, per channel, per cable, so it can never fill up.
+static OFF_OVERFLOW: Mutex<CriticalSectionRawMutex, RefCell<[[u128; 16]; NUM_CABLES as usize]>> =
+    Mutex::new(RefCell::new([[0; 16]; NUM_CABLES as usize]));
+
+// Note-offs held back by the minimum gate, each with the earliest time it may be sent. Sent before the note-ons.
+static DEFERRED_OFFS: Mutex<CriticalSectionRawMutex, RefCell<Vec<(NoteEvent, Instant), 32>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Queues a note-off without ever dropping it, since a lost note-off is a stuck note, see `queue::push_off`. What
+/// doesn't fit in OFF_EVENTS goes into OFF_OVERFLOW, sent before the next note-ons.
+fn push_note_off(event: NoteEvent) {
+    OFF_EVENTS.lock(|off_events| {
+        ON_EVENTS.lock(|on_events| {
+            OFF_OVERFLOW.lock(|overflow| {
+                let (mut off_events, mut on_events) = (off_events.borrow_mut(), on_events.borrow_mut());
+                queue::push_off(&mut off_events, &mut on_events, &mut *overflow.borrow_mut(), event);
+            });
+        });
+    });
+}
+
+/// A queued MIDI message other than a note, e.g. a CC. Sent in queue order after the note events.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageEvent {
+    pub message: MidiMessage,
+    pub cable: CableNumber,
+}
+
+static MESSAGE_EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<MessageEvent, 64>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Queues a message to be sent from the main loop. Dropped if the queue is full.
+pub fn queue_message(cable: CableNumber, message: MidiMessage) {
+    MESSAGE_EVENTS.lock(|message_events| {
+        message_events.borrow_mut().push(MessageEvent { message, cable }).ok();
+    });
+}
+
+/// Queues several messages as one unit: either all of them go in the queue, back to back, or none does if there isn't
+/// room for all. Returns false in that case.
+pub fn queue_messages(cable: CableNumber, messages: &[MidiMessage]) -> bool {
+    MESSAGE_EVENTS.lock(|message_events| {
+        let mut events = message_events.borrow_mut();
+        if events.capacity() - events.len() < messages.len() {
+            return false;
+        }
+        for &message in messages {
+            events.push(MessageEvent { message, cable }).ok();
+        }
+        true
+    })
+}
+
+/// Queues Bank Select MSB (CC 0), Bank Select LSB (CC 32) and then the program change on the current channel, the
+/// order synths expect: the bank only takes effect with the program change. The three are queued as one unit, so no
+/// other message gets between them; note events, which have queues of their own, can go out between them if the host
+/// stops accepting packets halfway, which synths don't mind as the bank waits for the program change.
+fn queue_patch_select(state: &GlobalState, bank_msb: u8, bank_lsb: u8, program: u8) {
+    queue_messages(state.cable, &messages::patch_select(state.channel, bank_msb, bank_lsb, program));
+}
+
+/// Queues All Notes Off (CC 123) on every channel of every cable.
+fn queue_all_notes_off() {
+    for cable in 0..NUM_CABLES {
+        let Ok(cable) = CableNumber::try_from(cable) else {
+            continue;
+        };
+        for channel in 0..16 {
+            let message = MidiMessage::ControlChange(Channel::from(channel), 123.into(), 0.into());
+            queue_message(cable, message);
+        }
+    }
+}
+
+/// The notes a key plays: its stored note and zone layers, each with the intervals of its chord in chord memory mode.
+/// Chord notes outside the MIDI note range are skipped.
+fn key_notes(state: &GlobalState, key: usize) -> Vec<Voice, 20> {
+    let Some(note) = state.key_note.note(key) else {
+        return Vec::new();
+    };
+    let stored = Voice {
+        note,
+        channel: state.key_channel[key],
+        velocity: state.key_velocity[key],
+    };
+    let mut notes: Vec<Voice, 20> = Vec::new();
+    for &root in core::iter::once(&stored).chain(state.key_layers[key].iter()) {
+        for note in chord::voicing(root.note, &state.key_chord[key]) {
+            notes.push(Voice { note, ..root }).ok();
+        }
+    }
+    notes
+}
+
+/// Stops everything: note-offs for the held keys and All Notes Off on every channel, sent out from the main loop.
+/// Called at boot after any reset other than a plain power-on, so notes a crashed session left hanging on the host
+/// are cleared.
+pub fn shutdown() {
+    GLOBAL_STATE.lock(|global_state| release_all_keys(&mut global_state.borrow_mut()));
+    queue_all_notes_off();
+}
+
+/// Makes a supply dip below the brown-out threshold reset the chip cleanly instead of letting it run on garbled: flash
+/// is cut off first, so a settings save in progress can't write junk, then the chip resets and the next boot flushes
+/// the notes the host still holds (see `main`). The user hears held notes stop a moment after the dip, once USB is back.
+/// The threshold itself is an analog setting the second stage bootloader programs, ESP-IDF's
+/// `CONFIG_ESP_BROWNOUT_DET_LVL` (level 7, about 2.5V, by default). To change it, build the bootloader with another
+/// level and flash it with `espflash flash --bootloader`.
+fn enable_brown_out_reset() {
+    // Safety: the brown-out register is only written here, once at boot.
+    let rtc = unsafe { &*esp_hal::peripherals::LPWR::ptr() };
+    rtc.brown_out().modify(|_, w| unsafe {
+        w.brown_out_close_flash_ena()
+            .set_bit()
+            .brown_out_pd_rf_ena()
+            .set_bit()
+            .brown_out_rst_wait()
+            .bits(0x3ff) // RTC slow clock cycles from detection to reset, ESP-IDF's value.
+            .brown_out_rst_ena()
+            .set_bit()
+            .brown_out_rst_sel()
+            .set_bit()
+            .brown_out_ana_rst_en()
+            .set_bit()
+            .brown_out_ena()
+            .set_bit()
+    });
+}
+
+/// Queues the note-on for a key from its stored note, velocity, channel and cable.
+fn queue_note_on(state: &mut GlobalState, key: usize) {
+    state.key_on_at[key] = Instant::now();
+    for voice in key_notes(state, key) {
+        queue_voice_on(state, voice, state.key_cable[key]);
+    }
+}
+
+/// Queues the note-off for a key from its stored note, channel and cable, with the release velocity. With a minimum
+/// gate set, a note-off coming too soon after its note-on waits in DEFERRED_OFFS until the gate has passed.
+fn queue_note_off(state: &mut GlobalState, key: usize) {
+    let due = state.min_gate.map(|gate| state.key_on_at[key] + gate).filter(|&due| Instant::now() < due);
+    for voice in key_notes(state, key) {
+        match due {
+            Some(due) => {
+                let event = note_off_event(state, voice, state.key_cable[key]);
+                let deferred = DEFERRED_OFFS.lock(|deferred| deferred.borrow_mut().push((event, due)).is_ok());
+                if !deferred {
+                    push_note_off(event); // Never dropped: better a short note than a stuck one.
+                }
+            }
+            None => queue_voice_off(state, voice, state.key_cable[key]),
+        }
+    }
+}
+
+/// Queues a note-on for a single voice, whether it belongs to a key or e.g. the sequencer. A note-off for the same
+/// note still held back by the minimum gate is made due right away, so it goes out before this note-on instead of
+/// cutting it short.
+fn queue_voice_on(state: &mut GlobalState, voice: Voice, cable: CableNumber) {
+    DEFERRED_OFFS.lock(|deferred| {
+        let now = Instant::now();
+        for (off, due) in deferred.borrow_mut().iter_mut() {
+            if off.note == voice.note && off.channel == voice.channel && off.cable == cable {
+                *due = now;
+            }
+        }
+    });
+    state.last_note = Some((voice.note as u8, Instant::now()));
+    let event = NoteEvent::new(voice.note, voice.velocity, voice.channel, cable, state.take_seq());
+    ON_EVENTS.lock(|on_events| {
+        // Lock the note-on events.
+        let mut events = on_events.borrow_mut();
+        if events.len() < 128 {
+            events.push(event).ok(); // Push the note-on event. All events in this list will be sent to the MIDI device in the main loop.
+        }
+    });
+}
+
+/// Queues a note-off for a single voice, with the release velocity.
+fn queue_voice_off(state: &mut GlobalState, voice: Voice, cable: CableNumber) {
+    let event = note_off_event(state, voice, cable);
+    push_note_off(event); // All note-off events will be sent to the MIDI device in the main loop.
+}
+
+fn note_off_event(state: &mut GlobalState, voice: Voice, cable: CableNumber) -> NoteEvent {
+    NoteEvent::new(voice.note, u8::from(state.release_velocity), voice.channel, cable, state.take_seq())
+}
+
+/// A note key's switch closed. Switch keys always play at full velocity; on a key with a `KeyFunction::SecondContact`
+/// or `KeyFunction::ThirdContact` this is the first contact, which only starts timing the strike, see `press_contact`.
+fn press_key(state: &mut GlobalState, key: u8) {
+    if state.key_map.contains(&Some(KeyFunction::ThirdContact(key))) {
+        state.triple_keys[key as usize].first_contact(Instant::now());
+    } else if state.key_map.contains(&Some(KeyFunction::SecondContact(key))) {
+        state.contact_keys[key as usize].first_contact(Instant::now());
+    } else {
+        press_note(state, key as usize, 127);
+    }
+}
+
+/// The second contact of a key closed. On a dual-contact key this starts its note with the velocity of the time since
+/// the first contact closed, see `velocity::DualContactVelocity`; a three-contact key only notes the time, its note
+/// starts on the bottom contact, see `press_bottom_contact`.
+fn press_contact(state: &mut GlobalState, key: u8) {
+    let now = Instant::now();
+    if state.key_map.contains(&Some(KeyFunction::ThirdContact(key))) {
+        return state.triple_keys[key as usize].second_contact(now);
+    }
+    let Some(time) = state.contact_keys[key as usize].second_contact(now) else {
+        return; // The first contact's edge was missed, the key plays on its next strike.
+    };
+    state.record_strike(time, now);
+    let velocity = state.contact_velocity.velocity_for(time);
+    press_note(state, key as usize, velocity);
+}
+
+/// The bottom contact of a three-contact key closed: starts its note with the velocity of the time since the first
+/// contact closed, see `velocity::TripleContactVelocity`. A held key pressed back into the bottom sends channel
+/// pressure 127 on the note's channel until it comes up off it again, see `release_bottom_contact`.
+fn press_bottom_contact(state: &mut GlobalState, key: usize) {
+    let now = Instant::now();
+    match state.triple_keys[key].third_contact(now) {
+        Some(timing) => {
+            state.record_strike(timing.first_to_third, now);
+            let velocity = state.triple_velocity.velocity_for(&timing);
+            press_note(state, key, velocity);
+        }
+        None if state.triple_keys[key].is_bottomed() && state.key_note.is_held(key) => {
+            let message = MidiMessage::ChannelPressure(state.key_channel[key], 127.into());
+            queue_message(state.key_cable[key], message);
+        }
+        None => {} // The first contact's edge was missed.
+    }
+}
+
+/// The bottom contact of a three-contact key opened: ends the aftertouch of a key pressed back into it.
+fn release_bottom_contact(state: &mut GlobalState, key: usize) {
+    if state.triple_keys[key].is_bottomed() && state.key_note.is_held(key) {
+        let message = MidiMessage::ChannelPressure(state.key_channel[key], 0.into());
+        queue_message(state.key_cable[key], message);
+    }
+    state.triple_keys[key].third_release();
+}
+
+/// Starts the note for a note key (`KeyFunction::Note`) at the current octave.
+/// By default the octave is the one set when the key's edge is handled, so a note key and an octave button pressed
+/// together, in the same sweep, play in whichever octave the scan reaches first. With "octave_settle" set (a few
+/// milliseconds is enough) the note waits that long and takes the octave as it stands then, so the octave button
+/// always wins, at the cost of that much latency on every note. See `start_settled_notes`.
+fn press_note(state: &mut GlobalState, key: usize, velocity: u8) {
+    if let Some(settle) = state.octave_settle.filter(|_| state.layout != Layout::Drums) {
+        let press = SettlingPress { key: key as u8, velocity, until: Instant::now() + settle };
+        if state.settling_presses.push(press).is_ok() {
+            return;
+        } // Too many at once: this one starts right away.
+    }
+    start_note(state, key, velocity);
+}
+
+/// Starts the notes of the presses held back by "octave_settle" once they are due. Called from the main loop. A key
+/// released before its press is due starts its note right then, see `release_note`, so a short tap still sounds.
+fn start_settled_notes(state: &mut GlobalState, now: Instant) {
+    while let Some(position) = state.settling_presses.iter().position(|press| now >= press.until) {
+        let press = state.settling_presses.remove(position);
+        start_note(state, press.key as usize, press.velocity);
+    }
+}
+
+/// Starts the note for a note key at the current octave, see `press_note`.
+fn start_note(state: &mut GlobalState, key: usize, velocity: u8) {
+    let mut layers = NO_LAYERS;
+    let (note, velocity, channel) = if state.layout == Layout::Drums {
+        let pad = state.drum_map[key];
+        (pad.note as i32, shape_velocity(state, key, pad.note as i32, pad.velocity), DRUM_CHANNEL)
+    } else {
+        let semitone = state.key_table.semitones()[key] as i32;
+        //Shifts note to current octave and transpose.
+        let note = octave::chromatic_note(semitone, state.octave, state.transpose);
+        if state.split_learn {
+            // The split button was pressed: this key sets the split point instead of playing.
+            state.split_point = Some(note as u8);
+            state.split_learn = false;
+            return;
+        }
+        let velocity = shape_velocity(state, key, note, velocity);
+        if let Some(mpe) = state.mpe {
+            (note, velocity, state.mpe_channel(mpe))
+        } else if state.zones.is_empty() {
+            (note, velocity, state.channel_for(note))
+        } else {
+            // Key zones: the key plays in every enabled zone containing it. The first one is stored like a normal note,
+            // the rest as layers.
+            let mut voices: Vec<Voice, 4> = Vec::new();
+            for zone in state.zones.iter().filter(|zone| zone.contains(key)) {
+                let own_octave = zone.octave.map_or(0, |octave| (octave - state.octave) * 12);
+                let note = note + own_octave + zone.transpose as i32;
+                if (0..=127).contains(&note) {
+                    let velocity = velocity::resolve(state.full_velocity, zone.velocity, velocity);
+                    voices.push(Voice { note, channel: zone.channel, velocity }).ok();
+                }
+            }
+            if voices.is_empty() {
+                return; // No zone plays this key.
+            }
+            let first = voices.remove(0);
+            layers.extend(voices);
+            (first.note, first.velocity, first.channel)
+        }
+    };
+    if !(0..=127).contains(&note) {
+        return; // Transposed out of the MIDI note range, the key stays silent.
+    }
+    if state.sostenuto_keys.take_pending(key) {
+        stop_note(state, key); // Restruck while the pedal still holds its last note.
+    }
+    state.key_note.press(key, note); // Store the note for note-off events.
+    state.key_layers[key] = layers;
+    state.key_chord[key] = match (&state.chord_memory, state.layout) {
+        (Some(chord), Layout::Chromatic) => chord.clone(),
+        _ => NO_CHORD,
+    };
+    state.key_velocity[key] = velocity;
+    state.key_cable[key] = state.cable; // The note-off must leave on the same cable.
+    state.key_channel[key] = channel; // And on the same channel.
+    if state.swell.is_some() {
+        state.swell_start[key] = Some(Instant::now());
+    }
+    match state.mono {
+        Some(priority) => mono_press(state, key, priority),
+        None => {
+            queue_note_on(state, key);
+            if let Some(repeat) = state.note_repeat {
+                // The first note is played now, the repeat task closes its gate and retriggers it.
+                state.repeat_at[key] = Some(Instant::now() + repeat.gate);
+                state.repeat_gate_open[key] = true;
+            }
+        }
+    }
+}
+
+/// Velocity after trim (or trim calibration), curve and floor (see `velocity::shape`), round robin and humanize, or the
+/// accent velocity while the accent key is held. 127 while the full velocity key is held, which wins over the accent.
+fn shape_velocity(state: &mut GlobalState, key: usize, note: i32, velocity: u8) -> u8 {
+    let trim = match state.trim_calibration.as_mut() {
+        Some(calibration) => {
+            calibration.record(key, velocity);
+            None
+        }
+        None => Some(&state.velocity_trim),
+    };
+    let velocity = velocity::shape(velocity, key, trim, state.velocity_curve, state.velocity_floor);
+    if state.full_velocity {
+        return 127;
+    }
+    if state.accent_active {
+        return state.accent_velocity;
+    }
+    let velocity = match state.round_robin.as_mut() {
+        Some(round_robin) => round_robin.next(note, Instant::now()),
+        None => velocity,
+    };
+    let humanize = state.humanize;
+    state.rng.humanize(velocity, humanize)
+}
+
+/// Stops the note a note key started, even if the octave changed since.
+fn release_note(state: &mut GlobalState, key: usize) {
+    if let Some(position) = state.settling_presses.iter().position(|press| press.key as usize == key) {
+        let press = state.settling_presses.remove(position);
+        start_note(state, key, press.velocity);
+    }
+    if !state.key_note.is_held(key) {
+        return; // The press was used to set the split point, there is no note to stop.
+    }
+    match state.mono {
+        Some(priority) => {
+            mono_release(state, key, priority);
+            state.swell_start[key] = None;
+            state.key_note.release(key);
+        }
+        None => {
+            // Unless the sostenuto pedal holds it until it lifts.
+            if !state.sostenuto_keys.hold_release(key) {
+                stop_note(state, key);
+            }
+        }
+    }
+}
+
+/// Queues the note-off for a key's stored note and forgets it.
+fn stop_note(state: &mut GlobalState, key: usize) {
+    // A repeating key may be between repeats with its note already off.
+    if state.repeat_at[key].is_none() || state.repeat_gate_open[key] {
+        queue_note_off(state, key);
+    }
+    state.repeat_at[key] = None;
+    state.swell_start[key] = None;
+    state.key_note.release(key);
+}
+
+/// Sends note-offs for every held key and forgets them, so nothing is left hanging when the key meaning changes.
+fn release_all_keys(state: &mut GlobalState) {
+    for key in state.key_note.held_keys() {
+        stop_note(state, key);
+    }
+    state.mono_stack.clear();
+    state.sostenuto_keys = held::Sostenuto::new();
+}
+
+/// Sostenuto pedal down: holds only the notes whose keys are down right now. Keys pressed later play and release as
+/// usual. Mirrored to the host as CC 66. Not used in mono mode.
+fn press_sostenuto(state: &mut GlobalState) {
+    state.sostenuto = true;
+    if state.mono.is_none() {
+        state.sostenuto_keys.capture(&state.key_note);
+    }
+    queue_message(state.cable, MidiMessage::ControlChange(state.channel, 66.into(), 127.into()));
+}
+
+/// Sostenuto pedal up: stops the held notes whose keys were released meanwhile.
+fn release_sostenuto(state: &mut GlobalState) {
+    state.sostenuto = false;
+    for key in state.sostenuto_keys.pedal_up() {
+        stop_note(state, key);
+    }
+    queue_message(state.cable, MidiMessage::ControlChange(state.channel, 66.into(), 0.into()));
+}
+
+/// Switches between the chromatic and drum pad layouts. Held notes are stopped first.
+pub fn set_layout(state: &mut GlobalState, layout: Layout) {
+    if state.layout != layout {
+        release_all_keys(state);
+        state.layout = layout;
+    }
+}
+
+/// Turns mono mode on (with its note priority) or off. Held notes are stopped first.
+pub fn set_mono(state: &mut GlobalState, mono: Option<NotePriority>) {
+    release_all_keys(state);
+    state.mono = mono;
+    update_portamento(state);
+}
+
+/// Sends portamento on (CC 5 with the time, then CC 65 on) or off (CC 65 off) when it should change: it's on while the
+/// glide key is held, or in mono mode with "glide_in_mono" set.
+fn update_portamento(state: &mut GlobalState) {
+    let on = state.glide_held || (state.glide_in_mono && state.mono.is_some());
+    if on == state.portamento_on {
+        return;
+    }
+    state.portamento_on = on;
+    if on {
+        queue_message(state.cable, MidiMessage::ControlChange(state.channel, 5.into(), state.portamento_time.into()));
+    }
+    let value = if on { 127 } else { 0 };
+    queue_message(state.cable, MidiMessage::ControlChange(state.channel, 65.into(), value.into()));
+}
+
+/// Portamento time pot reading: stored, and sent as CC 5 right away while portamento is on.
+#[cfg(feature = "analog-keys")]
+fn portamento_pot(state: &mut GlobalState, value: u16) {
+    let time = analog::to_7bit(value, analog::ADC_FULL_SCALE);
+    if time != state.portamento_time {
+        state.portamento_time = time;
+        if state.portamento_on {
+            queue_message(state.cable, MidiMessage::ControlChange(state.channel, 5.into(), time.into()));
+        }
+    }
+}
+
+/// Turns MPE mode on or off. Held notes are stopped first, then the MPE Configuration Message (RPN 6 on the manager
+/// channel with the member count) tells the synth about the zone. Turning it off sends a member count of 0.
+pub fn set_mpe(state: &mut GlobalState, mpe: Option<Mpe>) {
+    release_all_keys(state);
+    let (zone, members) = match (mpe, state.mpe) {
+        (Some(mpe), _) => (mpe, mpe.member_count()),
+        (None, Some(old)) => (old, 0),
+        (None, None) => return,
+    };
+    state.mpe = mpe;
+    state.mpe_next = 0;
+    // RPN 6 select, data entry, then RPN null so later data entry CCs don't change it.
+    for (control, value) in [(101, 0), (100, 6), (6, members), (101, 127), (100, 127)] {
+        let message = MidiMessage::ControlChange(zone.manager(), control.into(), value.into());
+        queue_message(state.cable, message);
+    }
+}
+
+/// Mute toggle. Muting sends All Notes Off right away and drops every note event until unmuted, while the keys are still
+/// tracked as usual. Unmuting re-sounds the keys still held if "resume_on_unmute" is set; by default they stay silent
+/// until pressed again, so nothing surprises the room.
+fn toggle_mute(state: &mut GlobalState) {
+    state.muted = !state.muted;
+    if state.muted {
+        queue_all_notes_off();
+    } else if state.resume_on_unmute {
+        for key in 0..NUM_KEYS {
+            let sounding = match state.mono {
+                Some(_) => state.mono_stack.sounding() == Some(key),
+                None => state.key_note.is_held(key),
+            };
+            if sounding {
+                queue_note_on(state, key);
+                if let (Some(repeat), None) = (state.note_repeat, state.mono) {
+                    state.repeat_at[key] = Some(Instant::now() + repeat.gate);
+                    state.repeat_gate_open[key] = true;
+                }
+            }
+        }
+    }
+}
+
+/// Chord memory button. With keys held, their chord becomes the template (intervals above the lowest held note) and
+/// chord memory turns on; with no keys held it turns chord memory off.
+fn press_chord_memory(state: &mut GlobalState) {
+    let mut held = Vec::new();
+    state.held_notes(&mut held);
+    state.chord_memory = chord::template(held.iter().map(|&note| note as i32));
+}
+
+/// Called with the mux channels of every chord detected. In chord capture mode the chord's note keys become the
+/// chord memory template, otherwise chords play as their separate notes and nothing more happens here.
+fn chord_handler(channels: &[u8]) {
+    GLOBAL_STATE.lock(|global_state| {
+        let mut state = global_state.borrow_mut();
+        if !state.chord_capture {
+            return;
+        }
+        let keys = channels.iter().filter_map(|&channel| match state.key_function(channel as usize) {
+            Some(KeyFunction::Note(key)) => Some(key as i32),
+            _ => None,
+        });
+        if keys.clone().count() >= 2 {
+            state.chord_memory = chord::template(keys);
+            state.chord_capture = false;
+        }
+    });
+}
+
+/// Sequencer start/stop button. Starting always begins at the first step.
+fn press_seq_start_stop(state: &mut GlobalState) {
+    if !state.sequencer.running() {
+        state.sequencer.start(Instant::now());
+    } else if state.sequencer.stop() {
+        seq_note_off(state);
+    }
+}
+
+/// Stops the note the sequencer started last, if it's still sounding.
+fn seq_note_off(state: &mut GlobalState) {
+    if let Some((voice, cable)) = state.seq_sounding.take() {
+        queue_voice_off(state, voice, cable);
+    }
+}
+
+/// A macro key press: starts MACROS entry `id`, or cancels it and stops the notes it left sounding. Does nothing for an
+/// `id` past the end of MACROS.
+fn press_macro(state: &mut GlobalState, id: u8) {
+    let Some(&steps) = MACROS.get(id as usize) else {
+        return;
+    };
+    // Taken out while it plays, as queueing needs the rest of the state.
+    let mut macros = core::mem::replace(&mut state.macros, performance::MacroPlayer::new());
+    macros.press(id, steps, Instant::now(), |message| queue_macro_message(state, message));
+    state.macros = macros;
+}
+
+/// Queues a message from a macro in the queue it belongs in: note events in the note queues, so muting and the
+/// retrigger coalescing apply to them, everything else in the message queue. Goes out on the current cable.
+fn queue_macro_message(state: &mut GlobalState, message: MidiMessage) {
+    let cable = state.cable;
+    match message {
+        MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+            let voice = Voice { note: u8::from(note) as i32, channel, velocity: velocity.into() };
+            queue_voice_on(state, voice, cable);
+        }
+        MidiMessage::NoteOn(channel, note, velocity) | MidiMessage::NoteOff(channel, note, velocity) => {
+            let event = NoteEvent::new(u8::from(note) as i32, velocity.into(), channel, cable, state.take_seq());
+            push_note_off(event);
+        }
+        _ => queue_message(cable, message),
+    }
+}
+
+/// Mono mode press: the key joins the held stack, and if it wins on priority it takes over from the sounding note.
+fn mono_press(state: &mut GlobalState, key: usize, priority: NotePriority) {
+    let change = state.mono_stack.press(key, priority, &state.key_note);
+    apply_mono_change(state, change);
+}
+
+/// Mono mode release: if the released key was sounding, fall back to the next held key by priority.
+fn mono_release(state: &mut GlobalState, key: usize, priority: NotePriority) {
+    let change = state.mono_stack.release(key, priority, &state.key_note);
+    apply_mono_change(state, change);
+}
+
+fn apply_mono_change(state: &mut GlobalState, change: held::MonoChange) {
+    if let Some(key) = change.stop {
+        queue_note_off(state, key);
+    }
+    if let Some(key) = change.start {
+        queue_note_on(state, key);
+    }
+}
+
+/// Applies an incoming MIDI message from the USB host. All Notes Off (CC 123, any channel) panic-stops the controller,
+/// otherwise only the CCs in the CC map do anything.
+fn handle_midi_in(state: &mut GlobalState, message: MidiMessage) {
+    let MidiMessage::ControlChange(_, control, value) = message else {
+        return;
+    };
+    if u8::from(control) == 123 {
+        return state.force_release_all(); // Nothing is echoed back as All Notes Off, the host already sent its own.
+    }
+    let control = Some(u8::from(control));
+    let value = u8::from(value);
+    if control == state.cc_map.octave {
+        state.set_octave(value as i32);
+    } else if control == state.cc_map.transpose {
+        state.transpose = value as i32 - 64;
+    } else if control == state.cc_map.channel {
+        state.channel = Channel::from(value.min(15));
+    } else if control == state.cc_map.system_reset {
+        if value >= 64 {
+            queue_message(state.cable, MidiMessage::Reset);
+        }
+    } else if control == state.cc_map.active_sensing {
+        state.active_sensing = value >= 64;
+    }
+}
+
+// SysEx the host can send to panic-stop the controller, for hosts whose panic button sends no CC 123: the
+// non-commercial manufacturer ID 7D, device 0, then 7B (the All Notes Off controller number).
+const PANIC_SYSEX: [u8; 5] = [0xf0, 0x7d, 0x00, 0x7b, 0xf7];
+
+// Config fields of the SysEx protocol and their value lengths, in dump order. See `sysex` for the format.
+const FIELD_OCTAVE_LIMITS: u8 = 0x00;
+const FIELD_DEBOUNCE: u8 = 0x01;
+const FIELD_VELOCITY_CURVE: u8 = 0x02;
+const FIELD_KEY_MAP: u8 = 0x03;
+const CONFIG_FIELDS: [(u8, usize); 4] = [
+    (FIELD_OCTAVE_LIMITS, 2),
+    (FIELD_DEBOUNCE, 1),
+    (FIELD_VELOCITY_CURVE, 1),
+    (FIELD_KEY_MAP, NUM_KEYS),
 ];
+// The config slot before it held a whole `config::ControllerConfig`: fields 00 to 02 only, the key map had its own slot.
+const LEGACY_SAVED_LEN: usize = 4;
 
-//Needed for MIDI out
-static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+/// A validated config field value, ready to apply.
+enum ConfigValue {
+    OctaveLimits(i32, i32),
+    Debounce(u8),
+    VelocityCurve(velocity::Curve),
+    KeyMap([Option<KeyFunction>; NUM_MAPPED]),
+}
 
-/// Global state for keys and octave.
-/// "key_note" stores which note is currently being held on each key.
-/// This is done so releasing the key will play the correct not off if you change octave.
-/// 255 means no note is held.
-/// "octave" stores the current octave.
-#[derive(Debug)]
-pub struct GlobalState {
-    pub key_note: [i32; 25],
-    pub octave: i32,
+/// The SysEx value of a config field. None for an unknown field.
+fn config_field(state: &GlobalState, field: u8) -> Option<Vec<u8, NUM_KEYS>> {
+    let mut value: Vec<u8, NUM_KEYS> = Vec::new();
+    match field {
+        FIELD_OCTAVE_LIMITS => {
+            value.push((state.min_octave + 64) as u8).ok();
+            value.push((state.max_octave + 64) as u8).ok();
+        }
+        FIELD_DEBOUNCE => value.push(state.debounce_ms).ok()?,
+        FIELD_VELOCITY_CURVE => {
+            let curve = match state.velocity_curve {
+                velocity::Curve::Linear => 0,
+                velocity::Curve::Soft => 1,
+                velocity::Curve::Hard => 2,
+            };
+            value.push(curve).ok()?;
+        }
+        FIELD_KEY_MAP => value.extend_from_slice(&key_map_channels(&state.key_map)).ok()?,
+        _ => return None,
+    }
+    Some(value)
 }
 
-static GLOBAL_STATE: Mutex<CriticalSectionRawMutex, RefCell<GlobalState>> =
-    Mutex::new(RefCell::new(GlobalState {
-        key_note: [255; 25],
-        octave: 4,
-    }));
+/// Checks a config field's SysEx value.
+fn parse_config_field(field: u8, value: &[u8]) -> Result<ConfigValue, sysex::Status> {
+    let length = CONFIG_FIELDS.iter().find(|&&(known, _)| known == field).ok_or(sysex::Status::Unknown)?.1;
+    if value.len() != length {
+        return Err(sysex::Status::BadValue);
+    }
+    match field {
+        FIELD_OCTAVE_LIMITS => {
+            let (min, max) = (value[0] as i32 - 64, value[1] as i32 - 64);
+            if !(0..=10).contains(&min) || !(min..=10).contains(&max) {
+                return Err(sysex::Status::BadValue);
+            }
+            Ok(ConfigValue::OctaveLimits(min, max))
+        }
+        FIELD_DEBOUNCE => Ok(ConfigValue::Debounce(value[0])),
+        FIELD_VELOCITY_CURVE => match value[0] {
+            0 => Ok(ConfigValue::VelocityCurve(velocity::Curve::Linear)),
+            1 => Ok(ConfigValue::VelocityCurve(velocity::Curve::Soft)),
+            2 => Ok(ConfigValue::VelocityCurve(velocity::Curve::Hard)),
+            _ => Err(sysex::Status::BadValue),
+        },
+        _ => {
+            let mut channels = [0u8; NUM_KEYS];
+            channels.copy_from_slice(value);
+            key_map_from_channels(&channels).map(ConfigValue::KeyMap).ok_or(sysex::Status::BadValue)
+        }
+    }
+}
 
-// Separate mutexes for note ON and note OFF events to prevent deadlock.
-static ON_EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<i32, 128>>> =
-    Mutex::new(RefCell::new(Vec::new()));
-static OFF_EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<i32, 128>>> =
-    Mutex::new(RefCell::new(Vec::new()));
+fn apply_config_value(state: &mut GlobalState, value: ConfigValue) {
+    match value {
+        ConfigValue::OctaveLimits(min, max) => {
+            state.min_octave = min;
+            state.max_octave = max;
+            state.octave = state.octave.clamp(min, max);
+        }
+        ConfigValue::Debounce(ms) => {
+            state.debounce_ms = ms;
+            mux::request_reconfig(apply_debounce).ok();
+        }
+        ConfigValue::VelocityCurve(curve) => state.velocity_curve = curve,
+        ConfigValue::KeyMap(map) => {
+            release_all_keys(state);
+            state.key_map = map;
+        }
+    }
+}
+
+/// Sets the running mux's debounce interval from `debounce_ms`. A `mux::request_reconfig` function.
+fn apply_debounce(mux: &mut mux::Multiplexer4051<'_>) {
+    let (press, release) = GLOBAL_STATE.lock(|global_state| {
+        let state = global_state.borrow();
+        (state.debounce_ms, state.release_debounce_ms)
+    });
+    set_debounce(mux, press, release);
+}
+
+/// Sets a mux's debounce intervals after a press and, if given, a different one after a release, in milliseconds.
+fn set_debounce(mux: &mut mux::Multiplexer4051<'_>, press_ms: u8, release_ms: Option<u8>) {
+    let press = Duration::from_millis(press_ms as u64);
+    match release_ms {
+        Some(release_ms) => mux.set_press_release_debounce(press, Duration::from_millis(release_ms as u64)),
+        None => mux.set_debounce_interval(press),
+    }
+}
+
+/// Every run-time setting, for flash and the SysEx dump.
+fn controller_config(state: &GlobalState) -> config::ControllerConfig {
+    config::ControllerConfig {
+        min_octave: state.min_octave,
+        max_octave: state.max_octave,
+        debounce_ms: state.debounce_ms,
+        velocity_curve: state.velocity_curve,
+        channel: u8::from(state.channel),
+        key_channels: key_map_channels(&state.key_map),
+        zones: state.zones.clone(),
+    }
+}
+
+/// Applies a config checked by `ControllerConfig::from_bytes`. The debounce interval goes through
+/// `mux::request_reconfig` unless `mux` is given, before the poll task runs.
+fn apply_controller_config(
+    state: &mut GlobalState,
+    config: config::ControllerConfig,
+    mux: Option<&mut mux::Multiplexer4051<'_>>,
+) {
+    apply_config_value(state, ConfigValue::OctaveLimits(config.min_octave, config.max_octave));
+    apply_config_value(state, ConfigValue::VelocityCurve(config.velocity_curve));
+    match mux {
+        Some(mux) => {
+            state.debounce_ms = config.debounce_ms;
+            set_debounce(mux, config.debounce_ms, state.release_debounce_ms);
+        }
+        None => apply_config_value(state, ConfigValue::Debounce(config.debounce_ms)),
+    }
+    if let Some(map) = key_map_from_channels(&config.key_channels) {
+        if map != state.key_map {
+            apply_config_value(state, ConfigValue::KeyMap(map));
+        }
+    }
+    state.channel = Channel::from(config.channel.min(15));
+    if config.zones != state.zones {
+        release_all_keys(state); // Held notes were started in the old zones.
+        state.zones = config.zones;
+    }
+}
+
+/// Saves every setting to flash, see `config::ControllerConfig`. Not with GLOBAL_STATE locked: the flash write stalls
+/// for a while, so take the config with `controller_config` and save it after the lock is released.
+fn save_config(config: &config::ControllerConfig) -> Result<(), sysex::Status> {
+    let mut saved = [0u8; config::MAX_LEN];
+    let len = config.to_bytes(&mut saved).map_err(|_| sysex::Status::SaveFailed)?;
+    storage::save(storage::Slot::Config, &saved[..len]).map_err(|_| sysex::Status::SaveFailed)
+}
+
+/// Applies the settings saved by `save_config`, if any. A config slot from before `ControllerConfig` (fields 00 to 02,
+/// with the key map in its own slot, see `load_key_map`) is read too. The debounce interval goes to `mux` directly,
+/// the poll task isn't running yet.
+fn load_config(state: &mut GlobalState, mux: &mut mux::Multiplexer4051<'_>) {
+    let mut saved = [0u8; storage::MAX_LEN];
+    let Some(len) = storage::load(storage::Slot::Config, &mut saved) else {
+        return;
+    };
+    if len != LEGACY_SAVED_LEN {
+        if let Ok(config) = config::ControllerConfig::from_bytes(&saved[..len]) {
+            apply_controller_config(state, config, Some(mux));
+        }
+        return;
+    }
+    let mut rest = &saved[..len];
+    for &(field, length) in CONFIG_FIELDS.iter().filter(|&&(field, _)| field != FIELD_KEY_MAP) {
+        let (value, next) = rest.split_at(length);
+        rest = next;
+        match parse_config_field(field, value) {
+            Ok(ConfigValue::Debounce(ms)) => {
+                state.debounce_ms = ms;
+                set_debounce(mux, ms, state.release_debounce_ms);
+            }
+            Ok(value) => apply_config_value(state, value),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Handles a complete SysEx message from the host: the panic SysEx or a config request. Returns the answer to send,
+/// if any. Writes are applied under the lock and saved to flash after it, from the main loop.
+fn handle_sysex(message: &[u8]) -> Option<Vec<u8, { sysex::MAX_MESSAGE }>> {
+    if message == PANIC_SYSEX {
+        GLOBAL_STATE.lock(|global_state| global_state.borrow_mut().force_release_all());
+        return None;
+    }
+    let request = match sysex::parse(message)? {
+        Ok(request) => request,
+        Err((command, status)) => return Some(sysex::ack(command, status)),
+    };
+    // Either the answer, or the command to ack once the config it changed is saved.
+    let (reply, unsaved) = GLOBAL_STATE.lock(|global_state| {
+        let mut state = global_state.borrow_mut();
+        match request {
+            sysex::Request::Read(field) => (Some(match config_field(&state, field) {
+                Some(value) => {
+                    let mut reply: Vec<u8, { NUM_KEYS + 1 }> = Vec::new();
+                    reply.push(field).ok();
+                    reply.extend_from_slice(&value).ok();
+                    sysex::message(sysex::READ_REPLY, &reply)
+                }
+                None => sysex::ack(sysex::READ, sysex::Status::Unknown),
+            }), None),
+            sysex::Request::Write(field, value) => match parse_config_field(field, value) {
+                Ok(value) => {
+                    apply_config_value(&mut state, value);
+                    (None, Some((sysex::WRITE, controller_config(&state))))
+                }
+                Err(status) => (Some(sysex::ack(sysex::WRITE, status)), None),
+            },
+            sysex::Request::Dump => {
+                let mut dump = [0u8; config::MAX_LEN];
+                let reply = match controller_config(&state).to_bytes(&mut dump) {
+                    Ok(len) => sysex::message(sysex::DUMP_REPLY, &dump[..len]),
+                    Err(_) => sysex::ack(sysex::DUMP, sysex::Status::BadValue), // Not an empty dump the host could restore.
+                };
+                (Some(reply), None)
+            }
+            sysex::Request::Restore(dump) => {
+                // Checked as a whole before anything is applied, so a bad dump changes nothing.
+                match config::ControllerConfig::from_bytes(dump) {
+                    Ok(config) => {
+                        apply_controller_config(&mut state, config, None);
+                        (None, Some((sysex::RESTORE, controller_config(&state))))
+                    }
+                    Err(_) => (Some(sysex::ack(sysex::RESTORE, sysex::Status::BadValue)), None),
+                }
+            }
+        }
+    });
+    match unsaved {
+        Some((command, config)) => Some(sysex::ack(command, save_config(&config).err().unwrap_or(sysex::Status::Ok))),
+        None => reply,
+    }
+}
+
+/// The USB MIDI packet for a rendered message, see `messages::packet`. A malformed buffer is logged and None is
+/// returned, so the caller drops the event rather than panicking: retrying wouldn't make it valid.
+fn midi_packet(cable: CableNumber, bytes: &[u8]) -> Option<UsbMidiEventPacket> {
+    let packet = messages::packet(cable, bytes);
+    if packet.is_none() {
+        midi_log!("Dropped a malformed packet {}", bytes);
+    }
+    packet
+}
+
+/// Sends a complete SysEx message to the host, 3 bytes per packet. Gives up on the first packet the endpoint refuses,
+/// so a busy host can miss the end of an answer; it can simply ask again.
+fn send_sysex<B: usb_device::bus::UsbBus>(midi_class: &mut UsbMidiClass<'_, B>, message: &[u8]) {
+    for chunk in message.chunks(3) {
+        let Ok(packet) = UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, chunk) else {
+            return;
+        };
+        if midi_class.send_packet(packet).is_err() {
+            return;
+        }
+    }
+}
+
+/// The key map with the note keys of KEYS moved to the learned channels: `learned[note]` is the channel of that note
+/// key. Note key channels that weren't learned play nothing.
+fn learned_key_map(learned: &[u8]) -> [Option<KeyFunction>; NUM_MAPPED] {
+    let mut map = DEFAULT_KEY_MAP;
+    for function in map.iter_mut() {
+        if let Some(KeyFunction::Note(_)) = function {
+            *function = None;
+        }
+    }
+    for (key, &channel) in learned.iter().enumerate() {
+        if let Some(function) = map.get_mut(channel as usize) {
+            *function = Some(KeyFunction::Note(key as u8));
+        }
+    }
+    map
+}
+
+/// Reads the key map older firmware saved in its own slot, if there is one and it still fits this build's KEYS. A config
+/// saved since includes the key map and replaces it, see `load_config`.
+fn load_key_map() -> Option<[Option<KeyFunction>; NUM_MAPPED]> {
+    let mut learned = [0u8; NUM_KEYS];
+    if storage::load(storage::Slot::KeyMap, &mut learned)? != NUM_KEYS {
+        return None;
+    }
+    key_map_from_channels(&learned)
+}
+
+/// The key map for the channel of every note key, as saved to flash. None unless every channel is a note key channel
+/// of KEYS and none appears twice.
+fn key_map_from_channels(learned: &[u8; NUM_KEYS]) -> Option<[Option<KeyFunction>; NUM_MAPPED]> {
+    for (position, &channel) in learned.iter().enumerate() {
+        let is_note_key = matches!(KEYS.get(channel as usize), Some(KeyFunction::Note(_)));
+        if !is_note_key || learned[..position].contains(&channel) {
+            return None;
+        }
+    }
+    Some(learned_key_map(learned))
+}
+
+/// The channel of every note key in a key map, in note order, as saved to flash.
+fn key_map_channels(map: &[Option<KeyFunction>; NUM_MAPPED]) -> [u8; NUM_KEYS] {
+    let mut channels = [0u8; NUM_KEYS];
+    for (channel, function) in map.iter().enumerate() {
+        if let Some(KeyFunction::Note(key)) = function {
+            if let Some(slot) = channels.get_mut(*key as usize) {
+                *slot = channel as u8;
+            }
+        }
+    }
+    channels
+}
+
+/// What a function (or note) key does when pressed.
+fn press_function(state: &mut GlobalState, function: KeyFunction) {
+    match function {
+        KeyFunction::OctaveUp => state.press_octave_button(1),
+        KeyFunction::OctaveDown => state.press_octave_button(-1),
+        KeyFunction::Split => state.split_learn = true, // The next note key sets the split point.
+        KeyFunction::Mute => toggle_mute(state),
+        KeyFunction::Sostenuto => press_sostenuto(state),
+        KeyFunction::ChordMemory => press_chord_memory(state),
+        KeyFunction::ChordCapture => state.chord_capture = true,
+        KeyFunction::SeqStartStop => press_seq_start_stop(state),
+        KeyFunction::SeqClear => state.sequencer.clear(),
+        KeyFunction::SeqEdit => state.seq_edit = !state.seq_edit,
+        KeyFunction::Note(key) if state.seq_edit => state.sequencer.toggle(key as usize),
+        KeyFunction::KeyLearn => state.begin_key_learn(),
+        KeyFunction::ExpressionPedal | KeyFunction::PortamentoTime => {} // Analog only, see `analog_key_handler`.
+        KeyFunction::Accent => state.accent_active = true,
+        KeyFunction::CalibratePedal => state.start_pedal_calibration(),
+        KeyFunction::FullVelocity => state.full_velocity = true,
+        KeyFunction::PatchSelect { bank_msb, bank_lsb, program } => queue_patch_select(state, bank_msb, bank_lsb, program),
+        KeyFunction::Macro(id) => press_macro(state, id),
+        KeyFunction::TapTempo => state.tap(Instant::now()),
+        KeyFunction::ZoneOctaveUp(zone) => state.shift_zone_octave(zone as usize, 1),
+        KeyFunction::ZoneOctaveDown(zone) => state.shift_zone_octave(zone as usize, -1),
+        KeyFunction::Sustain => {
+            queue_message(state.cable, MidiMessage::ControlChange(state.channel, 64.into(), 127.into()))
+        }
+        KeyFunction::ChannelUp => state.shift_channel(1),
+        KeyFunction::ChannelDown => state.shift_channel(-1),
+        KeyFunction::SetOctave(octave) => state.set_octave(octave as i32),
+        KeyFunction::Glide => {
+            state.glide_held = true;
+            update_portamento(state);
+        }
+        KeyFunction::Transport(transport) => {
+            // Real-time messages carry no channel and leave the octave and notes alone.
+            let message = match transport {
+                TransportMsg::Start => MidiMessage::Start,
+                TransportMsg::Stop => MidiMessage::Stop,
+                TransportMsg::Continue => MidiMessage::Continue,
+            };
+            queue_message(state.cable, message);
+        }
+        KeyFunction::System(system) => queue_message(state.cable, system.message()),
+        KeyFunction::Note(_) if state.chord_capture => {} // Only captured, see `chord_handler`.
+        KeyFunction::Note(key) => press_key(state, key),
+        KeyFunction::SecondContact(key) => press_contact(state, key),
+        KeyFunction::ThirdContact(key) => press_bottom_contact(state, key as usize),
+        KeyFunction::CalibrateVelocity => state.start_velocity_calibration(),
+    }
+}
 
 /// Called on a falling edge (button pressed).
 fn falling_edge_handler(index: usize) {
     GLOBAL_STATE.lock(|global_state| {
         // Lock the global state.
         let mut state = global_state.borrow_mut();
-        if KEYS[index] == 255 {
-            // Check for octave up button.
-            if state.octave < 8 {
-                state.octave += 1;
-            }
-        } else if KEYS[index] == 254 {
-            // Check for octave down button.
-            if state.octave > 0 {
-                state.octave -= 1;
+        let Some(function) = state.key_function(index) else {
+            return; // Channel without a key.
+        };
+        if state.key_learn.is_some() {
+            state.learn_key(index, function);
+            return;
+        }
+        if state.long_press_map.get(index).copied().flatten().is_some() {
+            // Decided on release or once held long enough, see `release_long_press` and `fire_long_presses`.
+            let pending = PendingPress { index: index as u8, at: Instant::now(), long: false };
+            if state.pending_presses.push(pending).is_ok() {
+                return;
             }
-        } else {
-            // Otherwise, it's a note button.
-            let note = KEYS[index] + (state.octave * 12); //Shifts note to current octave.
-            state.key_note[KEYS[index] as usize] = note; // Store the note in the key_note array for note-off events.
-            ON_EVENTS.lock(|on_events| {
-                // Lock the note-on events.
-                let mut events = on_events.borrow_mut();
-                if events.len() < 128 {
-                    events.push(note).ok(); // Push the note-on event. All events in this list will be sent to the MIDI device in the main loop.
-                }
-            });
         }
+        press_function(&mut state, function);
     });
 }
 
+/// What a function (or note) key does when released.
+fn release_function(state: &mut GlobalState, function: KeyFunction) {
+    match function {
+        KeyFunction::Note(key) => {
+            state.contact_keys[key as usize].release();
+            state.triple_keys[key as usize].release();
+            release_note(state, key as usize);
+            state.apply_deferred_octave();
+        }
+        // Releasing an octave button stops its repeat.
+        KeyFunction::OctaveUp | KeyFunction::OctaveDown => state.octave_hold = None,
+        KeyFunction::Sostenuto => release_sostenuto(state),
+        KeyFunction::ThirdContact(key) => release_bottom_contact(state, key as usize),
+        KeyFunction::Accent => state.accent_active = false,
+        KeyFunction::FullVelocity => state.full_velocity = false,
+        KeyFunction::Sustain => {
+            queue_message(state.cable, MidiMessage::ControlChange(state.channel, 64.into(), 0.into()))
+        }
+        KeyFunction::Glide => {
+            state.glide_held = false;
+            update_portamento(state);
+        }
+        _ => {}
+    }
+}
+
+/// A key with a long press action was released: a tap plays its own function now, press and release; after a long
+/// press that already fired, this is the long action's release.
+fn release_long_press(state: &mut GlobalState, position: usize, function: KeyFunction) {
+    let pending = state.pending_presses.swap_remove(position);
+    match state.long_press_map[pending.index as usize] {
+        Some(long) if pending.long => release_function(state, long),
+        _ => {
+            press_function(state, function);
+            release_function(state, function);
+        }
+    }
+}
+
+/// Fires the long press action of every key with one held for `LONG_PRESS`, while still held. Called from the main loop.
+fn fire_long_presses(state: &mut GlobalState, now: Instant) {
+    for position in 0..state.pending_presses.len() {
+        let pending = state.pending_presses[position];
+        if pending.long || now < pending.at + LONG_PRESS {
+            continue;
+        }
+        state.pending_presses[position].long = true;
+        if let Some(long) = state.long_press_map[pending.index as usize] {
+            press_function(state, long);
+        }
+    }
+}
+
 /// Called on a rising edge (button released).
 fn rising_edge_handler(index: usize) {
     GLOBAL_STATE.lock(|global_state| {
         // Lock the global state.
         let mut state = global_state.borrow_mut();
-        if KEYS[index] < 254 {
-            // If it's not an octave button.
-            let note = state.key_note[KEYS[index] as usize]; // Get the note from the key_note array.
-            OFF_EVENTS.lock(|off_events| {
-                // Lock the note-off events.
-                let mut events = off_events.borrow_mut();
-                if events.len() < 128 {
-                    events.push(note).ok(); // Push the note-off event. All events in this list will be sent to the MIDI device in the main loop.
-                }
-            });
-            state.key_note[KEYS[index] as usize] = 255; // Reset the key_note array for this key.
+        let Some(function) = state.key_function(index) else {
+            return;
+        };
+        if let Some(position) = state.pending_presses.iter().position(|pending| pending.index as usize == index) {
+            return release_long_press(&mut state, position, function);
+        }
+        release_function(&mut state, function);
+    });
+}
+
+/// Expression pedal reading: sent as CC 11 on the current channel whenever its 7 bit value changes, and recorded
+/// while calibrating.
+#[cfg(feature = "analog-keys")]
+fn expression_pedal(state: &mut GlobalState, value: u16) {
+    if let Some((calibration, until)) = state.pedal_calibration.as_mut() {
+        calibration.record(value);
+        if Instant::now() >= *until {
+            calibration.apply(&mut state.pedal);
+            state.pedal_calibration = None;
+        }
+    }
+    let cc = state.pedal.to_7bit(value);
+    if state.pedal_sent != Some(cc) {
+        state.pedal_sent = Some(cc);
+        queue_message(state.cable, MidiMessage::ControlChange(state.channel, 11.into(), cc.into()));
+    }
+}
+
+/// Called with every reading of an analog mux channel in FSR "piano" mode, with the "analog-keys" feature. The
+/// channel index maps through KEYS from ANALOG_FIRST_CHANNEL. Each note key channel of the analog chip is wired to a
+/// force-sensing resistor under the key. An expression pedal goes on an analog channel mapped to
+/// `KeyFunction::ExpressionPedal`, a portamento time pot on one mapped to `KeyFunction::PortamentoTime`.
+#[cfg(feature = "analog-keys")]
+fn analog_key_handler(index: usize, value: u16) {
+    GLOBAL_STATE.lock(|global_state| {
+        let mut state = global_state.borrow_mut();
+        let key = match state.key_function(ANALOG_FIRST_CHANNEL + index) {
+            Some(KeyFunction::Note(key)) => key as usize,
+            Some(KeyFunction::ExpressionPedal) => return expression_pedal(&mut state, value),
+            Some(KeyFunction::PortamentoTime) => return portamento_pot(&mut state, value),
+            _ => return, // Only note keys are pressure sensitive, the function buttons stay on switches.
+        };
+        match state.analog_keys[key].update(value) {
+            Some(analog::AnalogKeyEvent::NoteOn { velocity }) => {
+                state.key_pressure[key] = 0;
+                press_note(&mut state, key, velocity);
+            }
+            Some(analog::AnalogKeyEvent::NoteOff) => {
+                release_note(&mut state, key);
+                state.apply_deferred_octave();
+            }
+            None => {}
+        }
+        // In MPE mode the pressure of a held key goes to its own channel, only when it changes.
+        let held = state.key_note.is_held(key) && !state.sostenuto_keys.is_pending(key);
+        if state.mpe.is_some() && held {
+            let pressure = analog::to_7bit(value, analog::ADC_FULL_SCALE);
+            if pressure != state.key_pressure[key] {
+                state.key_pressure[key] = pressure;
+                let message = MidiMessage::ChannelPressure(state.key_channel[key], pressure.into());
+                queue_message(state.key_cable[key], message);
+            }
+        }
+    });
+}
+
+/// Called with every reading of an analog mux channel in piezo drum pad mode, with the "piezo-pads" feature. Each
+/// channel of the analog chip has a piezo disc (with a 1M resistor across it, and a 3.3V zener or clamp diode so a hard
+/// hit can't overdrive the pin). The channel index maps through KEYS from ANALOG_FIRST_CHANNEL. A hit plays its note
+/// key with the velocity from its peak, for the pad's gate time. Works best with the drum layout, see
+/// `analog::PiezoPad` for the scan rate a pad needs.
+#[cfg(feature = "piezo-pads")]
+fn piezo_pad_handler(index: usize, value: u16) {
+    GLOBAL_STATE.lock(|global_state| {
+        let mut state = global_state.borrow_mut();
+        let Some(KeyFunction::Note(key)) = state.key_function(ANALOG_FIRST_CHANNEL + index) else {
+            return;
+        };
+        let key = key as usize;
+        match state.piezo_pads[key].update(value, Instant::now()) {
+            Some(analog::AnalogKeyEvent::NoteOn { velocity }) => press_note(&mut state, key, velocity),
+            Some(analog::AnalogKeyEvent::NoteOff) => release_note(&mut state, key),
+            None => {}
         }
     });
 }
@@ -112,6 +2468,191 @@ async fn mux_poll_task(mut mux: mux::Multiplexer4051<'static>) {
     mux.poll_all().await;
 }
 
+#[embassy_executor::task]
+async fn chord_task() {
+    // Chords are picked out of the sweep snapshots the poll task signals.
+    let mut chords = gesture::ChordDetector::new();
+    chords.set_window(CHORD_WINDOW);
+    chords.set_chord_callback(chord_handler);
+    loop {
+        chords.update(&mux::SWEEP_COMPLETE.wait().await);
+    }
+}
+
+#[embassy_executor::task]
+async fn note_repeat_task() {
+    // Task for note repeat. Closes the gate of each repeating key and retriggers it when its next step is due.
+    loop {
+        GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            let Some(repeat) = state.note_repeat else {
+                return;
+            };
+            let now = Instant::now();
+            for key in 0..state.repeat_at.len() {
+                match state.repeat_at[key] {
+                    Some(due) if now >= due => {
+                        if state.repeat_gate_open[key] {
+                            queue_note_off(&mut state, key);
+                            state.repeat_at[key] = Some(due + repeat.interval.checked_sub(repeat.gate).unwrap_or_default());
+                        } else {
+                            queue_note_on(&mut state, key);
+                            state.repeat_at[key] = Some(due + repeat.gate);
+                        }
+                        state.repeat_gate_open[key] = !state.repeat_gate_open[key];
+                    }
+                    _ => {}
+                }
+            }
+        });
+        Timer::after_millis(1).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn sequencer_task() {
+    // Task for the step sequencer. Plays the steps as they come due and marks every quarter note as a beat.
+    loop {
+        GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            let state = &mut *state;
+            let mut events: Vec<sequencer::StepEvent, 3> = Vec::new();
+            state.sequencer.poll(Instant::now(), |event| {
+                events.push(event).ok();
+            });
+            for event in events {
+                match event {
+                    sequencer::StepEvent::Off => seq_note_off(state),
+                    sequencer::StepEvent::Enter { step, on } => {
+                        if step % 4 == 0 {
+                            state.last_beat = Some(Instant::now());
+                        }
+                        if step == 0 {
+                            state.last_downbeat = Some(Instant::now());
+                        }
+                        if let Some((note, velocity)) = on {
+                            let voice = Voice { note: note as i32, channel: state.channel, velocity };
+                            queue_voice_on(state, voice, state.cable);
+                            state.seq_sounding = Some((voice, state.cable));
+                        }
+                    }
+                }
+            }
+        });
+        Timer::after_millis(1).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn macro_task() {
+    // Task for the performance macros. Queues each step of the playing macros when it comes due.
+    loop {
+        GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            let mut macros = core::mem::replace(&mut state.macros, performance::MacroPlayer::new());
+            macros.poll(Instant::now(), |message| queue_macro_message(&mut state, message));
+            state.macros = macros;
+        });
+        Timer::after_millis(1).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn active_sensing_task() {
+    // Task for Active Sensing. Sends it at a fixed interval while it's on, see "active_sensing" in `GlobalState`.
+    loop {
+        GLOBAL_STATE.lock(|global_state| {
+            let state = global_state.borrow();
+            if state.active_sensing {
+                queue_message(state.cable, MidiMessage::ActiveSensing);
+            }
+        });
+        Timer::after(ACTIVE_SENSING_INTERVAL).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn swell_task() {
+    // Task for auto-swell. Sends the ramped CC on every channel with a held key, only when its value changes.
+    loop {
+        GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            let Some(swell) = state.swell else {
+                return;
+            };
+            let now = Instant::now();
+            let mut values: [Option<u8>; 16] = [None; 16];
+            for key in 0..state.swell_start.len() {
+                if let Some(start) = state.swell_start[key] {
+                    let channel = u8::from(state.key_channel[key]) as usize;
+                    let value = swell.value(now.duration_since(start));
+                    values[channel] = Some(values[channel].map_or(value, |other| other.max(value)));
+                }
+            }
+            for (channel, &value) in values.iter().enumerate() {
+                if value != state.swell_sent[channel] {
+                    if let Some(value) = value {
+                        let message = MidiMessage::ControlChange(
+                            Channel::from(channel as u8),
+                            swell.cc.into(),
+                            value.into(),
+                        );
+                        queue_message(state.cable, message);
+                    }
+                    state.swell_sent[channel] = value; // None once the channel's last key is released.
+                }
+            }
+        });
+        Timer::after_millis(10).await;
+    }
+}
+
+// How long the display highlights the last note after it started.
+#[cfg(feature = "display")]
+const DISPLAY_ACTIVITY_TIME: Duration = Duration::from_millis(150);
+
+/// The name of the play mode for the display.
+#[cfg(feature = "display")]
+fn mode_name(state: &GlobalState) -> &'static str {
+    if state.layout == Layout::Drums {
+        "Drums"
+    } else if state.mpe.is_some() {
+        "MPE"
+    } else if state.mono.is_some() {
+        "Mono"
+    } else if !state.zones.is_empty() {
+        "Zones"
+    } else if state.split_point.is_some() {
+        "Split"
+    } else {
+        "Poly"
+    }
+}
+
+/// Redraws the display whenever the state it shows changes. Exits if no display answers at startup.
+#[cfg(feature = "display")]
+#[embassy_executor::task]
+async fn display_task(i2c: esp_hal::i2c::master::I2c<'static, esp_hal::Async>) {
+    let Ok(mut display) = display::Display::new(i2c).await else {
+        return;
+    };
+    loop {
+        let now = Instant::now();
+        let screen = GLOBAL_STATE.lock(|global_state| {
+            let state = global_state.borrow();
+            display::Screen {
+                octave: state.octave,
+                channel: u8::from(state.channel) + 1,
+                mode: mode_name(&state),
+                last_note: state.last_note.map(|(note, _)| note),
+                active: state.last_note.is_some_and(|(_, at)| now < at + DISPLAY_ACTIVITY_TIME),
+            }
+        });
+        display.show(screen).await.ok(); // A failed transfer is retried with the next change.
+        Timer::after_millis(20).await;
+    }
+}
+
 #[main]
 async fn main(spawner: Spawner) {
     // Esp32S3 initialization.
@@ -127,55 +2668,308 @@ async fn main(spawner: Spawner) {
         unsafe { &mut *addr_of_mut!(EP_MEMORY) },
     );
 
+    // After a watchdog, panic, brown-out or software reset the host may still hold notes from before the reset.
+    // Queue the flush now; it goes out as soon as USB is up.
+    match esp_hal::reset::reset_reason() {
+        Some(esp_hal::rtc_cntl::SocResetReason::ChipPowerOn) => {}
+        Some(esp_hal::rtc_cntl::SocResetReason::SysBrownOut) => {
+            midi_log!("Brown-out reset, flushing notes");
+            shutdown();
+        }
+        _ => shutdown(),
+    }
+    enable_brown_out_reset();
+
     // Set up the GPIOs for the multiplexer and LEDs.
     let select = [
         Output::new(peripherals.GPIO1, Level::Low),
         Output::new(peripherals.GPIO2, Level::Low),
         Output::new(peripherals.GPIO3, Level::Low),
     ];
-    let mut down_led = Output::new(peripherals.GPIO8, Level::Low);
-    let mut up_led = Output::new(peripherals.GPIO9, Level::High);
-
-    // Set up the multiplexer.
-    let mut mux = mux::Multiplexer4051::new(select); // Create a new multiplexer with the select pins.
-                                                     // Create a MuxChipConfig for each chip you want to use.
-    let chip1_config =
-        mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO4, Pull::Up));
-    let chip2_config =
-        mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO5, Pull::Up));
-    let chip3_config =
-        mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO6, Pull::Up));
-    let chip4_config =
-        mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO7, Pull::Up));
-    // Add the chips to the multiplexer.
-    mux.add_chip(chip1_config);
-    mux.add_chip(chip2_config);
-    mux.add_chip(chip3_config);
-    mux.add_chip(chip4_config);
-    // Set callbacks and spawn the poll task.
-    mux.set_falling_edge_callback(falling_edge_handler);
-    mux.set_rising_edge_callback(rising_edge_handler);
+    // The octave LEDs, dimmable on LEDC channels 0 and 1 with the "led-pwm" feature.
+    #[cfg(feature = "led-pwm")]
+    let ledc = led::ledc(peripherals.LEDC);
+    #[cfg(feature = "led-pwm")]
+    let led_timer = led::pwm_timer(&ledc);
+    #[cfg(feature = "led-pwm")]
+    let (mut down_led, mut up_led) = (
+        Led::pwm(&ledc, &led_timer, esp_hal::ledc::channel::Number::Channel0, peripherals.GPIO8),
+        Led::pwm(&ledc, &led_timer, esp_hal::ledc::channel::Number::Channel1, peripherals.GPIO9),
+    );
+    #[cfg(not(feature = "led-pwm"))]
+    let (mut down_led, mut up_led) = (
+        Led::Plain(Output::new(peripherals.GPIO8, Level::Low)),
+        Led::Plain(Output::new(peripherals.GPIO9, Level::Low)),
+    );
+    led::set_led_brightness(LED_BRIGHTNESS);
+    up_led.set(true);
+
+    // Set up the multiplexer with one MuxChipConfig per chip, plus the edge callbacks.
+    let builder = mux::Multiplexer4051::builder(select)
+        .chip(mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO4, Pull::Up)))
+        .chip(mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO5, Pull::Up)))
+        .chip(mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO6, Pull::Up)));
+    #[cfg(not(any(feature = "analog-keys", feature = "piezo-pads")))]
+    let builder = builder.chip(mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO7, Pull::Up)));
+    // The fourth chip through ADC1 instead, see ANALOG_FIRST_CHANNEL.
+    #[cfg(any(feature = "analog-keys", feature = "piezo-pads"))]
+    let builder = {
+        use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
+        let mut adc_config = AdcConfig::new();
+        let pin = adc_config.enable_pin(peripherals.GPIO7, Attenuation::_11dB);
+        let adc = ADC.init(RefCell::new(Adc::new(peripherals.ADC1, adc_config)));
+        let source = ANALOG_SOURCE.init(mux::AdcSource::new(adc, pin));
+        let builder = builder.chip(mux::MuxChipConfig::new_analog_input(source));
+        #[cfg(feature = "analog-keys")]
+        let builder = builder.on_analog(analog_key_handler);
+        #[cfg(feature = "piezo-pads")]
+        let builder = builder.on_analog(piezo_pad_handler);
+        builder
+    };
+    let mut mux = builder
+        .on_falling(falling_edge_handler)
+        .on_rising(rising_edge_handler)
+        .build()
+        .unwrap();
+    // Power-on self-test. Stuck channels are ignored until released; blink their count on the down LED.
+    let stuck = mux.run_self_test().await;
+    for _ in 0..stuck.len() {
+        down_led.set(true);
+        Timer::after_millis(150).await;
+        down_led.set(false);
+        Timer::after_millis(150).await;
+    }
+    // A key map learned by older firmware replaces the KEYS note keys.
+    if let Some(key_map) = load_key_map() {
+        GLOBAL_STATE.lock(|global_state| global_state.borrow_mut().key_map = key_map);
+    }
+    // The debounce as set in GlobalState, then the settings saved over SysEx.
+    GLOBAL_STATE.lock(|global_state| {
+        let mut state = global_state.borrow_mut();
+        set_debounce(&mut mux, state.debounce_ms, state.release_debounce_ms);
+        load_config(&mut state, &mut mux);
+    });
+    // Seed the humanize PRNG from the chip's MAC address and the time the self-test took.
+    let mac = esp_hal::efuse::Efuse::read_base_mac_address();
+    let seed = u32::from_le_bytes([mac[2], mac[3], mac[4], mac[5]]) ^ Instant::now().as_ticks() as u32;
+    GLOBAL_STATE.lock(|global_state| global_state.borrow_mut().rng = velocity::XorShift32::new(seed));
+    // Watchdog: fed by the poll task after every sweep, resets the chip if polling stalls. Started after the
+    // self-test blink so a long stuck count can't time it out.
+    let mut watchdog = TimerGroup::new(peripherals.TIMG1).wdt;
+    watchdog.set_timeout(MwdtStage::Stage0, WATCHDOG_TIMEOUT_SECS.secs());
+    watchdog.enable();
+    mux.set_watchdog(watchdog);
+    mux.set_idle_timeout(Some(IDLE_TIMEOUT), IDLE_SCAN_INTERVAL);
     spawner.spawn(mux_poll_task(mux)).unwrap();
+    spawner.spawn(note_repeat_task()).unwrap();
+    spawner.spawn(swell_task()).unwrap();
+    spawner.spawn(chord_task()).unwrap();
+    spawner.spawn(sequencer_task()).unwrap();
+    spawner.spawn(macro_task()).unwrap();
+    spawner.spawn(active_sensing_task()).unwrap();
+
+    // Blink timers for octave indication.
+    let mut octave_blink = OctaveBlink::new();
+    // Octave LED pairs (up, down) for zones with their own octave, indexed like `zones`: 2 GPIOs per zone, each LED
+    // with its own 1k resistor like the main pair. None leaves the zone without LEDs. For example:
+    //     let zone_up = Led::Plain(Output::new(peripherals.GPIO43, Level::Low));
+    //     let zone_down = Led::Plain(Output::new(peripherals.GPIO21, Level::Low));
+    //     zone_leds[1] = Some((zone_up, zone_down, OctaveBlink::new()));
+    let mut zone_leds: [Option<(Led<'_>, Led<'_>, OctaveBlink)>; 4] = [None, None, None, None];
+    // LED pulse deadlines for activity mode.
+    let mut up_pulse_until = Instant::now();
+    let mut down_pulse_until = Instant::now();
+
+    // Latency: usbd-midi puts MIDI on bulk endpoints, whose bInterval is 0 and has no setting. A full-speed host polls
+    // bulk endpoints whenever the bus has room, several times per 1ms frame, so the endpoint never adds more latency than
+    // a 1ms interrupt endpoint would. What's left is the main loop's 1ms tick below.
+    // USB MIDI class and device. A bad configuration blinks its error code and retries instead of panicking, so a
+    // fielded controller shows what's wrong rather than looking dead. The poll task keeps the watchdog fed meanwhile.
+    let mut midi_class = loop {
+        match UsbMidiClass::new(&usb_bus_allocator, NUM_CABLES, NUM_CABLES) {
+            Ok(midi_class) => break midi_class,
+            Err(_) => blink_error(&mut down_led, &mut up_led, UsbSetupError::MidiClass as u8).await,
+        }
+    };
+    let mut usb_dev = loop {
+        let builder = UsbDeviceBuilder::new(&usb_bus_allocator, UsbVidPid(0x16c0, 0x5e4))
+            .device_class(0x01)
+            .device_sub_class(0x03)
+            .max_packet_size_0(16);
+        match builder {
+            Ok(builder) => break builder.build(),
+            Err(_) => blink_error(&mut down_led, &mut up_led, UsbSetupError::Device as u8).await,
+        }
+    };
 
-    // Functions for LED timers for octave indication
-    let mut down_led_timer = 0;
-    let mut up_led_timer = 0;
+    // MIDI Thru: merge a serial MIDI input (31250 baud on D7/GPIO44) into the USB output.
+    #[cfg(feature = "midi-thru")]
+    {
+        let uart_config = esp_hal::uart::Config::default().with_baudrate(31250);
+        let rx = esp_hal::uart::UartRx::new(peripherals.UART1, uart_config)
+            .unwrap()
+            .with_rx(peripherals.GPIO44)
+            .into_async();
+        spawner.spawn(serial_midi::uart_midi_task(rx)).unwrap();
+    }
 
-    // Global state for keys and octave.
-    let mut midi_class = UsbMidiClass::new(&usb_bus_allocator, 1, 1).unwrap();
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus_allocator, UsbVidPid(0x16c0, 0x5e4))
-        .device_class(0x01)
-        .device_sub_class(0x03)
-        .max_packet_size_0(16)
-        .unwrap()
-        .build();
+    // OLED display on D6/GPIO43 (SDA) and D7/GPIO44 (SCL), see `display`.
+    #[cfg(feature = "display")]
+    {
+        use esp_hal::time::RateExtU32;
+        let i2c_config = esp_hal::i2c::master::Config::default().with_frequency(400u32.kHz());
+        let i2c = esp_hal::i2c::master::I2c::new(peripherals.I2C0, i2c_config)
+            .unwrap()
+            .with_sda(peripherals.GPIO43)
+            .with_scl(peripherals.GPIO44)
+            .into_async();
+        spawner.spawn(display_task(i2c)).unwrap();
+    }
 
+    #[cfg(feature = "defmt")]
+    let mut last_usb_state = usb_dev.state();
+    let mut sysex_in = sysex::SysexReceiver::new();
     loop {
         // Poll USB.
-        if usb_dev.poll(&mut [&mut midi_class]) {}
+        if usb_dev.poll(&mut [&mut midi_class]) {
+            // Read MIDI from the host and apply any mapped CCs, the panic CC or SysEx, or a SysEx config request.
+            let mut rx_buffer = [0u8; 64];
+            if let Ok(size) = midi_class.read(&mut rx_buffer) {
+                for packet in UsbMidiPacketReader::new(&rx_buffer, size).flatten() {
+                    if packet.is_sysex() {
+                        if sysex_in.feed(packet.payload_bytes()) {
+                            if let Some(reply) = handle_sysex(sysex_in.message()) {
+                                send_sysex(&mut midi_class, &reply);
+                            }
+                        }
+                        continue;
+                    }
+                    if let Ok(message) = MidiMessage::try_parse_slice(packet.payload_bytes()) {
+                        GLOBAL_STATE.lock(|global_state| {
+                            handle_midi_in(&mut global_state.borrow_mut(), message);
+                        });
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "defmt")]
+        if usb_dev.state() != last_usb_state {
+            last_usb_state = usb_dev.state();
+            midi_log!("USB state: {}", defmt::Debug2Format(&last_usb_state));
+        }
+
+        // --- Merge MIDI Thru from the serial input ---
+        // Our own events wait while a forwarded SysEx is open so they can't split it.
+        #[cfg(feature = "midi-thru")]
+        let thru_sysex_open = serial_midi::forward_thru(&mut midi_class);
+        #[cfg(not(feature = "midi-thru"))]
+        let thru_sysex_open = false;
+
+        // Muted: note events are dropped instead of sent.
+        let muted = GLOBAL_STATE.lock(|global_state| global_state.borrow().muted);
+        if muted {
+            ON_EVENTS.lock(|on_events| on_events.borrow_mut().clear());
+            OFF_EVENTS.lock(|off_events| off_events.borrow_mut().clear());
+            OFF_OVERFLOW.lock(|overflow| *overflow.borrow_mut() = [[0; 16]; NUM_CABLES as usize]);
+            DEFERRED_OFFS.lock(|deferred| deferred.borrow_mut().clear());
+        }
+
+        // Every message below goes through the channel remap right before it is rendered.
+        let channel_remap = GLOBAL_STATE.lock(|global_state| global_state.borrow().channel_remap);
+
+        // --- Send note-offs that overflowed their queue ---
+        // Before the note-ons, because their note-ons went out in an earlier pass.
+        if !thru_sysex_open {
+            let mut unsent = OFF_OVERFLOW.lock(|overflow| {
+                let mut overflow = overflow.borrow_mut();
+                let pending = *overflow;
+                *overflow = [[0; 16]; NUM_CABLES as usize];
+                pending
+            });
+            let (velocity, as_zero_on) = GLOBAL_STATE.lock(|global_state| {
+                let state = global_state.borrow();
+                (state.release_velocity, state.note_off_as_zero_velocity_on)
+            });
+            'overflow: for (cable, channels) in unsent.iter_mut().enumerate() {
+                let Ok(cable) = CableNumber::try_from(cable as u8) else {
+                    continue;
+                };
+                for (channel, notes) in channels.iter_mut().enumerate() {
+                    for note in 0..128u8 {
+                        if *notes & (1 << note) == 0 {
+                            continue;
+                        }
+                        let channel = Channel::from(channel as u8);
+                        let message = messages::note_off_message(channel, Note::from(note), velocity, as_zero_on);
+                        let mut bytes: [u8; 3] = [0; 3];
+                        messages::remap_channel(&channel_remap, message).render_slice(&mut bytes);
+                        let sent = match midi_packet(cable, &bytes) {
+                            Some(packet) => midi_class.send_packet(packet).is_ok(),
+                            None => true, // Dropped.
+                        };
+                        if !sent {
+                            break 'overflow;
+                        }
+                        *notes &= !(1 << note);
+                    }
+                }
+            }
+            // Whatever didn't go out is retried on the next pass.
+            OFF_OVERFLOW.lock(|overflow| {
+                for (pending, unsent) in overflow.borrow_mut().iter_mut().flatten().zip(unsent.iter().flatten()) {
+                    *pending |= unsent;
+                }
+            });
+        }
+
+        // --- Send note-offs whose minimum gate has passed ---
+        // Before the note-ons, so a retrigger's note-off can't cut its new note short. If one doesn't go out, the
+        // note-ons wait for the next pass too.
+        let mut deferred_unsent = false;
+        if !thru_sysex_open {
+            let now = Instant::now();
+            let as_zero_on = GLOBAL_STATE.lock(|global_state| global_state.borrow().note_off_as_zero_velocity_on);
+            let due = DEFERRED_OFFS.lock(|deferred| {
+                let mut deferred = deferred.borrow_mut();
+                let due: Vec<(NoteEvent, Instant), 32> = deferred.iter().filter(|&&(_, at)| now >= at).copied().collect();
+                deferred.retain(|&(_, at)| now < at);
+                due
+            });
+            for (note_off, at) in due {
+                let Some(note) = utils::clamped_note(note_off.note) else {
+                    continue; // Its note-on was never sent either.
+                };
+                let velocity = utils::clamped_value7(note_off.velocity as i32);
+                let message = messages::note_off_message(note_off.channel, note, velocity, as_zero_on);
+                let mut bytes: [u8; 3] = [0; 3];
+                messages::remap_channel(&channel_remap, message).render_slice(&mut bytes);
+                let Some(packet) = midi_packet(note_off.cable, &bytes) else {
+                    continue;
+                };
+                if deferred_unsent || midi_class.send_packet(packet).is_err() {
+                    let kept = DEFERRED_OFFS.lock(|deferred| deferred.borrow_mut().push((note_off, at)).is_ok());
+                    if !kept {
+                        push_note_off(note_off);
+                    }
+                    deferred_unsent = true;
+                } else {
+                    down_pulse_until = Instant::now() + LED_PULSE;
+                }
+            }
+        }
+
+        // Cancel retriggers before either queue is sent, when enabled.
+        let coalesce = GLOBAL_STATE.lock(|global_state| global_state.borrow().coalesce);
+        if coalesce {
+            OFF_EVENTS.lock(|off_events| {
+                ON_EVENTS.lock(|on_events| {
+                    queue::coalesce_retriggers(&mut on_events.borrow_mut(), &mut off_events.borrow_mut());
+                });
+            });
+        }
 
         // --- Process Note ON events ---
-        {
+        if !thru_sysex_open && !deferred_unsent {
             let on_events_to_send = ON_EVENTS.lock(|on_events| {
                 // Get the note-on events from the mutex.
                 let mut events = on_events.borrow_mut();
@@ -183,27 +2977,66 @@ async fn main(spawner: Spawner) {
                 events.clear();
                 events_to_send
             });
+            let mirror_cc = GLOBAL_STATE.lock(|global_state| global_state.borrow().mirror_velocity_to_cc);
+            let mut on_unsent = false;
             for note_on in on_events_to_send.into_iter() {
+                if on_unsent {
+                    requeue_note_on(note_on); // Kept in order behind the one that failed.
+                    continue;
+                }
+                let Some(note) = utils::clamped_note(note_on.note) else {
+                    continue; // Shifted out of the MIDI note range.
+                };
+                if let Some(control) = mirror_cc {
+                    // The velocity CC goes first, so the synth has it when the note starts.
+                    let velocity = utils::clamped_value7(note_on.velocity as i32);
+                    let message = MidiMessage::ControlChange(note_on.channel, control.into(), velocity);
+                    let mut bytes: [u8; 3] = [0; 3];
+                    messages::remap_channel(&channel_remap, message).render_slice(&mut bytes);
+                    let sent = match midi_packet(note_on.cable, &bytes) {
+                        Some(packet) => midi_class.send_packet(packet).is_ok(),
+                        None => true, // Dropped, the note still plays.
+                    };
+                    if !sent {
+                        requeue_note_on(note_on);
+                        on_unsent = true;
+                        continue;
+                    }
+                }
                 let mut bytes: [u8; 3] = [0; 3]; // Create a buffer for the MIDI message.
-                let message =
-                    MidiMessage::NoteOn(Channel::C1, Note::from(note_on as u8), Value7::from(127)); // Create a MIDI message. Currently set to channel 1/127 velocity.
-                message.render_slice(&mut bytes); // Render the message to the buffer.
-                let packet = // Create a MIDI packet from the buffer.
-                    UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, &bytes)
-                        .unwrap();
+                let message = MidiMessage::NoteOn(
+                    note_on.channel,
+                    note,
+                    utils::clamped_value7(note_on.velocity as i32),
+                ); // Create a MIDI message.
+                messages::remap_channel(&channel_remap, message).render_slice(&mut bytes); // Render the message to the buffer.
+                let Some(packet) = midi_packet(note_on.cable, &bytes) else {
+                    continue; // Malformed, see `midi_packet`.
+                };
+                midi_log!(
+                    "Note on {} ch {} vel {}, {}us after the key",
+                    note_on.note,
+                    u8::from(note_on.channel) + 1,
+                    note_on.velocity,
+                    (Instant::now() - note_on.at).as_micros()
+                );
                 let result = midi_class.send_packet(packet); // Send the packet.
                 // If sending fails, reinsert the event to prevent dropped MIDI messages.
                 if result.is_err() {
-                    ON_EVENTS.lock(|on_events| {
-                        let mut events = on_events.borrow_mut();
-                        events.push(note_on).ok();
-                    });
+                    midi_log!("Note on send failed: {}", defmt::Debug2Format(&result));
+                    requeue_note_on(note_on);
+                    on_unsent = true;
+                } else {
+                    up_pulse_until = Instant::now() + LED_PULSE;
                 }
             }
         }
 
         // --- Process Note OFF events ---
-        {
+        // In sequence order with the note-ons: a note-off queued after a note-on that is still waiting (it failed to
+        // send, or the note-ons were held back) waits too, or the retried note-on would leave its note sounding.
+        if !thru_sysex_open {
+            let first_unsent_on = ON_EVENTS.lock(|on_events| on_events.borrow().first().map(|note_on| note_on.seq));
             let off_events_to_send = OFF_EVENTS.lock(|off_events| {
                 // Get the note-off events from the mutex.
                 let mut events = off_events.borrow_mut();
@@ -211,51 +3044,139 @@ async fn main(spawner: Spawner) {
                 events.clear();
                 events_to_send
             });
+            let as_zero_on = GLOBAL_STATE.lock(|global_state| global_state.borrow().note_off_as_zero_velocity_on);
             for note_off in off_events_to_send.into_iter() {
+                if queue::waits_for_note_on(&note_off, first_unsent_on) {
+                    requeue_note_off(note_off);
+                    continue;
+                }
+                let Some(note) = utils::clamped_note(note_off.note) else {
+                    continue; // Its note-on was never sent either.
+                };
                 let mut bytes: [u8; 3] = [0; 3]; // Create a buffer for the MIDI message.
-                let message =
-                    MidiMessage::NoteOff(Channel::C1, Note::from(note_off as u8), Value7::from(0)); // Create a MIDI message. Currently set to channel 1/0 velocity.
-                message.render_slice(&mut bytes);// Render the message to the buffer.
-                let packet = // Create a MIDI packet from the buffer.
-                    UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, &bytes)
-                        .unwrap();
+                let message = messages::note_off_message(
+                    note_off.channel,
+                    note,
+                    utils::clamped_value7(note_off.velocity as i32),
+                    as_zero_on,
+                ); // Create a MIDI message.
+                messages::remap_channel(&channel_remap, message).render_slice(&mut bytes);// Render the message to the buffer.
+                let Some(packet) = midi_packet(note_off.cable, &bytes) else {
+                    continue; // Malformed, see `midi_packet`.
+                };
+                midi_log!(
+                    "Note off {} ch {}, {}us after the key",
+                    note_off.note,
+                    u8::from(note_off.channel) + 1,
+                    (Instant::now() - note_off.at).as_micros()
+                );
                 let result = midi_class.send_packet(packet); // Send the packet.
                 // If sending fails, reinsert the event to prevent dropped MIDI messages.
                 if result.is_err() {
-                    OFF_EVENTS.lock(|off_events| {
-                        let mut events = off_events.borrow_mut();
-                        events.push(note_off).ok();
-                    });
+                    midi_log!("Note off send failed: {}", defmt::Debug2Format(&result));
+                    requeue_note_off(note_off);
+                } else {
+                    down_pulse_until = Instant::now() + LED_PULSE;
                 }
             }
         }
 
-        // Update LED blink based on the current octave.
-        let oct = GLOBAL_STATE.lock(|global_state| global_state.borrow().octave);
-        if oct > 4 {
-            up_led_timer += 1;
-            if down_led.is_set_high() {
-                down_led.set_low();
+        // --- Process other messages (CCs etc.) ---
+        // After the notes, so an All Notes Off from muting always follows the last note-on sent.
+        if !thru_sysex_open {
+            let messages_to_send = MESSAGE_EVENTS.lock(|message_events| {
+                let mut events = message_events.borrow_mut();
+                let events_to_send = events.clone();
+                events.clear();
+                events_to_send
+            });
+            for (sent, event) in messages_to_send.iter().enumerate() {
+                let mut bytes: [u8; 3] = [0; 3];
+                // System messages render to a single byte, see `SystemMsg`; only that much goes in the packet.
+                let len = messages::remap_channel(&channel_remap, event.message).render_slice(&mut bytes);
+                let Some(packet) = midi_packet(event.cable, &bytes[..len]) else {
+                    continue; // Not a message a single packet can carry.
+                };
+                if midi_class.send_packet(packet).is_err() {
+                    // Put this and the rest back in front of anything queued meanwhile, keeping their order.
+                    MESSAGE_EVENTS.lock(|message_events| {
+                        let mut events = message_events.borrow_mut();
+                        for (position, unsent) in messages_to_send[sent..].iter().enumerate() {
+                            events.insert(position, *unsent).ok();
+                        }
+                    });
+                    break;
+                }
             }
-            if up_led_timer > ((12 - oct) * 50) {
-                up_led.toggle();
-                up_led_timer = 0;
+        }
+
+        // Update the LEDs based on the LED mode.
+        let (oct, home, blink_period, led_mode, octave_leds, last_beat, learn_leds) = GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            state.repeat_octave(Instant::now());
+            fire_long_presses(&mut state, Instant::now());
+            start_settled_notes(&mut state, Instant::now());
+            state.release_overlong_notes(Instant::now());
+            state.update_count_pressure(Instant::now());
+            // Zone LEDs show their zone's octave whatever the LED mode, dark for a zone following the global octave.
+            let home = state.home_octave();
+            for (zone, leds) in state.zones.iter().zip(zone_leds.iter_mut()) {
+                if let Some((up_led, down_led, blink)) = leds {
+                    let octave = zone.octave.unwrap_or(home);
+                    blink.update(up_led, down_led, octave, home, state.led_blink_period_for(octave));
+                }
             }
-        } else if oct < 4 {
-            down_led_timer += 1;
-            if up_led.is_set_high() {
-                up_led.set_low();
+            (
+                state.octave,
+                state.home_octave(),
+                state.led_blink_period(),
+                state.led_mode,
+                state.has_octave_buttons(),
+                state.last_beat,
+                state.learn_leds(Instant::now()).or(state.tap_leds(Instant::now())),
+            )
+        });
+        match led_mode {
+            LedMode::Octave if octave_leds => octave_blink.update(&mut up_led, &mut down_led, oct, home, blink_period),
+            LedMode::Octave => {
+                set_led(&mut up_led, false);
+                set_led(&mut down_led, false);
             }
-            if down_led_timer > ((4 + oct) * 50) {
-                down_led.toggle();
-                down_led_timer = 0;
+            LedMode::Activity => {
+                // Up LED pulses on note-on, down LED on note-off.
+                let now = Instant::now();
+                set_led(&mut up_led, now < up_pulse_until);
+                set_led(&mut down_led, now < down_pulse_until);
             }
-        } else {
-            if down_led.is_set_high() {
-                down_led.set_low();
+            LedMode::ClockBeat => {
+                // Up LED flashes on every quarter note of the clock. Dark while no clock is running.
+                let lit = last_beat.is_some_and(|beat| Instant::now() < beat + LED_PULSE);
+                set_led(&mut up_led, lit);
+                set_led(&mut down_led, false);
             }
-            if up_led.is_set_high() {
-                up_led.set_low();
+        }
+        // Dark while idle. The octave blink timers keep running, so the blink picks up where it was on wake.
+        if mux::is_idle() {
+            set_led(&mut up_led, false);
+            set_led(&mut down_led, false);
+        }
+        // A key learn, or tapping the tempo, takes over both LEDs.
+        if let Some((up, down)) = learn_leds {
+            set_led(&mut up_led, up);
+            set_led(&mut down_led, down);
+        }
+
+        // Save a newly learned key map. Not from the edge callback: the flash write stalls for a while.
+        let unsaved = GLOBAL_STATE.lock(|global_state| {
+            let mut state = global_state.borrow_mut();
+            let unsaved = state.key_map_unsaved.then(|| controller_config(&state));
+            state.key_map_unsaved = false;
+            unsaved
+        });
+        if let Some(config) = unsaved {
+            // A config that doesn't serialize is not saved at all, so the last good one stays in flash.
+            if let Err(_status) = save_config(&config) {
+                midi_log!("Config save failed: {}", defmt::Debug2Format(&_status));
             }
         }
 
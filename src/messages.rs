@@ -0,0 +1,149 @@
+// The MIDI messages the main loop sends, built apart from the queues and the USB class so they can be tested on the
+// host.
+
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+use usbd_midi::{CableNumber, UsbMidiEventPacket};
+
+/// The message a release is sent as: a note-off, or with `as_zero_velocity_on` a note-on with velocity 0, which the
+/// MIDI spec treats the same and which lets a serial transport keep running status across presses and releases. The
+/// release velocity is lost that way.
+pub fn note_off_message(channel: Channel, note: Note, velocity: Value7, as_zero_velocity_on: bool) -> MidiMessage {
+    if as_zero_velocity_on {
+        MidiMessage::NoteOn(channel, note, Value7::new(0))
+    } else {
+        MidiMessage::NoteOff(channel, note, velocity)
+    }
+}
+
+/// The USB MIDI packet for a rendered message on `cable`, or None if `bytes` isn't one: empty, cut short, or an
+/// undefined status byte.
+pub fn packet(cable: CableNumber, bytes: &[u8]) -> Option<UsbMidiEventPacket> {
+    UsbMidiEventPacket::try_from_payload_bytes(cable, bytes).ok()
+}
+
+/// Bank Select MSB (CC 0), Bank Select LSB (CC 32) and the program change, in the order synths expect: the bank only
+/// takes effect with the program change. Values above 127 are clamped.
+pub fn patch_select(channel: Channel, bank_msb: u8, bank_lsb: u8, program: u8) -> [MidiMessage; 3] {
+    [
+        MidiMessage::ControlChange(channel, 0.into(), bank_msb.min(127).into()),
+        MidiMessage::ControlChange(channel, 32.into(), bank_lsb.min(127).into()),
+        MidiMessage::ProgramChange(channel, program.min(127).into()),
+    ]
+}
+
+/// Every channel to itself, the default remap.
+pub const IDENTITY_REMAP: [Channel; 16] = [
+    Channel::C1,
+    Channel::C2,
+    Channel::C3,
+    Channel::C4,
+    Channel::C5,
+    Channel::C6,
+    Channel::C7,
+    Channel::C8,
+    Channel::C9,
+    Channel::C10,
+    Channel::C11,
+    Channel::C12,
+    Channel::C13,
+    Channel::C14,
+    Channel::C15,
+    Channel::C16,
+];
+
+/// `message` with its channel translated through `remap`. Messages without a channel are unchanged.
+pub fn remap_channel(remap: &[Channel; 16], message: MidiMessage) -> MidiMessage {
+    let to = |channel: Channel| remap[u8::from(channel) as usize];
+    match message {
+        MidiMessage::NoteOff(channel, note, velocity) => MidiMessage::NoteOff(to(channel), note, velocity),
+        MidiMessage::NoteOn(channel, note, velocity) => MidiMessage::NoteOn(to(channel), note, velocity),
+        MidiMessage::KeyPressure(channel, note, value) => MidiMessage::KeyPressure(to(channel), note, value),
+        MidiMessage::ControlChange(channel, control, value) => MidiMessage::ControlChange(to(channel), control, value),
+        MidiMessage::ProgramChange(channel, program) => MidiMessage::ProgramChange(to(channel), program),
+        MidiMessage::ChannelPressure(channel, value) => MidiMessage::ChannelPressure(to(channel), value),
+        MidiMessage::PitchBendChange(channel, value) => MidiMessage::PitchBendChange(to(channel), value),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases_are_note_offs_by_default() {
+        let message = note_off_message(Channel::C3, Note::new(60), Value7::new(40), false);
+        assert_eq!(message, MidiMessage::NoteOff(Channel::C3, Note::new(60), Value7::new(40)));
+    }
+
+    #[test]
+    fn releases_can_be_zero_velocity_note_ons() {
+        let message = note_off_message(Channel::C3, Note::new(60), Value7::new(40), true);
+        assert_eq!(message, MidiMessage::NoteOn(Channel::C3, Note::new(60), Value7::new(0)));
+    }
+
+    #[test]
+    fn rendered_messages_become_packets() {
+        let packet = packet(CableNumber::Cable1, &[0x92, 60, 100]).unwrap();
+        assert_eq!(packet.cable_number(), CableNumber::Cable1);
+        assert_eq!(packet.payload_bytes(), &[0x92, 60, 100]);
+        // A one byte system message only takes its own byte.
+        assert_eq!(super::packet(CableNumber::Cable0, &[0xfa]).unwrap().payload_bytes(), &[0xfa]);
+    }
+
+    #[test]
+    fn truncated_messages_have_no_packet() {
+        assert!(packet(CableNumber::Cable0, &[]).is_none());
+        assert!(packet(CableNumber::Cable0, &[0x90, 60]).is_none());
+        assert!(packet(CableNumber::Cable0, &[0xb0]).is_none());
+        assert!(packet(CableNumber::Cable0, &[0xc0]).is_none());
+    }
+
+    #[test]
+    fn garbage_has_no_packet() {
+        assert!(packet(CableNumber::Cable0, &[0xf4, 0, 0]).is_none()); // Undefined status.
+        assert!(packet(CableNumber::Cable0, &[0xfd]).is_none());
+        assert!(packet(CableNumber::Cable0, &[0x12, 0x34]).is_none()); // Data bytes without a status.
+    }
+
+    #[test]
+    fn patch_select_is_bank_msb_lsb_then_program() {
+        assert_eq!(
+            patch_select(Channel::C2, 3, 100, 42),
+            [
+                MidiMessage::ControlChange(Channel::C2, 0.into(), 3.into()),
+                MidiMessage::ControlChange(Channel::C2, 32.into(), 100.into()),
+                MidiMessage::ProgramChange(Channel::C2, 42.into()),
+            ]
+        );
+        // Out of range values are clamped rather than wrapped.
+        let [msb, lsb, program] = patch_select(Channel::C1, 200, 128, 255);
+        assert_eq!(msb, MidiMessage::ControlChange(Channel::C1, 0.into(), 127.into()));
+        assert_eq!(lsb, MidiMessage::ControlChange(Channel::C1, 32.into(), 127.into()));
+        assert_eq!(program, MidiMessage::ProgramChange(Channel::C1, 127.into()));
+    }
+
+    #[test]
+    fn identity_remap_changes_nothing() {
+        let note_on = MidiMessage::NoteOn(Channel::C7, Note::new(60), Value7::new(100));
+        assert_eq!(remap_channel(&IDENTITY_REMAP, note_on), note_on);
+        for channel in 0..16 {
+            let message = MidiMessage::ControlChange(Channel::from(channel), 7.into(), 100.into());
+            assert_eq!(remap_channel(&IDENTITY_REMAP, message), message);
+        }
+    }
+
+    #[test]
+    fn remapped_channels_go_out_on_their_target() {
+        let mut remap = IDENTITY_REMAP;
+        remap[0] = Channel::C5;
+        let note_on = MidiMessage::NoteOn(Channel::C1, Note::new(60), Value7::new(100));
+        assert_eq!(remap_channel(&remap, note_on), MidiMessage::NoteOn(Channel::C5, Note::new(60), Value7::new(100)));
+        let program = MidiMessage::ProgramChange(Channel::C1, 3.into());
+        assert_eq!(remap_channel(&remap, program), MidiMessage::ProgramChange(Channel::C5, 3.into()));
+        // Other channels and channelless messages are left alone.
+        let other = MidiMessage::NoteOff(Channel::C2, Note::new(60), Value7::new(0));
+        assert_eq!(remap_channel(&remap, other), other);
+        assert_eq!(remap_channel(&remap, MidiMessage::TimingClock), MidiMessage::TimingClock);
+    }
+}
@@ -1,5 +1,6 @@
-// This module provides a debounced driver for a 4051 8 channel multiplexer chip. Only input is supported currently. 
-//Should support up to 8 chips, but has only been tested with 4.
+// This module provides a debounced driver for a 4051 8 channel multiplexer chip. Chips can be digital inputs (switches,
+//debounced), analog inputs (pots, FSRs) or digital outputs (LEDs, driven while their channel is selected).
+//Should support up to 8 chips per select bank (two banks), but has only been tested with 4.
 //The driver is designed to be used with the async/await pattern.
 
 //Basic example:
@@ -15,36 +16,271 @@
 //    let chip_config = mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO4, Pull::Up));
 //Add the chip to the Multiplexer4051 instance.
 //    mux.add_chip(chip_config);
-//Finally, spawn the poll task.
+//Switches wired with pull-downs (pressed reads high) use `new_digital_input_active_high` instead.
+//Analog chips (pots, FSRs) read their common pin through the ADC. The ADC is shared, so keep it in a RefCell.
+//    let mut adc_config = AdcConfig::new();
+//    let pin = adc_config.enable_pin(peripherals.GPIO10, Attenuation::_11dB);
+//    let adc = ADC.init(RefCell::new(Adc::new(peripherals.ADC1, adc_config)));
+//    let source = SOURCE.init(mux::AdcSource::new(adc, pin));
+//    mux.add_chip(mux::MuxChipConfig::new_analog_input(source));
+//    mux.set_analog_callback(analog_handler);
+//Noisy analog inputs can average several readings per sweep with `new_analog_input_oversampled(source, 8)` instead.
+//Output chips (e.g. an LED behind each key) share the select lines. Mirror an input channel onto an output channel:
+//    mux.add_chip(mux::MuxChipConfig::new_digital_output(Output::new(peripherals.GPIO10, Level::Low)));
+//    mux.set_output_mirror(2, Some(0)); // Input channel 2 lights output channel 0 while pressed.
+//    mux.set_output_refresh_divider(4); // Outputs only driven every 4th sweep, see `set_output_refresh_divider`.
+//Keybeds mixing normally-closed contacts in can flip single channels:
+//    mux.set_inverted(9, true); // Chip 1, channel 1 is pressed when its contact opens.
+//Switches that chatter on lift-off can use a longer debounce after a release than after a press:
+//    mux.set_press_release_debounce(Duration::from_millis(5), Duration::from_millis(30));
+//Channels that aren't wired can be disabled, so a floating input never plays a phantom note:
+//    mux.set_channel_enabled(31, false);
+//Optionally, pick a debounce algorithm. TimeLockout is the default; Integrator suits noisy switches.
+//    mux.set_debounce_mode(mux::DebounceMode::Integrator { threshold: 4 });
+//Flaky membrane switches can also drop presses that release within 2ms, at the cost of 2ms more latency per press.
+//    mux.set_min_press(Some(debounce::DEFAULT_MIN_PRESS));
+//Optionally, run the power-on self-test. Channels already pressed are reported and ignored until released.
+//    let stuck = mux.run_self_test().await;
+//More than 8 chips, or select lines loaded down by many chips, can use a second set of select pins (3 more GPIOs).
+//Both sets switch to the same channel and every chip is read after one shared settle time, so the second bank adds
+//no settle delays. Chip indexing doesn't change: digital chips count on from bank A into bank B in the order added.
+//    mux.set_second_bank([Output::new(peripherals.GPIO11, Level::Low), ...]);
+//    mux.add_chip_to_second_bank(mux::MuxChipConfig::new_digital_input(Input::new(peripherals.GPIO14, Pull::Up)));
+//The same setup can be written as a chain with `mux::Multiplexer4051::builder(select)`, see `MultiplexerBuilder`.
+//Every per-channel array is sized for 64 channels (8 chips). Smaller builds can save the RAM with a channel count:
+//    let mut mux: mux::Multiplexer4051<'_, 32> = mux::Multiplexer4051::new(select); // 4 chips.
+//A rotary encoder takes two input channels for its contacts and optionally a third for its push switch, see
+//`add_encoder`. Turning it calls the rotation callback once per detent; the push switch is an ordinary debounced
+//channel with the usual edge callbacks.
+//    let encoder = mux.add_encoder(24, 25, Some(26)).unwrap();
+//    mux.set_rotation_callback(rotation_handler); // fn rotation_handler(encoder: usize, delta: i8)
+//Instead of the callbacks, a task can also await every edge, see `next_event`:
+//    let event = mux::next_event().await;
+//Finally, spawn the poll task. Once it runs, the task owns the mux; change it with `mux::request_reconfig`:
+//    mux::request_reconfig(|mux| mux.set_debounce_interval(Duration::from_millis(5))).ok();
 //    spawner.spawn(mux_poll_task(mux)).unwrap();
 
 use core::fmt::Debug;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
 use embassy_time::Duration;
 use embassy_time::{Timer, Instant};
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use esp_hal::analog::adc::{Adc, AdcChannel, AdcPin};
 use esp_hal::gpio::{Input, Output, };
+use esp_hal::peripherals::{ADC1, TIMG1};
+use esp_hal::timer::timg::Wdt;
 use heapless::Vec;
+use crate::scan;
+pub use crate::debounce::{DebounceMode, Debouncer, SwitchState};
+pub use crate::scan::CHANNELS;
+
+/// A change to apply to a running multiplexer, e.g. `|mux| mux.set_debounce_interval(Duration::from_millis(5))`.
+/// Values that aren't known at compile time can be read from a static inside the function.
+pub type Reconfig = fn(&mut Multiplexer4051<'_>);
+
+// Pending reconfigurations, applied by the poll loop between sweeps.
+static RECONFIG: Channel<CriticalSectionRawMutex, Reconfig, 4> = Channel::new();
+
+/// Asks the running poll loop to apply `reconfig` before its next sweep, so the channel state is never changed
+/// mid-sweep. Up to 4 requests can be pending; if the queue is full the request is handed back.
+pub fn request_reconfig(reconfig: Reconfig) -> Result<(), Reconfig> {
+    RECONFIG.try_send(reconfig).map_err(|embassy_sync::channel::TrySendError::Full(reconfig)| reconfig)
+}
+
+/// The debounced state of every channel at the end of one full sweep, see `SWEEP_COMPLETE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sweep {
+    pub pressed: u64, //Bit `index` is set if the channel is pressed, indexed as `channel + 8 * chip`.
+    pub at: Instant,  //When the sweep finished.
+}
+
+impl Sweep {
+    pub fn is_pressed(&self, index: usize) -> bool {
+        index < 64 && self.pressed & (1 << index) != 0
+    }
+}
+
+/// Raised by the poll loop after every full sweep with a snapshot of all channels, so a task that needs a consistent
+/// view of the inputs (e.g. chord detection) can wait for it instead of polling. Only the latest sweep is kept:
+///    loop {
+///        let sweep = mux::SWEEP_COMPLETE.wait().await;
+///        if sweep.is_pressed(2) && sweep.is_pressed(6) { ... }
+///    }
+pub static SWEEP_COMPLETE: Signal<CriticalSectionRawMutex, Sweep> = Signal::new();
+
+/// A debounced state change of one channel, see `next_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeEvent {
+    pub index: usize, //Indexed as `channel + 8 * chip`, like the callbacks.
+    pub edge: Edge,
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Falling, //Pressed.
+    Rising,  //Released.
+}
+
+// Edges waiting for `next_event`. Only published once something has waited for one, so no stale edges pile up for a
+// firmware that only uses the callbacks.
+static EDGE_EVENTS: Channel<CriticalSectionRawMutex, EdgeEvent, 16> = Channel::new();
+static EDGE_LISTENER: AtomicBool = AtomicBool::new(false);
+
+/// Waits for the next debounced edge on any channel, for a task that just wants to hear about every key change:
+///    loop {
+///        let event = mux::next_event().await;
+///        if event.edge == mux::Edge::Falling { ... }
+///    }
+/// A free function, as the poll task owns the mux. The callbacks and edge handler still fire first, from inside the
+/// sweep: use them for the note keys, where they keep the latency lowest, and this for slower consumers such as
+/// a sequencer or menu task, which can await it alongside timers. Up to 16 edges are queued; further ones are dropped
+/// until the consumer catches up. Edges from before the first call are not kept.
+pub async fn next_event() -> EdgeEvent {
+    EDGE_LISTENER.store(true, Ordering::Relaxed);
+    EDGE_EVENTS.receive().await
+}
+
+// Queues an edge for `next_event`, by the poll loop (or another input backend) after the callbacks.
+pub(crate) fn publish_edge(index: usize, edge: Edge) {
+    if EDGE_LISTENER.load(Ordering::Relaxed) {
+        EDGE_EVENTS.try_send(EdgeEvent { index, edge, at: Instant::now() }).ok();
+    }
+}
+
+// Set by the poll loop while it scans at the idle rate, see `Multiplexer4051::set_idle_timeout`.
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// True while the mux is idling: no edge for its idle timeout. The main loop uses it to turn the LEDs off.
+pub fn is_idle() -> bool {
+    IDLE.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MuxMode {
     DigitalInput,
     DigitalOutput,
+    AnalogInput,
+}
+
+/// Receives edges (and analog readings) with its own state, as an alternative to the plain `fn` callbacks that have to
+/// reach shared state through statics. Both can be set; the handler is called first.
+///
+///    struct Keys { held: u32 }
+///    impl mux::EdgeHandler for Keys {
+///        fn falling(&mut self, index: usize) { self.held |= 1 << index; }
+///        fn rising(&mut self, index: usize) { self.held &= !(1 << index); }
+///    }
+///    mux.set_edge_handler(KEYS_HANDLER.init(Keys { held: 0 }));
+pub trait EdgeHandler {
+    fn falling(&mut self, index: usize); //A channel's state changed from high to low (pressed).
+    fn rising(&mut self, index: usize); //A channel's state changed from low to high (released).
+    fn analog(&mut self, _index: usize, _value: u16) {} //A new analog reading. Ignored unless implemented.
+    fn rotation(&mut self, _encoder: usize, _delta: i8) {} //An encoder turned, see `add_encoder`. Ignored unless implemented.
+}
+
+pub const MAX_ENCODERS: usize = 4; //Encoders per multiplexer, see `Multiplexer4051::add_encoder`.
+
+/// Rotation dropped after an encoder's push switch changes state: pushing the knob down (or letting it go) tends to
+/// nudge it a little, which would otherwise turn the value the push is meant to reset.
+pub const ENCODER_PUSH_GUARD: Duration = Duration::from_millis(30);
+
+/// A rotary encoder on mux input channels, see `Multiplexer4051::add_encoder`. The channels are indexed as
+/// `channel + 8 * chip`, like the callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoder {
+    pub a: u8, //Contact A.
+    pub b: u8, //Contact B.
+    pub push: Option<u8>, //The push switch, if the encoder has one.
+    contacts: u8, //The latest readings, A closed in bit 1 and B closed in bit 0.
+    last: u8, //The contact state the last quarter step was counted to.
+    quarter_steps: i8, //Counted since the encoder last rested.
+    guard_until: Instant, //Rotation before this is dropped, see ENCODER_PUSH_GUARD.
+}
+
+impl Encoder {
+    // Both contacts open: where common encoders rest at a detent.
+    const REST: u8 = 0b00;
+
+    fn new(a: u8, b: u8, push: Option<u8>) -> Self {
+        Self { a, b, push, contacts: Self::REST, last: Self::REST, quarter_steps: 0, guard_until: Instant::from_ticks(0) }
+    }
+
+    // Counts the contact change since the last sweep and returns the detents turned: 1 clockwise, -1 counter-clockwise.
+    // The contacts follow a Gray code, so only one changes per quarter step and a bouncing contact just steps back and
+    // forth. A detent counts when the encoder comes back to rest at least two quarter steps further on, which needs no
+    // separate debounce and also resyncs after a missed reading.
+    fn update(&mut self, now: Instant) -> i8 {
+        let state = self.contacts;
+        if state == self.last {
+            return 0;
+        }
+        self.quarter_steps += match (self.last, state) {
+            (0b00, 0b10) | (0b10, 0b11) | (0b11, 0b01) | (0b01, 0b00) => 1,
+            (0b00, 0b01) | (0b01, 0b11) | (0b11, 0b10) | (0b10, 0b00) => -1,
+            _ => 0, // Both changed, a reading was missed: the direction is unknown.
+        };
+        self.last = state;
+        if state != Self::REST {
+            return 0;
+        }
+        let quarter_steps = core::mem::take(&mut self.quarter_steps);
+        if now < self.guard_until {
+            return 0; // Nudged by the push switch.
+        }
+        match quarter_steps {
+            2.. => 1,
+            ..=-2 => -1,
+            _ => 0,
+        }
+    }
+}
+
+/// Something that can read the analog level on an analog chip's common pin for the currently selected channel.
+pub trait AnalogSource {
+    fn read(&mut self) -> u16; //Returns the raw reading, e.g. 0..4095 for the 12 bit ADC.
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum SwitchState {
-    High,
-    Low,
+/// Reads a chip's common pin through ADC1. The ADC is shared between chips, so it lives in a RefCell.
+pub struct AdcSource<'a, PIN> {
+    adc: &'a RefCell<Adc<'a, ADC1>>,
+    pin: AdcPin<PIN, ADC1>,
+}
+
+impl<'a, PIN> AdcSource<'a, PIN> {
+    pub fn new(adc: &'a RefCell<Adc<'a, ADC1>>, pin: AdcPin<PIN, ADC1>) -> Self {
+        Self { adc, pin }
+    }
+}
+
+impl<PIN: AdcChannel> AnalogSource for AdcSource<'_, PIN> {
+    fn read(&mut self) -> u16 {
+        loop {
+            // The conversion is only in progress until the result is ready.
+            if let Ok(value) = self.adc.borrow_mut().read_oneshot(&mut self.pin) {
+                return value;
+            }
+        }
+    }
 }
 
 pub enum MuxChipConfig<'a> {
     DigitalInput {
         common: Input<'a>,
         states: Vec<SwitchState, 8>,
+        active_low: bool, //True if a pressed switch reads low (pull-ups), false if it reads high (pull-downs).
     },
     DigitalOutput {
         common: Output<'a>,
         states: Vec<bool, 16>,
     },
+    AnalogInput {
+        common: &'a mut dyn AnalogSource,
+        oversample: u8, //Readings averaged per channel per sweep. 1 reads once.
+    },
 }
 
 impl<'a> MuxChipConfig<'a> {
@@ -53,10 +289,18 @@ impl<'a> MuxChipConfig<'a> {
         for _ in 0..8 {
             states.push(SwitchState::High).ok();
         }
-        Self::DigitalInput { common, states }
+        Self::DigitalInput { common, states, active_low: true }
     }
 
-    pub fn new_digital_output(common: Output<'a>) -> Self { //Not yet implemented.
+    pub fn new_digital_input_active_high(common: Input<'a>) -> Self { //Like new_digital_input, for switches wired with pull-downs that read high when pressed.
+        let mut chip = Self::new_digital_input(common);
+        if let Self::DigitalInput { active_low, .. } = &mut chip {
+            *active_low = false;
+        }
+        chip
+    }
+
+    pub fn new_digital_output(common: Output<'a>) -> Self { //This creates a new digital output chip, e.g. for LEDs. Requires a common GPIO pin. A channel is driven only while it's selected, so LEDs are lit for 1/8 of each sweep.
         let mut states: Vec<bool, 16> = Vec::new();
         for _ in 0..16 {
             states.push(false).ok();
@@ -64,51 +308,302 @@ impl<'a> MuxChipConfig<'a> {
         Self::DigitalOutput { common, states }
     }
 
+    pub fn new_analog_input(common: &'a mut dyn AnalogSource) -> Self { //This creates a new analog input chip. Requires an analog source for the common pin.
+        Self::AnalogInput { common, oversample: 1 }
+    }
+
+    pub fn new_analog_input_oversampled(common: &'a mut dyn AnalogSource, oversample: u8) -> Self { //Like new_analog_input, averaging `oversample` readings per channel per sweep for less noise. Each extra reading is one more ADC conversion (tens of microseconds) per channel, so a sweep of 8 channels at 16x takes a few milliseconds longer. With set_analog_smoothing on top, the filter also reacts over that many more milliseconds.
+        let mut chip = Self::new_analog_input(common);
+        if let Self::AnalogInput { oversample: samples, .. } = &mut chip {
+            *samples = oversample.max(1);
+        }
+        chip
+    }
+
     pub fn mode(&self) -> MuxMode {
         match self {
             Self::DigitalInput { .. } => MuxMode::DigitalInput,
             Self::DigitalOutput { .. } => MuxMode::DigitalOutput,
+            Self::AnalogInput { .. } => MuxMode::AnalogInput,
         }
     }
 }
 
-pub struct Multiplexer4051<'a> {
+/// Why a `MultiplexerBuilder` refused to build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    NoChips, //No chip was added.
+    TooManyChips, //More than 8 chips were added to one bank.
+    NoSecondBank, //Chips were added to the second bank without its select pins.
+    BadEncoder, //An encoder on a channel past the mux, or more than MAX_ENCODERS of them.
+}
+
+/// Chained configuration for a `Multiplexer4051`, validated by `build()`.
+///
+///    let mux = mux::Multiplexer4051::builder(select)
+///        .chip(chip1_config)
+///        .debounce(Duration::from_millis(20))
+///        .on_falling(falling_edge_handler)
+///        .on_rising(rising_edge_handler)
+///        .build()
+///        .unwrap();
+pub struct MultiplexerBuilder<'a, const CH: usize = CHANNELS> {
+    mux: Multiplexer4051<'a, CH>,
+    too_many_chips: bool,
+    bad_encoder: bool,
+}
+
+impl<'a, const CH: usize> MultiplexerBuilder<'a, CH> {
+    pub fn chip(mut self, chip: MuxChipConfig<'a>) -> Self { //Adds a chip to the multiplexer.
+        if !self.mux.push_chip(chip, false) {
+            self.too_many_chips = true;
+        }
+        self
+    }
+
+    pub fn second_bank(mut self, select: [Output<'a>; 3]) -> Self { //Sets the select pins of the second bank.
+        self.mux.set_second_bank(select);
+        self
+    }
+
+    pub fn chip_b(mut self, chip: MuxChipConfig<'a>) -> Self { //Adds a chip on the second bank's select pins.
+        if !self.mux.push_chip(chip, true) {
+            self.too_many_chips = true;
+        }
+        self
+    }
+
+    pub fn debounce(mut self, interval: Duration) -> Self { //Sets the debounce interval.
+        self.mux.set_debounce_interval(interval);
+        self
+    }
+
+    pub fn settle_time(mut self, settle_time: Duration) -> Self { //Sets the wait after each channel change.
+        self.mux.set_settle_time(settle_time);
+        self
+    }
+
+    pub fn debounce_mode(mut self, mode: DebounceMode) -> Self { //Sets the debounce algorithm.
+        self.mux.set_debounce_mode(mode);
+        self
+    }
+
+    pub fn min_press(mut self, min_press: Duration) -> Self { //Ignores presses shorter than this, e.g. debounce::DEFAULT_MIN_PRESS.
+        self.mux.set_min_press(Some(min_press));
+        self
+    }
+
+    pub fn on_falling(mut self, callback: fn(usize)) -> Self { //Sets the callback for when a channel's state changes from high to low.
+        self.mux.set_falling_edge_callback(callback);
+        self
+    }
+
+    pub fn on_rising(mut self, callback: fn(usize)) -> Self { //Sets the callback for when a channel's state changes from low to high.
+        self.mux.set_rising_edge_callback(callback);
+        self
+    }
+
+    pub fn on_analog(mut self, callback: fn(usize, u16)) -> Self { //Sets the callback that receives every analog reading.
+        self.mux.set_analog_callback(callback);
+        self
+    }
+
+    pub fn encoder(mut self, a: usize, b: usize, push: Option<usize>) -> Self { //Adds a rotary encoder, see `Multiplexer4051::add_encoder`.
+        if self.mux.add_encoder(a, b, push).is_none() {
+            self.bad_encoder = true;
+        }
+        self
+    }
+
+    pub fn on_rotation(mut self, callback: fn(usize, i8)) -> Self { //Sets the callback for every detent an encoder turns.
+        self.mux.set_rotation_callback(callback);
+        self
+    }
+
+    /// Returns the configured multiplexer, or an error if no chips, more than 8 chips on a bank, second bank chips
+    /// without second bank select pins, or an encoder that doesn't fit were added.
+    pub fn build(self) -> Result<Multiplexer4051<'a, CH>, BuildError> {
+        if self.too_many_chips {
+            Err(BuildError::TooManyChips)
+        } else if self.bad_encoder {
+            Err(BuildError::BadEncoder)
+        } else if self.mux.bank_b != 0 && self.mux.select_b.is_none() {
+            Err(BuildError::NoSecondBank)
+        } else if self.mux.chips.is_empty() {
+            Err(BuildError::NoChips)
+        } else {
+            Ok(self.mux)
+        }
+    }
+}
+
+pub struct Multiplexer4051<'a, const CH: usize = CHANNELS> {
     pub select: [Output<'a>; 3], //The GPIO pins for the 4051's select pins.
-    pub chips: Vec<MuxChipConfig<'a>, 8>, //The multiplexing chips wired to the micro controller.
-    pub digital_in: Vec<SwitchState, 64>, //The stable state of all 64 channels.
-    last_change: [Instant; 64], //The last time each channel changed state.
-    debounce_interval: Duration, //The debounce interval for all channels.
+    select_b: Option<[Output<'a>; 3]>, //The select pins of the second bank, driven to the same channel as `select`.
+    pub chips: Vec<MuxChipConfig<'a>, 16>, //The multiplexing chips wired to the micro controller, both banks.
+    bank_b: u16, //Chips on the second bank, one bit per position in `chips`.
+    pub debouncer: Debouncer<CH>, //The debounced state of all digital input channels.
+    settle_time: Duration, //How long to wait after changing the select pins before reading a channel.
+    scan_order: Vec<u8, 16>, //The order channels are selected in each sweep. A channel can appear more than once.
+    output_mirror: [Option<u8>; CH], //For each input channel, the output channel that lights up while it's pressed.
+    output_refresh_divider: u8, //Output chips are driven every this many sweeps, 1 is every sweep.
+    sweeps_to_refresh: u8, //Sweeps left before the outputs are driven again.
+    analog_smoothing: u8, //Exponential moving average strength for analog channels, 0 is off.
+    inverted: u64, //Normally-closed channels, one bit per channel.
+    disabled: u64, //Digital input channels that are never read, one bit per channel.
+    analog_filter: [Option<u32>; CH], //Filter state per analog channel with 4 fractional bits. None until the first reading.
+    pub analog_in: [u16; CH], //The latest (filtered) reading of all analog channels, indexed as `channel + 8 * analog chip`.
     pub falling_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from high to low.
     pub rising_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from low to high.
+    pub analog_callback: Option<fn(usize, u16)>, //Callback with every new analog reading.
+    pub rotation_callback: Option<fn(usize, i8)>, //Callback with the detents an encoder turned, by encoder.
+    encoders: Vec<Encoder, MAX_ENCODERS>, //Rotary encoders, in the order added.
+    edge_handler: Option<&'a mut dyn EdgeHandler>, //Handler with its own state, called before the callbacks.
+    watchdog: Option<Wdt<TIMG1>>, //Hardware watchdog fed after every sweep.
+    idle_timeout: Option<Duration>, //Time without an edge before sweeps slow down, None never idles.
+    idle_interval: Duration, //Wait between sweeps while idle.
+    last_edge: Instant, //When the last debounced edge was reported.
 }
 
-impl<'a> Multiplexer4051<'a> {
-    pub fn new(select: [Output<'a>; 3]) -> Self {
-        // Initialize the stable state for all 64 channels.
-        let mut digital_in: Vec<SwitchState, 64> = Vec::new();
-        for _ in 0..64 {
-            digital_in.push(SwitchState::High);
-        }
-        // Default debounce interval is 20ms.
-        let debounce_interval = Duration::from_millis(20);
-        let now = Instant::now();
-        // Initialize each channel's last-change timestamp to allow immediate changes.
-        let last_change = [now - debounce_interval; 64];
+impl<'a, const CH: usize> Multiplexer4051<'a, CH> {
+    // Sweep snapshots and the normally-closed mask have one bit per channel.
+    const FITS_BITMASK: () = assert!(CH <= 64, "a mux has at most 64 channels");
 
+    pub fn new(select: [Output<'a>; 3]) -> Self {
+        let () = Self::FITS_BITMASK;
         Self {
             select,
+            select_b: None,
             chips: Vec::new(),
-            digital_in,
-            last_change,
-            debounce_interval,
+            bank_b: 0,
+            debouncer: Debouncer::new(),
+            settle_time: Duration::from_micros(50),
+            scan_order: Vec::from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap(),
+            output_mirror: [None; CH],
+            output_refresh_divider: 1,
+            sweeps_to_refresh: 0,
+            analog_smoothing: 0,
+            inverted: 0,
+            disabled: 0,
+            analog_filter: [None; CH],
+            analog_in: [0; CH],
             falling_edge_callback: None,
             rising_edge_callback: None,
+            analog_callback: None,
+            rotation_callback: None,
+            encoders: Vec::new(),
+            edge_handler: None,
+            watchdog: None,
+            idle_timeout: None,
+            idle_interval: Duration::from_millis(10),
+            last_edge: Instant::now(),
+        }
+    }
+
+    /// Starts a chained configuration. See `MultiplexerBuilder`.
+    pub fn builder(select: [Output<'a>; 3]) -> MultiplexerBuilder<'a, CH> {
+        MultiplexerBuilder {
+            mux: Self::new(select),
+            too_many_chips: false,
+            bad_encoder: false,
         }
     }
 
     /// Allows the main script to change the debounce interval.
     pub fn set_debounce_interval(&mut self, interval: Duration) {
-        self.debounce_interval = interval;
+        self.debouncer.set_interval(interval);
+    }
+
+    /// Sets separate debounce intervals after a press and after a release, see `Debouncer::set_intervals`.
+    pub fn set_press_release_debounce(&mut self, press_debounce: Duration, release_debounce: Duration) {
+        self.debouncer.set_intervals(press_debounce, release_debounce);
+    }
+
+    /// Allows the main script to change how long the chips get to settle after a channel change. Default is 50µs.
+    pub fn set_settle_time(&mut self, settle_time: Duration) {
+        self.settle_time = settle_time;
+    }
+
+    /// Sets the order channels are selected in each sweep, e.g. to read a latency-sensitive pad first. Channels can be
+    /// listed more than once (up to 16 entries) and channels left out are not scanned at all. Entries above 7 and entries
+    /// past 16 are ignored.
+    pub fn set_scan_order(&mut self, order: &[u8]) {
+        self.scan_order = scan::scan_order(order);
+    }
+
+    /// Reads the given channels twice per sweep, at the start and halfway through, so a key on them is seen up to half a
+    /// sweep sooner. Every extra read adds one settle time to the sweep, slowing all other channels a little. Priority
+    /// channels also count twice as fast in `DebounceMode::Integrator`.
+    pub fn set_priority_channels(&mut self, channels: &[u8]) {
+        self.scan_order = scan::priority_order(channels);
+    }
+
+    /// Smooths analog readings with an integer exponential moving average before they reach `analog_in` and the analog
+    /// callback. Each reading moves the output 1/2^`shift` of the way to the new reading, so higher is smoother but
+    /// slower. 0 turns smoothing off; values above 8 are treated as 8. The output always settles on the exact reading,
+    /// so a pot at the end of its travel still reports 0 or full scale.
+    pub fn set_analog_smoothing(&mut self, shift: u8) {
+        self.analog_smoothing = shift.min(8);
+        self.analog_filter = [None; CH];
+    }
+
+    /// Marks a digital input channel as normally-closed: its contact is closed at rest and opens when pressed, so its
+    /// reading is flipped. The chip's polarity still decides what a closed contact reads, this only flips it for the
+    /// one channel, so for that channel it wins over the chip setting. Out of range indices are ignored.
+    pub fn set_inverted(&mut self, index: usize, inverted: bool) {
+        if index >= CH {
+            return;
+        }
+        if inverted {
+            self.inverted |= 1 << index;
+        } else {
+            self.inverted &= !(1 << index);
+        }
+    }
+
+    /// Enables or disables a digital input channel (all are enabled by default). A disabled channel is never read or
+    /// debounced and fires no callbacks, so an unwired, floating input can't play phantom notes. A mux channel whose
+    /// channels are disabled on every chip is skipped in the sweep altogether, settle time included, as long as there
+    /// are no analog or output chips that need it. Disable channels before the poll task starts: a channel disabled
+    /// while pressed keeps reading as pressed. Out of range indices are ignored.
+    pub fn set_channel_enabled(&mut self, index: usize, enabled: bool) {
+        if index >= CH {
+            return;
+        }
+        if enabled {
+            self.disabled &= !(1 << index);
+        } else {
+            self.disabled |= 1 << index;
+        }
+    }
+
+    // True if no chip needs mux channel `channel` selected: every digital input channel on it is disabled and there are
+    // no analog or output chips.
+    fn channel_unused(&self, channel: usize) -> bool {
+        let mut digital_chip = 0;
+        for chip in self.chips.iter() {
+            match chip {
+                MuxChipConfig::DigitalInput { .. } => {
+                    let index = scan::channel_index(channel, digital_chip, CH);
+                    if index.is_some_and(|index| self.disabled & (1 << index) == 0) {
+                        return false;
+                    }
+                    digital_chip += 1;
+                }
+                MuxChipConfig::AnalogInput { .. } | MuxChipConfig::DigitalOutput { .. } => return false,
+            }
+        }
+        true
+    }
+
+    /// Allows the main script to change the debounce algorithm. Resets any partially integrated reads.
+    pub fn set_debounce_mode(&mut self, mode: DebounceMode) {
+        self.debouncer.set_mode(mode);
+    }
+
+    /// Ignores presses shorter than `min_press`, see `Debouncer::set_min_press`. E.g. `Some(debounce::DEFAULT_MIN_PRESS)`.
+    pub fn set_min_press(&mut self, min_press: Option<Duration>) {
+        self.debouncer.set_min_press(min_press);
     }
 
     pub fn set_falling_edge_callback(&mut self, callback: fn(usize)) { //Sets the callback for when a channel's state changes from high to low.
@@ -119,76 +614,407 @@ impl<'a> Multiplexer4051<'a> {
         self.rising_edge_callback = Some(callback);
     }
 
+    pub fn set_analog_callback(&mut self, callback: fn(usize, u16)) { //Sets the callback that receives every analog reading.
+        self.analog_callback = Some(callback);
+    }
+
+    pub fn set_rotation_callback(&mut self, callback: fn(usize, i8)) { //Sets the callback for every detent an encoder turns.
+        self.rotation_callback = Some(callback);
+    }
+
+    /// Adds a rotary encoder with its contacts on input channels `a` and `b` and, if it has one, its push switch on
+    /// `push`, all indexed as `channel + 8 * chip`. Returns the encoder's index for the rotation callback, or None if
+    /// a channel is past the mux or MAX_ENCODERS are already added.
+    /// The contacts leave the usual debounce and edge callbacks: they are decoded into detents instead (see `Encoder`)
+    /// and reported once per sweep to the rotation callback, clockwise positive (swap `a` and `b` if it counts the
+    /// wrong way). The push switch is debounced and
+    /// reported like any other channel, and for ENCODER_PUSH_GUARD after each of its edges the encoder's rotation is
+    /// dropped. The encoder is expected to rest with both contacts open; one resting with them closed needs both
+    /// channels inverted with `set_inverted`. A contact changes about four times per detent, so it has to be read
+    /// faster than that: each sweep reads it once.
+    pub fn add_encoder(&mut self, a: usize, b: usize, push: Option<usize>) -> Option<usize> {
+        if a >= CH || b >= CH || push.is_some_and(|push| push >= CH) {
+            return None;
+        }
+        self.encoders.push(Encoder::new(a as u8, b as u8, push.map(|push| push as u8))).ok()?;
+        Some(self.encoders.len() - 1)
+    }
+
+    pub fn set_edge_handler(&mut self, handler: &'a mut dyn EdgeHandler) { //Sets a handler that receives edges and analog readings with its own state.
+        self.edge_handler = Some(handler);
+    }
+
+    /// Hands the mux an enabled hardware watchdog to feed after every sweep. If polling stops (a stalled or dead poll
+    /// task) the watchdog times out and resets the chip, instead of leaving the controller silent with notes hanging.
+    /// Its timeout must comfortably exceed a sweep: 16 settle times plus the callbacks.
+    pub fn set_watchdog(&mut self, watchdog: Wdt<TIMG1>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Saves power after `timeout` without any edge: sweeps are then `interval` apart (default 10ms) and the LEDs go dark
+    /// (see `is_idle`). Scanning never stops, so the press that ends the idle is read on the next sweep and reported as
+    /// usual. The tradeoff is that first press: it lands up to `interval` later, and a tap shorter than `interval` can be
+    /// missed entirely, so keep it well below a quick tap (~30ms). Pass None to never idle (default).
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>, interval: Duration) {
+        self.idle_timeout = timeout;
+        self.idle_interval = interval;
+    }
+
+    /// Waits between two sweeps if the mux has been idle for its idle timeout, and updates `is_idle`.
+    /// `poll_all` calls this after every sweep; a task looping `poll_once` should too.
+    pub async fn idle_wait(&mut self) {
+        let idle = self.idle_timeout.is_some_and(|timeout| self.last_edge.elapsed() >= timeout);
+        IDLE.store(idle, Ordering::Relaxed);
+        if idle {
+            Timer::after(self.idle_interval).await;
+        }
+    }
+
     pub fn add_chip(&mut self, chip: MuxChipConfig<'a>) { //Adds a chip to the multiplexer.
-        self.chips.push(chip).ok();
+        self.push_chip(chip, false);
+    }
+
+    /// Sets the select pins of a second bank of chips, see `add_chip_to_second_bank`. Costs 3 more GPIOs.
+    pub fn set_second_bank(&mut self, select: [Output<'a>; 3]) {
+        self.select_b = Some(select);
+    }
+
+    /// Adds a chip wired to the second bank's select pins. It's read in the same step as the first bank's chips.
+    pub fn add_chip_to_second_bank(&mut self, chip: MuxChipConfig<'a>) {
+        self.push_chip(chip, true);
+    }
+
+    // Adds a chip to a bank. Returns false if that bank already has 8 chips.
+    fn push_chip(&mut self, chip: MuxChipConfig<'a>, bank_b: bool) -> bool {
+        let on_bank = self.bank_b.count_ones() as usize;
+        let on_bank = if bank_b { on_bank } else { self.chips.len() - on_bank };
+        if on_bank >= 8 || self.chips.push(chip).is_err() {
+            return false;
+        }
+        if bank_b {
+            self.bank_b |= 1 << (self.chips.len() - 1);
+        }
+        true
+    }
+
+    /// Returns the debounced state of every channel, indexed as `channel + 8 * chip`.
+    pub fn channel_states(&self) -> &[SwitchState] {
+        self.debouncer.states()
+    }
+
+    /// Returns true if the debounced state of the channel is low (pressed). Out of range indices read as not pressed.
+    pub fn is_pressed(&self, index: usize) -> bool {
+        self.debouncer.is_pressed(index)
+    }
+
+    /// Clears `buf` and fills it with the indices of all currently pressed channels. Stops early if `buf` is full.
+    pub fn pressed_indices<const N: usize>(&self, buf: &mut Vec<usize, N>) {
+        self.debouncer.pressed_indices(buf);
     }
 
-    fn set_channel(&mut self, channel: u8) { //Sets the channel on the 4051.
-        let bits = [(channel >> 0) & 1, (channel >> 1) & 1, (channel >> 2) & 1];
-        for (pin, &bit) in self.select.iter_mut().zip(&bits) {
+    fn set_channel(&mut self, channel: u8) { //Sets the channel on the 4051s of both banks.
+        let bits = [channel & 1, (channel >> 1) & 1, (channel >> 2) & 1];
+        let bank_b = self.select_b.iter_mut().flatten();
+        for (pin, &bit) in self.select.iter_mut().chain(bank_b).zip(bits.iter().cycle()) {
             if bit == 0 {
-                let _ = pin.set_low();
+                pin.set_low();
             } else {
-                let _ = pin.set_high();
+                pin.set_high();
             }
         }
     }
 
-    /// Debounced polling for a single multiplexer channel using a time-based debounce.
+    /// Debounced polling for a single multiplexer channel. The algorithm is chosen by `debounce_mode`.
     ///
-    /// - `reading`: the raw reading from the chip’s common pin (true if low, i.e. pressed).
+    /// - `reading`: the raw reading from the chip’s common pin (true if pressed).
     /// - `read_channel`: the multiplexer channel (0..7).
     /// - `chip_offset`: which chip (in our chips Vec) is being read.
+    ///
+    /// Channels past `CH` are ignored rather than indexed.
     fn poll_digital_input_chip(
         &mut self,
         reading: bool,
         read_channel: usize,
         chip_offset: u8,
     ) {
-        let index = read_channel + (8 * chip_offset as usize);
-        let current_state = self.digital_in[index];
-        // Map the raw reading into our stable state.
-        // (true means the input is low/pressed → Low state;
-        //  false means not pressed → High state)
-        let expected_state = if reading { SwitchState::Low } else { SwitchState::High };
+        let Some(index) = scan::channel_index(read_channel, chip_offset, CH) else {
+            return; // Past the per-channel arrays, see `scan::channel_index`.
+        };
+        if self.disabled & (1 << index) != 0 {
+            return; // Disabled, see `set_channel_enabled`.
+        }
+        // Normally-closed channels read the other way round.
+        let inverted = self.inverted & (1 << index) != 0;
+        let reading = reading != inverted;
+        // Encoder contacts are decoded at the end of the sweep instead of debounced, see `add_encoder`.
+        let mut contact = false;
+        for encoder in self.encoders.iter_mut() {
+            for (channel, bit) in [(encoder.a, 0b10), (encoder.b, 0b01)] {
+                if channel as usize == index {
+                    encoder.contacts = if reading { encoder.contacts | bit } else { encoder.contacts & !bit };
+                    contact = true;
+                }
+            }
+        }
+        if contact {
+            return;
+        }
+        let edge = self.debouncer.update(index, reading);
+        if edge.is_some() {
+            self.last_edge = Instant::now();
+            for encoder in self.encoders.iter_mut().filter(|encoder| encoder.push == Some(index as u8)) {
+                encoder.guard_until = self.last_edge + ENCODER_PUSH_GUARD;
+            }
+        }
+        match edge {
+            Some(SwitchState::Low) => {
+                if let Some(handler) = self.edge_handler.as_mut() {
+                    handler.falling(index);
+                }
+                if let Some(callback) = self.falling_edge_callback {
+                    callback(index);
+                }
+                publish_edge(index, Edge::Falling);
+            }
+            Some(SwitchState::High) => {
+                if let Some(handler) = self.edge_handler.as_mut() {
+                    handler.rising(index);
+                }
+                if let Some(callback) = self.rising_edge_callback {
+                    callback(index);
+                }
+                publish_edge(index, Edge::Rising);
+            }
+            None => {}
+        }
+    }
 
-        let now = Instant::now();
-        if current_state != expected_state {
-            // Only accept the change if the debounce interval has elapsed.
-            if now.duration_since(self.last_change[index]) >= self.debounce_interval {
-                self.digital_in[index] = expected_state;
-                self.last_change[index] = now;
-                if expected_state == SwitchState::Low {
-                    if let Some(callback) = self.falling_edge_callback {
-                        callback(index);
+    /// How many state changes the debounce rejected on each channel since startup or the last reset. A high count points
+    /// at a noisy switch or too short a debounce interval. Read it from a `request_reconfig` function. Needs the
+    /// "bounce-stats" feature.
+    #[cfg(feature = "bounce-stats")]
+    pub fn bounce_stats(&self) -> &[u32; CH] {
+        self.debouncer.bounce_stats()
+    }
+
+    /// Clears the bounce counters.
+    #[cfg(feature = "bounce-stats")]
+    pub fn reset_bounce_stats(&mut self) {
+        self.debouncer.reset_bounce_stats();
+    }
+
+    /// Sets an output channel, indexed as `channel + 8 * output chip` (output chips are counted separately from inputs).
+    /// The level is driven during the next sweeps. Out of range indices are ignored.
+    pub fn set_output(&mut self, index: usize, on: bool) {
+        let mut output_chip = 0;
+        for chip in self.chips.iter_mut() {
+            if let MuxChipConfig::DigitalOutput { states, .. } = chip {
+                if output_chip == index / 8 {
+                    if let Some(state) = states.get_mut(index % 8) {
+                        *state = on;
                     }
+                    return;
+                }
+                output_chip += 1;
+            }
+        }
+    }
+
+    /// Makes an output channel mirror an input channel: it's lit while the input is pressed, e.g. an LED behind each key.
+    /// Both are indexed as `channel + 8 * chip` within their own kind of chip. Pass None to stop mirroring the input.
+    pub fn set_output_mirror(&mut self, input: usize, output: Option<usize>) {
+        if let Some(entry) = self.output_mirror.get_mut(input) {
+            *entry = output.map(|output| output as u8);
+        }
+    }
+
+    /// Drives the output chips only every `divider` sweeps (1, the default, is every sweep) and leaves them dark in
+    /// between, so a fast input scan doesn't also switch the outputs at that rate. Outputs are multiplexed, each lit
+    /// only while its channel is selected, so this also divides their brightness by `divider`, PWM-dimmed LEDs on
+    /// the output chips included, and a high divider with a slow scan can flicker: keep the refresh rate (sweeps per
+    /// second / divider) above about 100Hz.
+    pub fn set_output_refresh_divider(&mut self, divider: u8) {
+        self.output_refresh_divider = divider.max(1);
+        self.sweeps_to_refresh = 0;
+    }
+
+    // Copies the mirrored input states onto their output channels.
+    fn apply_output_mirror(&mut self) {
+        for input in 0..self.output_mirror.len() {
+            if let Some(output) = self.output_mirror[input] {
+                let pressed = self.is_pressed(input);
+                self.set_output(output as usize, pressed);
+            }
+        }
+    }
+
+    // Drives every output chip's common pin for the selected channel, or low for all of them while switching channels.
+    fn drive_outputs(&mut self, channel: Option<usize>) {
+        for chip in self.chips.iter_mut() {
+            if let MuxChipConfig::DigitalOutput { common, states } = chip {
+                let on = channel.is_some_and(|channel| states[channel]);
+                if on {
+                    common.set_high();
                 } else {
-                    if let Some(callback) = self.rising_edge_callback {
-                        callback(index);
+                    common.set_low();
+                }
+            }
+        }
+    }
+
+    /// Stores an analog reading and passes it on to the analog callback.
+    ///
+    /// - `value`: the raw reading from the chip's common pin.
+    /// - `read_channel`: the multiplexer channel (0..7).
+    /// - `chip_offset`: which analog chip is being read. Analog chips are counted separately from digital ones.
+    fn poll_analog_input_chip(&mut self, value: u16, read_channel: usize, chip_offset: u8) {
+        let Some(index) = scan::channel_index(read_channel, chip_offset, CH) else {
+            return;
+        };
+        let value = self.smooth_analog(index, value);
+        self.analog_in[index] = value;
+        if let Some(handler) = self.edge_handler.as_mut() {
+            handler.analog(index, value);
+        }
+        if let Some(callback) = self.analog_callback {
+            callback(index, value);
+        }
+    }
+
+    // Runs one analog reading through the channel's moving average.
+    fn smooth_analog(&mut self, index: usize, value: u16) -> u16 {
+        if self.analog_smoothing == 0 {
+            return value;
+        }
+        let target = (value as u32) << 4;
+        let filtered = match self.analog_filter[index] {
+            None => target, // Start from the first reading instead of ramping up from 0.
+            Some(previous) => {
+                let step = (target.abs_diff(previous) >> self.analog_smoothing).max(1);
+                if target > previous {
+                    (previous + step).min(target)
+                } else {
+                    previous.saturating_sub(step).max(target)
+                }
+            }
+        };
+        self.analog_filter[index] = Some(filtered);
+        // Round to the nearest whole reading.
+        ((filtered + 8) >> 4) as u16
+    }
+
+    /// Polls every channel on every input chip once, and drives every output chip's channels in turn.
+    /// Inputs and outputs share the select lines, so each output channel is driven while its channel is selected.
+    pub async fn poll_once(&mut self) {
+        let refresh_outputs = self.sweeps_to_refresh == 0;
+        self.sweeps_to_refresh = match self.sweeps_to_refresh {
+            0 => self.output_refresh_divider - 1,
+            left => left - 1,
+        };
+        if refresh_outputs {
+            self.apply_output_mirror();
+        } else {
+            self.drive_outputs(None); // Dark until the next refresh, see `set_output_refresh_divider`.
+        }
+        for step in 0..self.scan_order.len() {
+            let channel = self.scan_order[step];
+            let read_channel = channel as usize;
+            if self.channel_unused(read_channel) {
+                continue;
+            }
+            if refresh_outputs {
+                self.drive_outputs(None); // Blank outputs while the channel changes so no LED ghosts onto its neighbour.
+            }
+            self.set_channel(channel);
+            if refresh_outputs {
+                self.drive_outputs(Some(read_channel));
+            }
+            Timer::after(self.settle_time).await; // Wait for the channel to change in the multiplexing IC.
+            // Each chip is read and debounced in turn, no readings are collected first.
+            let (mut digital_chip, mut analog_chip) = (0, 0);
+            for chip_index in 0..self.chips.len() {
+                match &mut self.chips[chip_index] {
+                    MuxChipConfig::DigitalInput { common, active_low, .. } => {
+                        // With Pull-Up inputs, a pressed button pulls the pin low. With pull-downs it pulls it high.
+                        let state = common.is_low() == *active_low;
+                        self.poll_digital_input_chip(state, read_channel, digital_chip);
+                        digital_chip += 1;
+                    }
+                    MuxChipConfig::AnalogInput { common, oversample } => {
+                        // The channel has settled once above; the extra samples are taken back to back.
+                        let samples = (*oversample).max(1) as u32;
+                        let sum: u32 = (0..samples).map(|_| common.read() as u32).sum();
+                        self.poll_analog_input_chip((sum / samples) as u16, read_channel, analog_chip);
+                        analog_chip += 1;
                     }
+                    MuxChipConfig::DigitalOutput { .. } => {}
                 }
             }
         }
+        self.report_rotation();
+        let pressed = (0..CH)
+            .filter(|&index| self.debouncer.is_pressed(index))
+            .fold(0, |pressed, index| pressed | 1 << index);
+        SWEEP_COMPLETE.signal(Sweep { pressed, at: Instant::now() });
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.feed();
+        }
     }
 
-    /// Continuously polls all channels on all chips. Currently only checks chips set to digital input.
+    // Decodes every encoder's contacts read this sweep and reports those that turned.
+    fn report_rotation(&mut self) {
+        let now = Instant::now();
+        for encoder in 0..self.encoders.len() {
+            let delta = self.encoders[encoder].update(now);
+            if delta == 0 {
+                continue;
+            }
+            self.last_edge = now;
+            if let Some(handler) = self.edge_handler.as_mut() {
+                handler.rotation(encoder, delta);
+            }
+            if let Some(callback) = self.rotation_callback {
+                callback(encoder, delta);
+            }
+        }
+    }
+
+    /// Power-on self-test. Runs a few sweeps with the callbacks disabled and returns every channel that already reads as pressed.
+    /// Those channels are flagged as stuck: they fire no callbacks until they have been released once, so a shorted or
+    /// miswired channel can't spam note-ons. Call this after adding chips and before spawning the poll task.
+    pub async fn run_self_test(&mut self) -> Vec<usize, CH> {
+        let falling = self.falling_edge_callback.take();
+        let rising = self.rising_edge_callback.take();
+        let rotation = self.rotation_callback.take();
+        let handler = self.edge_handler.take();
+        for _ in 0..self.debouncer.settle_sweeps() {
+            self.poll_once().await;
+        }
+        self.falling_edge_callback = falling;
+        self.rising_edge_callback = rising;
+        self.rotation_callback = rotation;
+        self.edge_handler = handler;
+
+        let mut stuck: Vec<usize, CH> = Vec::new();
+        self.pressed_indices(&mut stuck);
+        for &index in stuck.iter() {
+            self.debouncer.mark_stuck(index);
+        }
+        stuck
+    }
+}
+
+// The reconfiguration queue holds functions for one mux type, the default 64 channel one. A mux with a smaller
+// channel count has no `poll_all`: loop `poll_once` in its task instead.
+impl Multiplexer4051<'_> {
+    /// Continuously polls all channels on all chips. Reconfigurations from `request_reconfig` are applied between sweeps.
     pub async fn poll_all(&mut self) {
         loop {
-            for channel in 0..8 {
-                let read_channel = channel as usize;
-                self.set_channel(channel);
-                Timer::after_micros(50).await; // Wait for the channel to change in the multiplexing IC.
-                let mut common_states: Vec<bool, 8> = Vec::new();
-                for chip in self.chips.iter_mut() {
-                    if let MuxChipConfig::DigitalInput { common, .. } = chip {
-                        // With Pull-Up inputs, a pressed button pulls the pin low.
-                        common_states.push(common.is_low()).ok();
-                    }
-                }
-                for (chip_index, &state) in common_states.iter().enumerate() {
-                    self.poll_digital_input_chip(state, read_channel, chip_index as u8);
-                }
+            while let Ok(reconfig) = RECONFIG.try_receive() {
+                reconfig(self);
             }
+            self.poll_once().await;
+            self.idle_wait().await;
         }
     }
 }
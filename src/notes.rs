@@ -0,0 +1,161 @@
+// Small no-std helpers for converting between MIDI note numbers, note names and frequencies.
+// Note numbering follows the common convention where middle C (60) is "C4" and A4 (69) is 440Hz.
+// Names and frequencies are only shown on the display, so they are only built with it (and for the tests).
+
+// Name of every MIDI note, indexed by note number.
+#[cfg(any(feature = "display", test))]
+const NOTE_NAMES: [&str; 128] = [
+    "C-1", "C#-1", "D-1", "D#-1", "E-1", "F-1", "F#-1", "G-1", "G#-1", "A-1", "A#-1", "B-1",
+    "C0", "C#0", "D0", "D#0", "E0", "F0", "F#0", "G0", "G#0", "A0", "A#0", "B0",
+    "C1", "C#1", "D1", "D#1", "E1", "F1", "F#1", "G1", "G#1", "A1", "A#1", "B1",
+    "C2", "C#2", "D2", "D#2", "E2", "F2", "F#2", "G2", "G#2", "A2", "A#2", "B2",
+    "C3", "C#3", "D3", "D#3", "E3", "F3", "F#3", "G3", "G#3", "A3", "A#3", "B3",
+    "C4", "C#4", "D4", "D#4", "E4", "F4", "F#4", "G4", "G#4", "A4", "A#4", "B4",
+    "C5", "C#5", "D5", "D#5", "E5", "F5", "F#5", "G5", "G#5", "A5", "A#5", "B5",
+    "C6", "C#6", "D6", "D#6", "E6", "F6", "F#6", "G6", "G#6", "A6", "A#6", "B6",
+    "C7", "C#7", "D7", "D#7", "E7", "F7", "F#7", "G7", "G#7", "A7", "A#7", "B7",
+    "C8", "C#8", "D8", "D#8", "E8", "F8", "F#8", "G8", "G#8", "A8", "A#8", "B8",
+    "C9", "C#9", "D9", "D#9", "E9", "F9", "F#9", "G9",
+];
+
+// Frequencies (Hz) of the twelve semitones from A4 up to G#5. Other octaves are found by doubling or halving.
+#[cfg(any(feature = "display", test))]
+const A4_OCTAVE_FREQUENCIES: [f32; 12] = [
+    440.0, // A
+    466.1638, // A#
+    493.8833, // B
+    523.2511, // C
+    554.3653, // C#
+    587.3295, // D
+    622.254, // D#
+    659.2551, // E
+    698.4565, // F
+    739.9888, // F#
+    783.9909, // G
+    830.6094, // G#
+];
+
+#[cfg(any(feature = "display", test))]
+const A4: i32 = 69;
+
+/// Returns the name of a MIDI note, e.g. 60 -> "C4". Notes above 127 return "?".
+#[cfg(any(feature = "display", test))]
+pub fn note_name(note: u8) -> &'static str {
+    NOTE_NAMES.get(note as usize).copied().unwrap_or("?")
+}
+
+/// Returns the equal-tempered frequency of a MIDI note in Hz, tuned to A4 = 440Hz.
+#[cfg(any(feature = "display", test))]
+pub fn note_frequency(note: u8) -> f32 {
+    let offset = note as i32 - A4;
+    let mut frequency = A4_OCTAVE_FREQUENCIES[offset.rem_euclid(12) as usize];
+    let octaves = offset.div_euclid(12);
+    for _ in 0..octaves.abs() {
+        if octaves > 0 {
+            frequency *= 2.0;
+        } else {
+            frequency /= 2.0;
+        }
+    }
+    frequency
+}
+
+/// Parses a note name such as "C4", "F#3", "Bb-1" or "c#4" into a MIDI note number. A const fn, so constants can be
+/// written as note names. Returns None if the name is malformed or the note falls outside 0..=127.
+pub const fn note_for_name(name: &str) -> Option<u8> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+    let semitone: i32 = match bytes[0].to_ascii_uppercase() {
+        b'C' => 0,
+        b'D' => 2,
+        b'E' => 4,
+        b'F' => 5,
+        b'G' => 7,
+        b'A' => 9,
+        b'B' => 11,
+        _ => return None,
+    };
+    let mut rest = 1;
+    let mut accidental = 0;
+    if rest < bytes.len() && bytes[rest] == b'#' {
+        accidental = 1;
+        rest += 1;
+    } else if rest < bytes.len() && bytes[rest] == b'b' {
+        accidental = -1;
+        rest += 1;
+    }
+    let negative = rest < bytes.len() && bytes[rest] == b'-';
+    if negative {
+        rest += 1;
+    }
+    if rest == bytes.len() {
+        return None; // No octave.
+    }
+    let mut octave: i32 = 0;
+    while rest < bytes.len() {
+        if !bytes[rest].is_ascii_digit() || octave > 10 {
+            return None;
+        }
+        octave = octave * 10 + (bytes[rest] - b'0') as i32;
+        rest += 1;
+    }
+    if negative {
+        octave = -octave;
+    }
+    let note = (octave + 1) * 12 + semitone + accidental;
+    if note >= 0 && note <= 127 {
+        Some(note as u8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a4_is_440hz() {
+        assert_eq!(note_for_name("A4"), Some(69));
+        assert_eq!(note_frequency(69), 440.0);
+        assert_eq!(note_frequency(57), 220.0);
+        assert_eq!(note_frequency(81), 880.0);
+    }
+
+    #[test]
+    fn frequencies_follow_equal_temperament() {
+        assert!((note_frequency(60) - 261.6256).abs() < 0.001); // Middle C.
+        assert!((note_frequency(0) - 8.175799).abs() < 0.0001);
+        assert!((note_frequency(127) - 12543.85).abs() < 0.01);
+        for note in 1..=127u8 {
+            let ratio = note_frequency(note) / note_frequency(note - 1);
+            assert!((ratio - 1.059463).abs() < 0.0001, "note {}", note);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(128), "?");
+        for note in 0..=127u8 {
+            assert_eq!(note_for_name(note_name(note)), Some(note));
+        }
+    }
+
+    #[test]
+    fn parses_flats_lowercase_and_negative_octaves() {
+        assert_eq!(note_for_name("Bb3"), note_for_name("A#3"));
+        assert_eq!(note_for_name("c#4"), Some(61));
+        assert_eq!(note_for_name("C-1"), Some(0));
+        assert_eq!(note_for_name("G9"), Some(127));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for name in ["", "H4", "C", "C#", "C-", "C4x", "G#9", "Cb-1", "C99999999999"] {
+            assert_eq!(note_for_name(name), None, "{}", name);
+        }
+    }
+}
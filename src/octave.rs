@@ -0,0 +1,72 @@
+// The octave range, what the octave buttons do at its edges and the note a key plays at an octave, kept apart from the
+// global state so it can be tested on the host. The octave only ever moves within min_octave..=max_octave; a shift
+// past a limit is handled by the `OctavePolicy`.
+
+/// What the octave buttons do at the edge of the configured range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OctavePolicy {
+    Clamp, // Stay at the limit.
+    Wrap,  // Jump to the opposite limit.
+}
+
+/// The octave `delta` octaves from `octave`, kept within `min..=max` by `policy`.
+pub fn shifted(octave: i32, delta: i32, min: i32, max: i32, policy: OctavePolicy) -> i32 {
+    let next = octave + delta;
+    if next > max {
+        match policy {
+            OctavePolicy::Clamp => max,
+            OctavePolicy::Wrap => min,
+        }
+    } else if next < min {
+        match policy {
+            OctavePolicy::Clamp => min,
+            OctavePolicy::Wrap => max,
+        }
+    } else {
+        next
+    }
+}
+
+/// The chromatic note of a key `semitone` semitones up the keybed at `octave`, shifted by `transpose` semitones.
+pub fn chromatic_note(semitone: i32, octave: i32, transpose: i32) -> i32 {
+    semitone + octave * 12 + transpose
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inside_the_range_both_policies_shift() {
+        for policy in [OctavePolicy::Clamp, OctavePolicy::Wrap] {
+            assert_eq!(shifted(4, 1, 0, 8, policy), 5);
+            assert_eq!(shifted(4, -1, 0, 8, policy), 3);
+            assert_eq!(shifted(7, 1, 0, 8, policy), 8);
+            assert_eq!(shifted(1, -1, 0, 8, policy), 0);
+        }
+    }
+
+    #[test]
+    fn clamp_stays_at_both_limits() {
+        assert_eq!(shifted(8, 1, 0, 8, OctavePolicy::Clamp), 8);
+        assert_eq!(shifted(0, -1, 0, 8, OctavePolicy::Clamp), 0);
+        assert_eq!(shifted(6, 1, 2, 6, OctavePolicy::Clamp), 6);
+        assert_eq!(shifted(2, -1, 2, 6, OctavePolicy::Clamp), 2);
+    }
+
+    #[test]
+    fn wrap_jumps_to_the_opposite_limit() {
+        assert_eq!(shifted(8, 1, 0, 8, OctavePolicy::Wrap), 0);
+        assert_eq!(shifted(0, -1, 0, 8, OctavePolicy::Wrap), 8);
+        assert_eq!(shifted(6, 1, 2, 6, OctavePolicy::Wrap), 2);
+        assert_eq!(shifted(2, -1, 2, 6, OctavePolicy::Wrap), 6);
+    }
+
+    #[test]
+    fn single_octave_range_never_moves() {
+        for policy in [OctavePolicy::Clamp, OctavePolicy::Wrap] {
+            assert_eq!(shifted(3, 1, 3, 3, policy), 3);
+            assert_eq!(shifted(3, -1, 3, 3, policy), 3);
+        }
+    }
+}
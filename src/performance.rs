@@ -0,0 +1,171 @@
+// Performance macros: short MIDI sequences stored in flash and played back by a macro key, e.g. a song start stinger or
+// a scene change (program changes on two channels, then a few CCs). Each step waits its delay after the step before
+// it, then sends its message. The player only decides what is sent when; the caller turns each message into a queued
+// event.
+//
+// Storage limits: a macro is a `&'static [MacroStep]` in flash, so macros cost no RAM and have no length limit of
+// their own. At run time `MAX_PLAYING` macros play at once (pressing another macro key while that many play does
+// nothing), and the player remembers up to `MAX_SOUNDING` notes per macro to stop when it is cancelled; notes past
+// that keep sounding until the macro's own note-off.
+//
+// Timing: like the sequencer, every step is scheduled from the previous step's deadline, so late polls add no drift;
+// each step still goes out up to one poll interval (1ms) late. Steps that come due in the same poll are emitted in
+// order, but note events have queues of their own, which the main loop sends before other messages: give a note a delay
+// of at least 1ms after the program change it needs.
+//
+// Example, polled every millisecond:
+//    const STINGER: performance::Macro = &[
+//        MacroStep { delay: Duration::from_millis(0), message: MidiMessage::ProgramChange(Channel::C1, 5.into()) },
+//        MacroStep { delay: Duration::from_millis(5), message: MidiMessage::NoteOn(Channel::C1, 60.into(), 100.into()) },
+//        MacroStep { delay: Duration::from_millis(500), message: MidiMessage::NoteOff(Channel::C1, 60.into(), 0.into()) },
+//    ];
+//    let mut player = performance::MacroPlayer::new();
+//    player.press(0, STINGER, Instant::now(), |message| { /* a note-off, only when cancelled */ });
+//    player.poll(Instant::now(), |message| { /* queue the message */ });
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use midi_convert::midi_types::{Channel, MidiMessage, Note, Value7};
+
+/// Macros playing at once.
+pub const MAX_PLAYING: usize = 4;
+
+/// Notes per playing macro stopped when it is cancelled.
+pub const MAX_SOUNDING: usize = 8;
+
+/// One message of a macro, sent `delay` after the step before it (after the key press for the first step).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacroStep {
+    pub delay: Duration,
+    pub message: MidiMessage,
+}
+
+/// A stored sequence, see the top of this file.
+pub type Macro = &'static [MacroStep];
+
+#[derive(Debug, Clone)]
+struct Playback {
+    id: u8,
+    steps: Macro,
+    next: usize,      // The step sent next.
+    next_at: Instant, // When it is due.
+    sounding: Vec<(Channel, Note), MAX_SOUNDING>,
+}
+
+/// The macros playing.
+#[derive(Debug, Clone)]
+pub struct MacroPlayer {
+    playing: Vec<Playback, MAX_PLAYING>,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> Self {
+        Self { playing: Vec::new() }
+    }
+
+    /// A macro key press: starts macro `id` playing `steps`, or cancels it if it is still playing. Cancelling emits a
+    /// note-off for every note it started and hasn't stopped yet.
+    pub fn press(&mut self, id: u8, steps: Macro, now: Instant, mut emit: impl FnMut(MidiMessage)) {
+        if let Some(position) = self.playing.iter().position(|playback| playback.id == id) {
+            let playback = self.playing.swap_remove(position);
+            for (channel, note) in playback.sounding {
+                emit(MidiMessage::NoteOff(channel, note, Value7::new(0)));
+            }
+            return;
+        }
+        let Some(first) = steps.first() else {
+            return;
+        };
+        let playback = Playback { id, steps, next: 0, next_at: now + first.delay, sounding: Vec::new() };
+        self.playing.push(playback).ok(); // Full: ignored, see the top of this file.
+    }
+
+    /// Emits every step that is due, in order, and forgets the macros that finished.
+    pub fn poll(&mut self, now: Instant, mut emit: impl FnMut(MidiMessage)) {
+        for playback in self.playing.iter_mut() {
+            while let Some(step) = playback.steps.get(playback.next) {
+                if now < playback.next_at {
+                    break;
+                }
+                match step.message {
+                    MidiMessage::NoteOn(channel, note, velocity) if u8::from(velocity) > 0 => {
+                        playback.sounding.push((channel, note)).ok();
+                    }
+                    MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                        playback.sounding.retain(|&sounding| sounding != (channel, note));
+                    }
+                    _ => {}
+                }
+                emit(step.message);
+                playback.next += 1;
+                if let Some(following) = playback.steps.get(playback.next) {
+                    playback.next_at += following.delay;
+                }
+            }
+        }
+        self.playing.retain(|playback| playback.next < playback.steps.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi_convert::midi_types::{Control, Program};
+
+    const fn step(delay: u64, message: MidiMessage) -> MacroStep {
+        MacroStep { delay: Duration::from_millis(delay), message }
+    }
+
+    const STINGER: Macro = &[
+        step(0, MidiMessage::ProgramChange(Channel::C1, Program::new(5))),
+        step(5, MidiMessage::NoteOn(Channel::C1, Note::new(60), Value7::new(100))),
+        step(5, MidiMessage::ControlChange(Channel::C1, Control::new(7), Value7::new(90))),
+        step(500, MidiMessage::NoteOff(Channel::C1, Note::new(60), Value7::new(0))),
+    ];
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    // Polls every millisecond from `from` to `to`, returning when each message went out.
+    fn play(player: &mut MacroPlayer, from: u64, to: u64) -> Vec<(u64, MidiMessage), 8> {
+        let mut sent = Vec::new();
+        for millis in from..=to {
+            player.poll(at(millis), |message| sent.push((millis, message)).unwrap());
+        }
+        sent
+    }
+
+    #[test]
+    fn steps_go_out_in_order_after_their_delays() {
+        let mut player = MacroPlayer::new();
+        player.press(0, STINGER, at(100), |_| panic!("nothing to cancel"));
+        let sent = play(&mut player, 100, 700);
+        let times: Vec<u64, 8> = sent.iter().map(|&(millis, _)| millis).collect();
+        assert_eq!(times.as_slice(), &[100, 105, 110, 610]);
+        assert!(sent.iter().zip(STINGER).all(|(&(_, message), step)| message == step.message));
+        assert!(play(&mut player, 701, 1500).is_empty(), "finished");
+    }
+
+    #[test]
+    fn a_late_poll_catches_up_without_drift() {
+        let mut player = MacroPlayer::new();
+        player.press(0, STINGER, at(0), |_| {});
+        let sent = play(&mut player, 12, 12);
+        assert_eq!(sent.len(), 3, "every step due is sent, in order");
+        assert!(sent.iter().zip(STINGER).all(|(&(_, message), step)| message == step.message));
+        // The last step is due 500ms after the third step was, not after the late poll that sent it.
+        assert_eq!(play(&mut player, 13, 600).as_slice(), &[(510, STINGER[3].message)]);
+    }
+
+    #[test]
+    fn cancelling_stops_the_notes_it_started() {
+        let mut player = MacroPlayer::new();
+        player.press(0, STINGER, at(0), |_| {});
+        play(&mut player, 0, 20);
+        let mut stopped: Vec<MidiMessage, 8> = Vec::new();
+        player.press(0, STINGER, at(21), |message| stopped.push(message).unwrap());
+        assert_eq!(stopped.as_slice(), &[MidiMessage::NoteOff(Channel::C1, Note::new(60), Value7::new(0))]);
+        assert!(play(&mut player, 22, 1000).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+// The note event queues between the key callbacks and the main loop, kept apart from their statics so the ordering can
+// be tested on the host. Every event gets a sequence number when it is queued, and the main loop sends both queues in
+// that order: a note-off never goes out before the note-on it ends, even when a send fails and has to be retried.
+// Note-offs are never dropped, a lost note-off is a stuck note, see `push_off`.
+
+#[cfg(feature = "defmt")]
+use embassy_time::Instant;
+use heapless::Vec;
+use midi_convert::midi_types::Channel;
+use usbd_midi::CableNumber;
+
+/// A queued note event, sent to the MIDI device in the main loop.
+/// "seq" orders events across both queues and "at" is when the key edge was seen, for debugging dropped or late events
+/// (press-to-send latency is `Instant::now() - at`, logged with the "defmt" feature, which is the only build that has
+/// it). At 24 bytes per event each 128 entry queue costs about 3KB of RAM.
+#[derive(Debug, Clone, Copy)]
+pub struct NoteEvent {
+    pub note: i32,
+    pub velocity: u8,
+    pub channel: Channel,
+    pub cable: CableNumber,
+    pub seq: u32,
+    #[cfg(feature = "defmt")]
+    pub at: Instant,
+}
+
+impl NoteEvent {
+    /// An event for a key edge seen now.
+    pub fn new(note: i32, velocity: u8, channel: Channel, cable: CableNumber, seq: u32) -> Self {
+        Self {
+            note,
+            velocity,
+            channel,
+            cable,
+            seq,
+            #[cfg(feature = "defmt")]
+            at: Instant::now(),
+        }
+    }
+
+    fn same_note(&self, other: &NoteEvent) -> bool {
+        self.note == other.note && self.channel == other.channel && self.cable == other.cable
+    }
+}
+
+/// The note-ons or note-offs waiting to be sent.
+pub type Events = Vec<NoteEvent, 128>;
+
+/// True if `seq` was handed out after `other`. Sequence numbers wrap, so this holds for events queued less than 2^31
+/// events apart.
+pub fn is_after(seq: u32, other: u32) -> bool {
+    seq.wrapping_sub(other) as i32 > 0
+}
+
+/// Puts an event that failed to send back in `events`, in sequence order so it goes out before anything newer. Hands
+/// the event back if the queue is full.
+pub fn insert_in_order(events: &mut Events, event: NoteEvent) -> Result<(), NoteEvent> {
+    let position = events.iter().position(|queued| is_after(queued.seq, event.seq)).unwrap_or(events.len());
+    events.insert(position, event)
+}
+
+/// True if `note_off` has to wait for a note-on that didn't go out: `first_unsent_on` is the oldest note-on still
+/// queued, if any. Sending the note-off first would leave its note sounding once the note-on is retried.
+pub fn waits_for_note_on(note_off: &NoteEvent, first_unsent_on: Option<u32>) -> bool {
+    first_unsent_on.is_some_and(|seq| is_after(note_off.seq, seq))
+}
+
+/// Queues a note-off without ever dropping it. When `off_events` is full:
+/// - a matching note-on still waiting in `on_events` is removed instead, so the note never sounds, or else
+/// - the note-off goes into `overflow`, one bit per note, per channel, per cable, and is sent before the next note-ons.
+///
+/// Note-ons are simply dropped when their queue is full; their note-off then turns off a note that isn't playing.
+pub fn push_off(off_events: &mut Events, on_events: &mut Events, overflow: &mut [[u128; 16]], event: NoteEvent) {
+    let Err(event) = off_events.push(event) else {
+        return;
+    };
+    if let Some(position) = on_events.iter().rposition(|on| on.same_note(&event)) {
+        on_events.remove(position);
+        return;
+    }
+    let channel = u8::from(event.channel) as usize;
+    if let Some(notes) = overflow.get_mut(u8::from(event.cable) as usize) {
+        notes[channel] |= 1 << (event.note as u32 & 127);
+    }
+}
+
+/// Removes every queued note-off that has a later note-on for the same note, channel and cable queued behind it, along
+/// with that note-on, so a bouncing or re-struck key doesn't retrigger a note that is still sounding. Returns the number
+/// of pairs removed. A note-on followed by its note-off (a quick tap) is left alone.
+///
+/// The tradeoff: a deliberate fast repeat of the same note within one pass (about 1ms) is swallowed, and the note keeps
+/// the velocity of its first strike.
+pub fn coalesce_retriggers(on_events: &mut Events, off_events: &mut Events) -> usize {
+    let mut removed = 0;
+    let mut off_index = 0;
+    while off_index < off_events.len() {
+        let off = off_events[off_index];
+        let retrigger = on_events.iter().position(|on| on.same_note(&off) && is_after(on.seq, off.seq));
+        match retrigger {
+            Some(on_index) => {
+                on_events.remove(on_index);
+                off_events.remove(off_index);
+                removed += 1;
+            }
+            None => off_index += 1,
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(note: i32, channel: u8, seq: u32) -> NoteEvent {
+        NoteEvent::new(note, 64, Channel::from(channel), CableNumber::Cable0, seq)
+    }
+
+    fn seqs(events: &Events) -> Vec<u32, 128> {
+        events.iter().map(|event| event.seq).collect()
+    }
+
+    #[test]
+    fn requeued_events_go_back_in_sequence_order() {
+        let mut events = Events::new();
+        for seq in [2, 5, 7] {
+            events.push(event(60, 0, seq)).unwrap();
+        }
+        insert_in_order(&mut events, event(60, 0, 4)).unwrap();
+        insert_in_order(&mut events, event(60, 0, 1)).unwrap();
+        insert_in_order(&mut events, event(60, 0, 9)).unwrap();
+        assert_eq!(seqs(&events), [1, 2, 4, 5, 7, 9]);
+        // Across the wrap, u32::MAX comes before 0.
+        let mut events = Events::new();
+        events.push(event(60, 0, 0)).unwrap();
+        insert_in_order(&mut events, event(60, 0, u32::MAX)).unwrap();
+        assert_eq!(seqs(&events), [u32::MAX, 0]);
+    }
+
+    #[test]
+    fn a_full_queue_hands_the_event_back() {
+        let mut events = Events::new();
+        for seq in 0..128 {
+            events.push(event(60, 0, seq)).unwrap();
+        }
+        assert_eq!(insert_in_order(&mut events, event(61, 0, 200)).unwrap_err().note, 61);
+    }
+
+    #[test]
+    fn note_offs_after_a_failed_note_on_wait() {
+        // The note-on (seq 3) failed and was requeued: its own note-off and anything later waits, earlier ones don't.
+        let first_unsent_on = Some(3);
+        assert!(waits_for_note_on(&event(60, 0, 4), first_unsent_on));
+        assert!(waits_for_note_on(&event(62, 0, 9), first_unsent_on));
+        assert!(!waits_for_note_on(&event(64, 0, 2), first_unsent_on));
+        assert!(!waits_for_note_on(&event(60, 0, 4), None));
+    }
+
+    #[test]
+    fn no_note_off_is_lost_past_the_queue() {
+        let (mut off_events, mut on_events) = (Events::new(), Events::new());
+        let mut overflow = [[0u128; 16]; 1];
+        // Note-ons still waiting for the last 10 notes, which go in after the queue is full.
+        for seq in 290..300 {
+            on_events.push(event(seq as i32 % 128, (seq / 128) as u8, seq)).unwrap();
+        }
+        for seq in 0..300 {
+            push_off(&mut off_events, &mut on_events, &mut overflow, event(seq as i32 % 128, (seq / 128) as u8, seq));
+        }
+        assert_eq!(off_events.len(), 128);
+        assert!(on_events.is_empty(), "the pending note-ons are cancelled instead");
+        let overflowed: u32 = overflow[0].iter().map(|notes| notes.count_ones()).sum();
+        assert_eq!(overflowed, 300 - 128 - 10);
+        // Every note-off is either queued, overflowed or cancelled its note-on.
+        for seq in 0..300u32 {
+            let (note, channel) = (seq % 128, (seq / 128) as usize);
+            let queued = off_events.iter().any(|off| off.seq == seq);
+            let overflowed = overflow[0][channel] & (1 << note) != 0;
+            assert!(queued || overflowed || seq >= 290, "note-off {seq} was lost");
+        }
+    }
+
+    #[test]
+    fn retriggers_are_coalesced() {
+        // A note-off with a note-on for the same note behind it: both go.
+        let mut on_events = Events::new();
+        let mut off_events = Events::new();
+        off_events.push(event(60, 0, 1)).unwrap();
+        on_events.push(event(60, 0, 2)).unwrap();
+        assert_eq!(coalesce_retriggers(&mut on_events, &mut off_events), 1);
+        assert!(on_events.is_empty() && off_events.is_empty());
+    }
+
+    #[test]
+    fn unrelated_events_pass_through() {
+        let mut on_events = Events::new();
+        let mut off_events = Events::new();
+        on_events.push(event(60, 0, 1)).unwrap(); // A quick tap: on, then off.
+        off_events.push(event(60, 0, 2)).unwrap();
+        off_events.push(event(62, 0, 3)).unwrap(); // Another note.
+        on_events.push(event(64, 0, 4)).unwrap();
+        off_events.push(event(65, 1, 5)).unwrap(); // The same note on another channel.
+        on_events.push(event(65, 2, 6)).unwrap();
+        assert_eq!(coalesce_retriggers(&mut on_events, &mut off_events), 0);
+        assert_eq!(seqs(&on_events), [1, 4, 6]);
+        assert_eq!(seqs(&off_events), [2, 3, 5]);
+    }
+}
@@ -0,0 +1,86 @@
+// Channel indexing and scan order of the mux sweep, kept apart from the pins so it can be tested on the host. A channel
+// is indexed as `channel + 8 * chip`, counting chips of one kind (digital inputs, analog inputs) in the order they were
+// added, on to the second bank. With 8 chips on each bank that's up to 128 indices, more than the per-channel arrays
+// hold: the sweep still selects every chip, but channels past their array are ignored.
+
+use heapless::Vec;
+
+pub const CHANNELS: usize = 64; //Channels across all chips (8 chips with 8 channels). The default size of every per-channel array.
+
+/// The index of `read_channel` (0..7) on the `chip_offset`th chip of its kind, or None past `channels`.
+pub fn channel_index(read_channel: usize, chip_offset: u8, channels: usize) -> Option<usize> {
+    let index = read_channel + 8 * chip_offset as usize;
+    (read_channel < 8 && index < channels).then_some(index)
+}
+
+/// A sweep order from a list of channels: entries above 7 and entries past 16 are dropped.
+pub fn scan_order(order: &[u8]) -> Vec<u8, 16> {
+    order.iter().copied().filter(|&channel| channel < 8).take(16).collect()
+}
+
+/// The sweep order that reads `channels` at the start and halfway through, every other channel once in between.
+pub fn priority_order(channels: &[u8]) -> Vec<u8, 16> {
+    let is_priority = |channel: &u8| channels.contains(channel);
+    let mut order: Vec<u8, 32> = Vec::new();
+    for half in [0..4, 4..8] {
+        let priority = channels.iter().copied().filter(|&channel| channel < 8);
+        for channel in priority.chain(half.filter(|channel| !is_priority(channel))) {
+            order.push(channel).ok();
+        }
+    }
+    scan_order(&order) // Anything past 16 reads is dropped.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sixteen_chips_on_two_banks() {
+        // 8 digital chips per bank, counted on from bank A into bank B, swept in the default order.
+        let mut seen = [0u8; CHANNELS];
+        let mut ignored = 0;
+        for channel in scan_order(&[0, 1, 2, 3, 4, 5, 6, 7]) {
+            for chip in 0..16 {
+                match channel_index(channel as usize, chip, CHANNELS) {
+                    Some(index) => {
+                        assert_eq!(index % 8, channel as usize);
+                        assert_eq!(index / 8, chip as usize);
+                        seen[index] += 1;
+                    }
+                    None => {
+                        assert!(chip >= 8, "chip {chip} channel {channel} has no index");
+                        ignored += 1;
+                    }
+                }
+            }
+        }
+        // Bank A fills every index once, bank B is past the arrays.
+        assert!(seen.iter().all(|&count| count == 1));
+        assert_eq!(ignored, 64);
+    }
+
+    #[test]
+    fn channels_past_seven_have_no_index() {
+        assert_eq!(channel_index(8, 0, CHANNELS), None);
+        assert_eq!(channel_index(7, 7, CHANNELS), Some(63));
+        assert_eq!(channel_index(0, 255, CHANNELS), None);
+        assert_eq!(channel_index(0, 4, 32), None);
+    }
+
+    #[test]
+    fn scan_order_drops_bad_entries() {
+        assert_eq!(scan_order(&[7, 8, 0, 200, 3]).as_slice(), &[7, 0, 3]);
+        assert_eq!(scan_order(&[1; 20]).len(), 16);
+    }
+
+    #[test]
+    fn priority_channels_are_read_twice() {
+        let order = priority_order(&[5]);
+        assert_eq!(order.as_slice(), &[5, 0, 1, 2, 3, 5, 4, 6, 7]);
+        // Every channel is still scanned, however many are priority ones.
+        let order = priority_order(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(order.len(), 16);
+        assert!((0..8).all(|channel| order.iter().filter(|&&read| read == channel).count() == 2));
+    }
+}
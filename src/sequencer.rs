@@ -0,0 +1,249 @@
+// A basic 16 step sequencer. Keys switch steps on and off, and the internal clock advances through them at a fixed
+// tempo, playing each active step's note. The sequencer only decides what happens when; the caller turns the events
+// into MIDI.
+//
+// Timing: every step is scheduled from the previous step's deadline, not from when it was polled, so late polls add no
+// drift. Each step still goes out up to one poll interval (1ms) late, and USB MIDI leaves the device at most once per
+// 1ms frame anyway, so a hardware-timer-driven tick wouldn't tighten it further. There is no MIDI clock (F8) output to
+// drive from one yet.
+//
+// Basic example, polled every millisecond:
+//    let mut sequencer = sequencer::SequencerState::new();
+//    sequencer.toggle(0);
+//    sequencer.toggle(8);
+//    sequencer.start(Instant::now());
+//    sequencer.poll(Instant::now(), |event| match event {
+//        sequencer::StepEvent::Enter { on: Some((note, velocity)), .. } => { /* note-on */ }
+//        sequencer::StepEvent::Enter { on: None, .. } => {}
+//        sequencer::StepEvent::Off => { /* note-off for the last note-on */ }
+//    });
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::notes;
+
+pub const STEPS: usize = 16;
+
+// Taps averaged for the tap tempo, and the longest gap between two taps of one sequence (30 BPM).
+const TAPS: usize = 4;
+const MAX_TAP_GAP: Duration = Duration::from_secs(2);
+
+/// What the sequencer wants played, see `SequencerState::poll`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepEvent {
+    Enter { step: usize, on: Option<(u8, u8)> }, // A new step started, with its note and velocity if it's active.
+    Off,                                         // The note started last has to stop.
+}
+
+/// The step grid and playback position. Every step is a sixteenth note at `bpm`.
+/// "gate" is how long each note sounds; None (the default) holds it until the end of its step.
+#[derive(Debug, Clone, Copy)]
+pub struct SequencerState {
+    pub steps: [bool; STEPS],
+    pub notes: [u8; STEPS],
+    pub velocities: [u8; STEPS],
+    pub bpm: u32,
+    pub gate: Option<Duration>,
+    running: bool,
+    current: Option<usize>, //The step playing, None before the first step after a start.
+    next_step_at: Instant,
+    gate_off_at: Option<Instant>, //When the sounding note stops, None if no note is sounding.
+}
+
+impl SequencerState {
+    pub const DEFAULT_NOTE: u8 = match notes::note_for_name("C4") {
+        Some(note) => note,
+        None => panic!("not a note name"),
+    };
+    pub const DEFAULT_VELOCITY: u8 = 100;
+
+    pub const fn new() -> Self {
+        Self {
+            steps: [false; STEPS],
+            notes: [Self::DEFAULT_NOTE; STEPS],
+            velocities: [Self::DEFAULT_VELOCITY; STEPS],
+            bpm: 120,
+            gate: None,
+            running: false,
+            current: None,
+            next_step_at: Instant::from_ticks(0),
+            gate_off_at: None,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// The step playing, None while stopped or before the first step.
+    pub fn current_step(&self) -> Option<usize> {
+        self.current.filter(|_| self.running)
+    }
+
+    pub fn toggle(&mut self, step: usize) {
+        if let Some(active) = self.steps.get_mut(step) {
+            *active = !*active;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.steps = [false; STEPS];
+    }
+
+    /// Length of one step, a sixteenth note.
+    pub fn step_length(&self) -> Duration {
+        Duration::from_micros(15_000_000 / self.bpm.clamp(20, 300) as u64)
+    }
+
+    /// Starts from the first step, at `now`.
+    pub fn start(&mut self, now: Instant) {
+        self.running = true;
+        self.current = None;
+        self.next_step_at = now;
+        self.gate_off_at = None;
+    }
+
+    /// Stops playback. Returns true if a note was still sounding, which the caller has to stop.
+    pub fn stop(&mut self) -> bool {
+        self.running = false;
+        self.gate_off_at.take().is_some()
+    }
+
+    /// Moves to the next step, wrapping from the last step back to the first, and returns it.
+    pub fn advance(&mut self) -> usize {
+        let step = self.current.map_or(0, |step| (step + 1) % STEPS);
+        self.current = Some(step);
+        step
+    }
+
+    /// Emits everything due at `now`: the note-off of a note whose gate closed, then the next step if it started.
+    /// A note still sounding when its step ends is always stopped first.
+    pub fn poll(&mut self, now: Instant, mut emit: impl FnMut(StepEvent)) {
+        if !self.running {
+            return;
+        }
+        if self.gate_off_at.is_some_and(|off| now >= off) {
+            self.gate_off_at = None;
+            emit(StepEvent::Off);
+        }
+        if now < self.next_step_at {
+            return;
+        }
+        if self.gate_off_at.take().is_some() {
+            emit(StepEvent::Off);
+        }
+        let start = self.next_step_at;
+        let length = self.step_length();
+        self.next_step_at = start + length;
+        let step = self.advance();
+        let on = self.steps[step].then(|| (self.notes[step], self.velocities[step]));
+        if on.is_some() {
+            self.gate_off_at = Some(start + self.gate.unwrap_or(length).min(length));
+        }
+        emit(StepEvent::Enter { step, on });
+    }
+}
+
+impl Default for SequencerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tap tempo: the BPM from the average spacing of the last 4 taps.
+#[derive(Debug, Clone)]
+pub struct TapTempo {
+    taps: Vec<Instant, TAPS>,
+}
+
+impl TapTempo {
+    pub const fn new() -> Self {
+        Self { taps: Vec::new() }
+    }
+
+    /// Records a tap and returns the tapped BPM, from the second tap of a sequence on. A gap more than twice the
+    /// average so far, or over 2 seconds, is an outlier: the sequence starts over from this tap.
+    pub fn tap(&mut self, now: Instant) -> Option<u32> {
+        if let Some(&last) = self.taps.last() {
+            let gap = now - last;
+            let outlier = self.average().is_some_and(|average| gap > average * 2);
+            if outlier || gap > MAX_TAP_GAP {
+                self.taps.clear();
+            }
+        }
+        if self.taps.is_full() {
+            self.taps.remove(0);
+        }
+        self.taps.push(now).ok();
+        self.average().map(|average| (60_000_000 / average.as_micros().max(1)) as u32)
+    }
+
+    // The average gap between the recorded taps, None with fewer than two.
+    fn average(&self) -> Option<Duration> {
+        let gaps = self.taps.len().checked_sub(1).filter(|&gaps| gaps > 0)? as u32;
+        Some((*self.taps.last()? - *self.taps.first()?) / gaps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    fn events(sequencer: &mut SequencerState, now: Instant) -> Vec<StepEvent, 4> {
+        let mut events = Vec::new();
+        sequencer.poll(now, |event| events.push(event).unwrap());
+        events
+    }
+
+    #[test]
+    fn advance_wraps_after_the_last_step() {
+        let mut sequencer = SequencerState::new();
+        for step in 0..STEPS {
+            assert_eq!(sequencer.advance(), step);
+        }
+        assert_eq!(sequencer.advance(), 0);
+    }
+
+    #[test]
+    fn a_note_stops_before_the_next_step_starts() {
+        let mut sequencer = SequencerState::new();
+        sequencer.toggle(0);
+        sequencer.toggle(1);
+        sequencer.start(at(0));
+        let note = Some((SequencerState::DEFAULT_NOTE, SequencerState::DEFAULT_VELOCITY));
+        assert_eq!(events(&mut sequencer, at(0)).as_slice(), &[StepEvent::Enter { step: 0, on: note }]);
+        // No gate: the note holds to the end of its step, the next step's note-on comes after its note-off.
+        assert_eq!(events(&mut sequencer, at(124)).as_slice(), &[]);
+        let next = [StepEvent::Off, StepEvent::Enter { step: 1, on: note }];
+        assert_eq!(events(&mut sequencer, at(125)).as_slice(), &next);
+        // A short gate stops the note on its own, the step after it is empty.
+        sequencer.gate = Some(Duration::from_millis(30));
+        let next = [StepEvent::Off, StepEvent::Enter { step: 2, on: None }];
+        assert_eq!(events(&mut sequencer, at(250)).as_slice(), &next);
+        sequencer.toggle(3);
+        assert_eq!(events(&mut sequencer, at(375)).as_slice(), &[StepEvent::Enter { step: 3, on: note }]);
+        assert_eq!(events(&mut sequencer, at(405)).as_slice(), &[StepEvent::Off]);
+        assert_eq!(events(&mut sequencer, at(500)).as_slice(), &[StepEvent::Enter { step: 4, on: None }]);
+    }
+
+    #[test]
+    fn late_polls_do_not_drift() {
+        let mut sequencer = SequencerState::new();
+        sequencer.start(at(0));
+        // Every step is 125ms at 120 BPM. Every other step is polled 7ms late, the one after it still starts on time.
+        for step in 0..40u64 {
+            let late = if step % 2 == 0 { 7 } else { 0 };
+            if step > 0 {
+                assert_eq!(events(&mut sequencer, at(step * 125 - 1)).as_slice(), &[], "step {step} is early");
+            }
+            let entered = events(&mut sequencer, at(step * 125 + late));
+            assert!(matches!(entered.as_slice(), [StepEvent::Enter { .. }]), "step {step} is late");
+        }
+        assert_eq!(sequencer.current_step(), Some(39 % STEPS));
+    }
+}
@@ -0,0 +1,196 @@
+// MIDI Thru/merge for a serial (DIN/TRS) MIDI input. A UART task parses the incoming byte stream into complete
+// messages and queues them for the main loop, which merges them with the controller's own notes onto the USB output.
+//
+// The parser hands out each message as a self-contained 1 to 3 byte chunk, which is exactly one USB MIDI packet,
+// so merged streams can only interleave between whole messages. It handles:
+// - Running status: data bytes without a status byte reuse the last channel status.
+// - Real-time bytes (0xF8..=0xFF) anywhere, including mid-message. They are forwarded at once and leave the
+//   message being assembled untouched.
+// - SysEx passthrough: 0xF0..0xF7 is forwarded in 3 byte chunks. A status byte other than real-time inside a SysEx
+//   ends it, and it is closed with 0xF7 so the USB side always sees a terminated SysEx.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use esp_hal::uart::UartRx;
+use esp_hal::Async;
+use heapless::Vec;
+use usb_device::bus::UsbBus;
+use usbd_midi::{CableNumber, UsbMidiClass, UsbMidiEventPacket};
+
+/// One complete message (or SysEx fragment) from the serial input, ready to be sent as a USB MIDI packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiChunk {
+    bytes: [u8; 3],
+    len: u8,
+    pub sysex_continues: bool, //True if this is part of a SysEx that hasn't ended yet.
+}
+
+impl MidiChunk {
+    pub fn payload(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Byte-at-a-time parser for a serial MIDI stream.
+#[derive(Debug, Default)]
+pub struct MidiStreamParser {
+    status: Option<u8>, //Status of the message being assembled. Kept after a channel message for running status.
+    data: [u8; 2],
+    data_len: usize,
+    in_sysex: bool,
+    sysex: [u8; 3], //SysEx bytes not yet forwarded.
+    sysex_len: usize,
+}
+
+// Number of data bytes that follow a status byte.
+fn data_bytes_for(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    }
+}
+
+impl MidiStreamParser {
+    pub const fn new() -> Self {
+        Self {
+            status: None,
+            data: [0; 2],
+            data_len: 0,
+            in_sysex: false,
+            sysex: [0; 3],
+            sysex_len: 0,
+        }
+    }
+
+    /// Feeds one byte from the serial input. `emit` is called for every chunk completed by this byte.
+    pub fn feed(&mut self, byte: u8, mut emit: impl FnMut(MidiChunk)) {
+        if byte >= 0xF8 {
+            // Real-time, forwarded immediately without disturbing the message in progress.
+            emit(MidiChunk { bytes: [byte, 0, 0], len: 1, sysex_continues: self.in_sysex });
+            return;
+        }
+        if self.in_sysex {
+            if byte < 0x80 {
+                self.sysex[self.sysex_len] = byte;
+                self.sysex_len += 1;
+                if self.sysex_len == 3 {
+                    self.flush_sysex(&mut emit, true);
+                }
+                return;
+            }
+            // 0xF7 ends the SysEx normally; any other status byte ends it early and is then parsed below.
+            self.end_sysex(&mut emit);
+            if byte == 0xF7 {
+                return;
+            }
+        }
+
+        if byte >= 0x80 {
+            self.data_len = 0;
+            match byte {
+                0xF0 => {
+                    self.status = None;
+                    self.in_sysex = true;
+                    self.sysex[0] = 0xF0;
+                    self.sysex_len = 1;
+                }
+                0xF6 => {
+                    // Tune request has no data bytes.
+                    self.status = None;
+                    emit(MidiChunk { bytes: [byte, 0, 0], len: 1, sysex_continues: false });
+                }
+                0x80..=0xF3 => self.status = Some(byte),
+                _ => self.status = None, // Undefined status bytes or a stray 0xF7.
+            }
+            return;
+        }
+
+        // A data byte.
+        let Some(status) = self.status else {
+            return; // No status to attach it to.
+        };
+        self.data[self.data_len] = byte;
+        self.data_len += 1;
+        let needed = data_bytes_for(status);
+        if self.data_len == needed {
+            emit(MidiChunk {
+                bytes: [status, self.data[0], self.data[1]],
+                len: needed as u8 + 1,
+                sysex_continues: false,
+            });
+            self.data_len = 0;
+            if status >= 0xF0 {
+                self.status = None; // System common messages cancel running status.
+            }
+        }
+    }
+
+    fn flush_sysex(&mut self, emit: &mut impl FnMut(MidiChunk), continues: bool) {
+        emit(MidiChunk { bytes: self.sysex, len: self.sysex_len as u8, sysex_continues: continues });
+        self.sysex_len = 0;
+    }
+
+    fn end_sysex(&mut self, emit: &mut impl FnMut(MidiChunk)) {
+        self.sysex[self.sysex_len] = 0xF7;
+        self.sysex_len += 1;
+        self.in_sysex = false;
+        self.flush_sysex(emit, false);
+    }
+}
+
+// Messages from the serial input waiting to be merged onto USB. Drained by the main loop.
+pub static THRU_EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<MidiChunk, 128>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Reads the serial MIDI input and queues every parsed message for the main loop.
+/// The UART must be configured for 31250 baud. Configure it in `main` with the `midi-thru` feature.
+#[embassy_executor::task]
+pub async fn uart_midi_task(mut rx: UartRx<'static, Async>) {
+    let mut parser = MidiStreamParser::new();
+    let mut buf = [0u8; 16];
+    loop {
+        let Ok(count) = rx.read_async(&mut buf).await else {
+            continue; // Framing or overflow errors drop the bytes; the parser resyncs on the next status byte.
+        };
+        for &byte in &buf[..count] {
+            parser.feed(byte, |chunk| {
+                THRU_EVENTS.lock(|thru_events| {
+                    thru_events.borrow_mut().push(chunk).ok();
+                });
+            });
+        }
+    }
+}
+
+/// Sends queued serial messages over USB in arrival order. Stops at the first failed send and keeps the rest queued.
+/// Returns true while a forwarded SysEx is still open, in which case the caller should hold back its own events so
+/// nothing lands inside the SysEx.
+pub fn forward_thru<B: UsbBus>(midi_class: &mut UsbMidiClass<'_, B>) -> bool {
+    let chunks = THRU_EVENTS.lock(|thru_events| {
+        let mut events = thru_events.borrow_mut();
+        let chunks = events.clone();
+        events.clear();
+        chunks
+    });
+    let mut sysex_open = false;
+    for (sent, chunk) in chunks.iter().enumerate() {
+        let Ok(packet) = UsbMidiEventPacket::try_from_payload_bytes(CableNumber::Cable0, chunk.payload())
+        else {
+            continue;
+        };
+        if midi_class.send_packet(packet).is_err() {
+            // Put the unsent chunks back in front of anything that arrived meanwhile.
+            THRU_EVENTS.lock(|thru_events| {
+                let mut events = thru_events.borrow_mut();
+                let mut pending: Vec<MidiChunk, 128> = Vec::new();
+                pending.extend(chunks[sent..].iter().copied().chain(events.iter().copied()).take(128));
+                *events = pending;
+            });
+            return true;
+        }
+        sysex_open = chunk.sysex_continues;
+    }
+    sysex_open
+}
@@ -0,0 +1,137 @@
+// This module provides a debounced input driver for daisy-chained 74HC165 parallel-in/serial-out shift registers, as an
+// alternative to the 4051 multiplexer for large button counts. It shares the mux's debounce and edge callbacks, so the
+// rest of the firmware works the same with either backend.
+//Each 74HC165 reads 8 switches. Chain them by wiring each chip's QH output to the next chip's SER input; the last chip's
+//QH goes to the data pin. Channel 0 is input H of the chip nearest the micro controller, channel 8 is input H of the
+//next chip, and so on.
+
+//Basic example:
+//    let mut input = shift_register::ShiftRegisterInput::new(
+//        Output::new(peripherals.GPIO1, Level::Low),  // CLK
+//        Output::new(peripherals.GPIO2, Level::High), // SH/LD
+//        Input::new(peripherals.GPIO3, Pull::None),   // QH of the last chip
+//    );
+//    input.set_chips(4);
+//    input.set_falling_edge_callback(falling_edge_handler);
+//    input.set_rising_edge_callback(rising_edge_handler);
+//    spawner.spawn(shift_register_task(input)).unwrap(); // The task calls `input.poll_all().await`.
+
+use crate::mux::{DebounceMode, Debouncer, EdgeHandler, SwitchState, CHANNELS};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{Input, Output};
+
+pub struct ShiftRegisterInput<'a> {
+    clock: Output<'a>, //The CLK pin shared by all chips.
+    latch: Output<'a>, //The SH/LD pin shared by all chips. Low loads the switch states, high shifts them out.
+    data: Input<'a>, //The QH output of the last chip in the chain.
+    chips: usize, //Number of chips in the chain, 1..=8.
+    active_low: bool, //True if a pressed switch reads low (pull-ups, default), false for pull-downs.
+    scan_interval: Duration, //Wait between sweeps.
+    pub debouncer: Debouncer, //The debounced state of all channels.
+    pub falling_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from high to low.
+    pub rising_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from low to high.
+    edge_handler: Option<&'a mut dyn EdgeHandler>, //Handler with its own state, called before the callbacks.
+}
+
+impl<'a> ShiftRegisterInput<'a> {
+    pub fn new(clock: Output<'a>, latch: Output<'a>, data: Input<'a>) -> Self {
+        Self {
+            clock,
+            latch,
+            data,
+            chips: 1,
+            active_low: true,
+            scan_interval: Duration::from_micros(500),
+            debouncer: Debouncer::new(),
+            falling_edge_callback: None,
+            rising_edge_callback: None,
+            edge_handler: None,
+        }
+    }
+
+    /// Sets how many chips are chained, clamped to 1..=8.
+    pub fn set_chips(&mut self, chips: usize) {
+        self.chips = chips.clamp(1, CHANNELS / 8);
+    }
+
+    /// Pass false for switches wired with pull-downs, which read high when pressed.
+    pub fn set_active_low(&mut self, active_low: bool) {
+        self.active_low = active_low;
+    }
+
+    /// Sets the wait between sweeps. Default is 500µs.
+    pub fn set_scan_interval(&mut self, interval: Duration) {
+        self.scan_interval = interval;
+    }
+
+    pub fn set_debounce_interval(&mut self, interval: Duration) {
+        self.debouncer.set_interval(interval);
+    }
+
+    pub fn set_debounce_mode(&mut self, mode: DebounceMode) {
+        self.debouncer.set_mode(mode);
+    }
+
+    /// Ignores presses shorter than `min_press`, see `Debouncer::set_min_press`.
+    pub fn set_min_press(&mut self, min_press: Option<Duration>) {
+        self.debouncer.set_min_press(min_press);
+    }
+
+    pub fn set_falling_edge_callback(&mut self, callback: fn(usize)) {
+        self.falling_edge_callback = Some(callback);
+    }
+
+    pub fn set_rising_edge_callback(&mut self, callback: fn(usize)) {
+        self.rising_edge_callback = Some(callback);
+    }
+
+    /// Sets a handler that receives the edges with its own state, see `mux::EdgeHandler`.
+    pub fn set_edge_handler(&mut self, handler: &'a mut dyn EdgeHandler) {
+        self.edge_handler = Some(handler);
+    }
+
+    /// Returns true if the debounced state of the channel is low (pressed).
+    pub fn is_pressed(&self, index: usize) -> bool {
+        self.debouncer.is_pressed(index)
+    }
+
+    /// Latches every switch and clocks the whole chain out once, debouncing each bit.
+    pub fn poll_once(&mut self) {
+        // Load the parallel inputs, then switch back to shifting. GPIO writes are far slower than the chip's minimum pulse width.
+        self.latch.set_low();
+        self.latch.set_high();
+        for index in 0..self.chips * 8 {
+            let reading = self.data.is_low() == self.active_low;
+            match self.debouncer.update(index, reading) {
+                Some(SwitchState::Low) => {
+                    if let Some(handler) = self.edge_handler.as_mut() {
+                        handler.falling(index);
+                    }
+                    if let Some(callback) = self.falling_edge_callback {
+                        callback(index);
+                    }
+                }
+                Some(SwitchState::High) => {
+                    if let Some(handler) = self.edge_handler.as_mut() {
+                        handler.rising(index);
+                    }
+                    if let Some(callback) = self.rising_edge_callback {
+                        callback(index);
+                    }
+                }
+                None => {}
+            }
+            // Shift the next bit onto QH.
+            self.clock.set_high();
+            self.clock.set_low();
+        }
+    }
+
+    /// Continuously polls the whole chain.
+    pub async fn poll_all(&mut self) {
+        loop {
+            self.poll_once();
+            Timer::after(self.scan_interval).await;
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Settings kept in flash across power cycles. They live in the first sector of the "nvs" partition (0x9000 in the
+// default partition table), which nothing else uses in a bare-metal build. The sector is split into fixed slots, one
+// per kind of setting, each with a small header so an erased sector (or a slot never written) reads as nothing saved.
+//
+// Writing erases and rewrites the whole 4KB sector, which stalls the CPU for tens of milliseconds: save from the main
+// loop, never from an edge callback, and only when a setting actually changed.
+
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::{FlashStorage, FlashStorageError};
+
+const SECTOR_ADDR: u32 = 0x9000;
+const SLOT_SIZE: usize = 512;
+const HEADER_SIZE: usize = 6; //Magic, length and checksum, 2 bytes each.
+const MAGIC: u16 = 0x4d43;
+
+/// The most data one slot holds.
+pub const MAX_LEN: usize = SLOT_SIZE - HEADER_SIZE;
+
+/// Where each kind of setting is stored. At most 8 slots fit in the sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    KeyMap = 0, // The learned key map of older firmware, now part of the config. Only read, see `load_key_map`.
+    Config = 1, // Every run-time setting, see `save_config`.
+}
+
+fn slot_addr(slot: Slot) -> u32 {
+    SECTOR_ADDR + (slot as u32) * SLOT_SIZE as u32
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |sum, &byte| sum.rotate_left(1) ^ byte as u16)
+}
+
+/// Stores `data` in `slot`, replacing what was there. Data longer than `MAX_LEN` is refused with `OutOfBounds`.
+pub fn save(slot: Slot, data: &[u8]) -> Result<(), FlashStorageError> {
+    if data.len() > MAX_LEN {
+        return Err(FlashStorageError::OutOfBounds);
+    }
+    let mut record = [0xff; SLOT_SIZE];
+    record[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+    record[2..4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    record[4..6].copy_from_slice(&checksum(data).to_le_bytes());
+    record[HEADER_SIZE..][..data.len()].copy_from_slice(data);
+    FlashStorage::new().write(slot_addr(slot), &record[..HEADER_SIZE + data.len()])
+}
+
+/// Reads `slot` into `buf` and returns the stored length. None if nothing valid is stored or it doesn't fit in `buf`.
+pub fn load(slot: Slot, buf: &mut [u8]) -> Option<usize> {
+    let mut flash = FlashStorage::new();
+    let mut header = [0; HEADER_SIZE];
+    flash.read(slot_addr(slot), &mut header).ok()?;
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if u16::from_le_bytes([header[0], header[1]]) != MAGIC || len > MAX_LEN || len > buf.len() {
+        return None;
+    }
+    flash.read(slot_addr(slot) + HEADER_SIZE as u32, &mut buf[..len]).ok()?;
+    (checksum(&buf[..len]) == u16::from_le_bytes([header[4], header[5]])).then_some(len)
+}
@@ -0,0 +1,140 @@
+// Configuration over USB MIDI SysEx, so a host editor can read and change settings without reflashing. This module
+// only frames and checks messages; `main` applies them to the global state and saves them to flash.
+//
+// Every message, in both directions, is
+//    F0 7D 01 <command> <data...> <checksum> F7
+// 7D is the non-commercial manufacturer ID and 01 the device ID of this controller. All data bytes are 7 bit, and the
+// checksum makes the low 7 bits of command + data + checksum add up to 0.
+//
+// Commands from the host, each answered by the controller:
+//    01 <field>            Read a field.             Answer: 41 <field> <value...>
+//    02 <field> <value...> Write and save a field.   Answer: 7F 02 <status>
+//    03                    Dump every field.         Answer: 43 <dump>
+//    04 <dump>             Restore a dump and save.  Answer: 7F 04 <status>
+// A dump is every run-time setting in the versioned layout of `config::ControllerConfig`, which is also what is saved
+// to flash: a version byte, the values of fields 00 to 02, the channel, the key map (field 03) and the key zones, see
+// the top of `src/config.rs`. A restore is checked as a whole and may come from a later firmware version, whose extra
+// trailing fields are ignored. Any request that can't be carried out is answered with 7F <command> <status> and
+// changes nothing.
+//
+// Fields and their values:
+//    00 Octave limits   2 bytes: lowest and highest octave, each + 64.
+//    01 Debounce        1 byte: debounce interval in milliseconds.
+//    02 Velocity curve  1 byte: 0 linear, 1 soft, 2 hard.
+//    03 Key map         NUM_KEYS bytes: the mux channel of each note key, lowest note first. Every channel must be a
+//                       note key channel of KEYS, and no channel may appear twice.
+
+use heapless::Vec;
+
+pub const MANUFACTURER_ID: u8 = 0x7d;
+pub const DEVICE_ID: u8 = 0x01;
+
+/// The longest complete SysEx message kept, F0 and F7 included. Longer messages are dropped.
+pub const MAX_MESSAGE: usize = 96; // A dump with every zone in use is about 70 bytes.
+
+pub const READ: u8 = 0x01;
+pub const WRITE: u8 = 0x02;
+pub const DUMP: u8 = 0x03;
+pub const RESTORE: u8 = 0x04;
+pub const READ_REPLY: u8 = 0x41;
+pub const DUMP_REPLY: u8 = 0x43;
+pub const ACK: u8 = 0x7f;
+
+/// The result reported in an ack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    BadChecksum = 1,
+    Unknown = 2,     // Unknown command or field.
+    BadValue = 3,    // Wrong length or a value out of range.
+    SaveFailed = 4,  // Applied, but the flash write failed: the change is lost on the next power cycle.
+}
+
+/// A request from the host, borrowing its data from the received message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Read(u8),
+    Write(u8, &'a [u8]),
+    Dump,
+    Restore(&'a [u8]),
+}
+
+/// Collects the bytes of one incoming SysEx message from USB MIDI packet payloads.
+#[derive(Debug)]
+pub struct SysexReceiver {
+    message: Vec<u8, MAX_MESSAGE>,
+    too_long: bool, //The message being received didn't fit and is dropped.
+}
+
+impl SysexReceiver {
+    pub const fn new() -> Self {
+        Self { message: Vec::new(), too_long: false }
+    }
+
+    /// Feeds the payload of one SysEx packet. Returns true once a complete message is available from `message`.
+    pub fn feed(&mut self, payload: &[u8]) -> bool {
+        for &byte in payload {
+            if byte == 0xf0 {
+                self.message.clear();
+                self.too_long = false;
+            }
+            if self.message.push(byte).is_err() {
+                self.too_long = true;
+            }
+            if byte == 0xf7 {
+                let complete = !self.too_long && self.message.first() == Some(&0xf0);
+                if !complete {
+                    self.message.clear();
+                }
+                return complete;
+            }
+        }
+        false
+    }
+
+    /// The message completed by the last `feed`, F0 and F7 included.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    (128 - bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte) & 0x7f)) & 0x7f
+}
+
+/// Parses a complete message. Returns None for SysEx meant for someone else, which should be ignored without an answer.
+/// Otherwise the request, or the status to ack its command with.
+pub fn parse(message: &[u8]) -> Option<Result<Request<'_>, (u8, Status)>> {
+    let [0xf0, MANUFACTURER_ID, DEVICE_ID, command, body @ .., sum, 0xf7] = message else {
+        return None;
+    };
+    if checksum(&message[3..message.len() - 2]) != *sum {
+        return Some(Err((*command, Status::BadChecksum)));
+    }
+    Some(match (*command, body) {
+        (READ, &[field]) => Ok(Request::Read(field)),
+        (WRITE, [field, value @ ..]) => Ok(Request::Write(*field, value)),
+        (DUMP, []) => Ok(Request::Dump),
+        (RESTORE, dump) => Ok(Request::Restore(dump)),
+        (READ | WRITE | DUMP, _) => Err((*command, Status::BadValue)),
+        _ => Err((*command, Status::Unknown)),
+    })
+}
+
+/// Builds a message from the controller: `command` followed by `data`, framed and checksummed. Data that doesn't fit
+/// in `MAX_MESSAGE` is cut short.
+pub fn message(command: u8, data: &[u8]) -> Vec<u8, MAX_MESSAGE> {
+    let mut message: Vec<u8, MAX_MESSAGE> = Vec::new();
+    let room = MAX_MESSAGE - 6;
+    let data = &data[..data.len().min(room)];
+    message.extend_from_slice(&[0xf0, MANUFACTURER_ID, DEVICE_ID, command]).ok();
+    message.extend_from_slice(data).ok();
+    message.push(checksum(&message[3..])).ok();
+    message.push(0xf7).ok();
+    message
+}
+
+/// The ack for `command`.
+pub fn ack(command: u8, status: Status) -> Vec<u8, MAX_MESSAGE> {
+    message(ACK, &[command, status as u8])
+}
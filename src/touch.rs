@@ -0,0 +1,236 @@
+// This module provides a debounced input driver for capacitive touch pads on the ESP32-S3's own touch sensor, as an
+// alternative to the 4051 multiplexer for a keyboard with no moving parts. Each pad is a copper area (or a key plate)
+// wired straight to a touch pin, no mux needed. It shares the mux's debounce and edge callbacks, so the rest of the
+// firmware works the same with either backend.
+//Touch pad N is GPIO N, for pads 1..=14. On the XIAO those are D0-D5 (GPIO1-6) and D8-D10 (GPIO7-9), so up to 9 pads,
+//and none of them can also drive the mux. Channel 0 is the first pad in the list given to `new`, channel 1 the next, and
+//so on; map the channels in KEYS as usual. Keep the pad wires short and away from each other: the wire is part of the
+//pad. esp-hal has no touch driver for the S3, so this one programs the touch registers itself: nothing else may use
+//them, nor the pads' GPIOs.
+//A touch raises a pad's reading. Each pad is compared with its own baseline, the reading with nothing near it, which is
+//measured by `calibrate` and follows slow drift (temperature, humidity) while the pad is untouched.
+
+//Basic example:
+//    let mut input = touch::TouchInput::new(&[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+//    input.calibrate().await; // Keep hands off the pads for the ~100ms this takes.
+//    input.set_falling_edge_callback(falling_edge_handler);
+//    input.set_rising_edge_callback(rising_edge_handler);
+//    spawner.spawn(touch_task(input)).unwrap(); // The task calls `input.poll_all().await`.
+
+use core::cmp::Ordering;
+use crate::mux::{self, DebounceMode, Debouncer, Edge, EdgeHandler, SwitchState};
+use embassy_time::{Duration, Timer};
+use esp32s3::{RTC_CNTL, RTC_IO, SENS};
+use heapless::Vec;
+
+/// The S3 has 14 touch pads usable as inputs.
+pub const MAX_PADS: usize = 14;
+
+// Readings averaged into each baseline by `calibrate`.
+const CALIBRATION_SAMPLES: u32 = 16;
+
+pub struct TouchInput<'a> {
+    pads: Vec<u8, MAX_PADS>, //The touch pad number of each channel.
+    baseline: [u32; MAX_PADS], //The untouched reading of each channel.
+    touched: [bool; MAX_PADS], //The raw touch state of each channel, before debouncing.
+    threshold_percent: u32, //How far above its baseline a channel must read to count as touched.
+    scan_interval: Duration, //Wait between sweeps.
+    pub debouncer: Debouncer, //The debounced state of all channels.
+    pub falling_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from high to low.
+    pub rising_edge_callback: Option<fn(usize)>, //Callback for when a channel's state changes from low to high.
+    edge_handler: Option<&'a mut dyn EdgeHandler>, //Handler with its own state, called before the callbacks.
+}
+
+impl<'a> TouchInput<'a> {
+    /// Starts the touch sensor scanning `pads` (touch pad numbers 1..=14, i.e. GPIO numbers) in the background.
+    /// Numbers out of range or given twice are skipped. Call `calibrate` before polling.
+    pub fn new(pads: &[u8]) -> Self {
+        let mut channels: Vec<u8, MAX_PADS> = Vec::new();
+        for &pad in pads {
+            if (1..=MAX_PADS as u8).contains(&pad) && !channels.contains(&pad) {
+                channels.push(pad).ok();
+            }
+        }
+        let mut debouncer = Debouncer::new();
+        // A finger doesn't bounce, but the reading is noisy near the threshold.
+        debouncer.set_interval(Duration::from_millis(10));
+        start_sensor(channels.iter().fold(0, |mask, &pad| mask | 1 << pad));
+        Self {
+            pads: channels,
+            baseline: [0; MAX_PADS],
+            touched: [false; MAX_PADS],
+            threshold_percent: 20,
+            scan_interval: Duration::from_millis(2),
+            debouncer,
+            falling_edge_callback: None,
+            rising_edge_callback: None,
+            edge_handler: None,
+        }
+    }
+
+    /// Measures every pad's baseline. Nothing may touch the pads meanwhile, or that pad will read as never touched.
+    pub async fn calibrate(&mut self) {
+        Timer::after_millis(20).await; // Let the first scans finish.
+        let mut sums = [0u32; MAX_PADS];
+        for _ in 0..CALIBRATION_SAMPLES {
+            for (channel, &pad) in self.pads.iter().enumerate() {
+                sums[channel] += read_pad(pad);
+            }
+            Timer::after_millis(5).await;
+        }
+        for (baseline, sum) in self.baseline.iter_mut().zip(sums) {
+            *baseline = sum / CALIBRATION_SAMPLES;
+        }
+    }
+
+    /// Sets how far above its baseline a pad must read to count as touched, in percent. Default is 20; lower it for
+    /// pads under a thick cover, raise it if pads trigger from a hand nearby. Releases use half of it, so a finger
+    /// resting right at the threshold doesn't chatter.
+    pub fn set_threshold_percent(&mut self, percent: u32) {
+        self.threshold_percent = percent.max(1);
+    }
+
+    /// Sets the wait between sweeps. Default is 2ms.
+    pub fn set_scan_interval(&mut self, interval: Duration) {
+        self.scan_interval = interval;
+    }
+
+    pub fn set_debounce_interval(&mut self, interval: Duration) {
+        self.debouncer.set_interval(interval);
+    }
+
+    pub fn set_debounce_mode(&mut self, mode: DebounceMode) {
+        self.debouncer.set_mode(mode);
+    }
+
+    pub fn set_falling_edge_callback(&mut self, callback: fn(usize)) {
+        self.falling_edge_callback = Some(callback);
+    }
+
+    pub fn set_rising_edge_callback(&mut self, callback: fn(usize)) {
+        self.rising_edge_callback = Some(callback);
+    }
+
+    /// Sets a handler that receives the edges with its own state, see `mux::EdgeHandler`.
+    pub fn set_edge_handler(&mut self, handler: &'a mut dyn EdgeHandler) {
+        self.edge_handler = Some(handler);
+    }
+
+    /// Returns true if the debounced state of the channel is low (touched).
+    pub fn is_pressed(&self, index: usize) -> bool {
+        self.debouncer.is_pressed(index)
+    }
+
+    /// The last raw reading and the baseline of a channel, for tuning the threshold.
+    pub fn reading(&self, index: usize) -> Option<(u32, u32)> {
+        let pad = *self.pads.get(index)?;
+        Some((read_pad(pad), self.baseline[index]))
+    }
+
+    /// Reads every pad once, compares it with its baseline and debounces the result.
+    pub fn poll_once(&mut self) {
+        for index in 0..self.pads.len() {
+            let reading = read_pad(self.pads[index]);
+            let baseline = self.baseline[index];
+            let percent = if self.touched[index] { self.threshold_percent / 2 } else { self.threshold_percent };
+            self.touched[index] = reading > baseline + baseline * percent / 100;
+            if !self.touched[index] {
+                // Follow slow drift while untouched, one count per sweep, far slower than a finger approaching.
+                self.baseline[index] = match reading.cmp(&baseline) {
+                    Ordering::Greater => baseline + 1,
+                    Ordering::Less => baseline - 1,
+                    Ordering::Equal => baseline,
+                };
+            }
+            match self.debouncer.update(index, self.touched[index]) {
+                Some(SwitchState::Low) => {
+                    if let Some(handler) = self.edge_handler.as_mut() {
+                        handler.falling(index);
+                    }
+                    if let Some(callback) = self.falling_edge_callback {
+                        callback(index);
+                    }
+                    mux::publish_edge(index, Edge::Falling);
+                }
+                Some(SwitchState::High) => {
+                    if let Some(handler) = self.edge_handler.as_mut() {
+                        handler.rising(index);
+                    }
+                    if let Some(callback) = self.rising_edge_callback {
+                        callback(index);
+                    }
+                    mux::publish_edge(index, Edge::Rising);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Continuously polls every pad.
+    pub async fn poll_all(&mut self) {
+        loop {
+            self.poll_once();
+            Timer::after(self.scan_interval).await;
+        }
+    }
+}
+
+// Charge/discharge cycles per measurement (8MHz clock) and the RTC slow clock cycles between scans. ESP-IDF's defaults.
+const MEASURE_CYCLES: u16 = 500;
+const SLEEP_CYCLES: u16 = 0xf;
+
+/// Programs the touch sensor to scan the pads in `mask` (bit N for pad N) on its own timer.
+fn start_sensor(mask: u16) {
+    // Safety: the touch registers (and the RTC pad registers of touch pads) are only used by this driver.
+    let rtc = unsafe { &*RTC_CNTL::ptr() };
+    let io = unsafe { &*RTC_IO::ptr() };
+    let sens = unsafe { &*SENS::ptr() };
+    for pad in 1..=MAX_PADS {
+        if mask & 1 << pad == 0 {
+            continue;
+        }
+        // Hand the pin to the RTC pad, no pulls, input and output off: the sensor drives it.
+        io.touch_pad(pad).modify(|_, w| unsafe {
+            w.mux_sel().set_bit().fun_sel().bits(0).fun_ie().clear_bit().rue().clear_bit().rde().clear_bit()
+        });
+        io.enable_w1tc().write(|w| unsafe { w.enable_w1tc().bits(1 << pad) });
+        // Charge/discharge slope 7, the fastest.
+        if pad < 10 {
+            rtc.touch_dac().modify(|r, w| unsafe { w.bits(r.bits() | 7 << (29 - 3 * pad)) });
+        } else {
+            rtc.touch_dac1().modify(|r, w| unsafe { w.bits(r.bits() | 7 << (29 - 3 * (pad - 10))) });
+        }
+    }
+    rtc.touch_ctrl1().write(|w| unsafe { w.touch_meas_num().bits(MEASURE_CYCLES).touch_sleep_cycles().bits(SLEEP_CYCLES) });
+    rtc.touch_scan_ctrl().modify(|_, w| unsafe {
+        w.touch_scan_pad_map().bits(mask).touch_inactive_connection().set_bit() // Pads not being measured go to GND.
+    });
+    sens.sar_touch_conf().modify(|_, w| unsafe { w.sar_touch_outen().bits(mask).sar_touch_data_sel().bits(0) });
+    // Voltage swing 0.5V to 2.7V, timer triggered scans, clock on.
+    rtc.touch_ctrl2().modify(|_, w| unsafe {
+        w.touch_drefh()
+            .bits(3)
+            .touch_drefl()
+            .bits(0)
+            .touch_drange()
+            .bits(3)
+            .touch_xpd_bias()
+            .set_bit()
+            .touch_start_force()
+            .clear_bit()
+            .touch_start_fsm_en()
+            .set_bit()
+            .touch_clkgate_en()
+            .set_bit()
+            .touch_slp_timer_en()
+            .set_bit()
+    });
+}
+
+/// The last raw measurement of a touch pad.
+fn read_pad(pad: u8) -> u32 {
+    let sens = unsafe { &*SENS::ptr() };
+    // One status register per pad, pad 0 first, each with the data in its low 22 bits.
+    let status = sens.sar_touch_status0() as *const _ as *const u32;
+    unsafe { status.add(pad as usize).read_volatile() & 0x3f_ffff }
+}
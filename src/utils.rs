@@ -0,0 +1,15 @@
+// Checked constructors for MIDI values computed with integer maths (octave, transpose, trim, curves), so an overflow
+// turns into a clamp or a skipped note instead of a wrapped value. `Value7::from(n as u8)` would send note 130 as 127
+// and note -2 as 127 too.
+
+use midi_convert::midi_types::{Note, Value7};
+
+/// A 7 bit value (velocity, CC value) from any integer, clamped into 0..=127.
+pub fn clamped_value7(n: i32) -> Value7 {
+    Value7::new(n.clamp(0, 127) as u8)
+}
+
+/// The note for a note number, or None outside 0..=127 so the caller can skip sending it.
+pub fn clamped_note(n: i32) -> Option<Note> {
+    (0..=127).contains(&n).then(|| Note::new(n as u8))
+}
@@ -0,0 +1,526 @@
+// Velocity from dual-contact keys. Each key has two switches that close one after the other as the key travels down;
+// the time between the first and second contact is the key speed, which maps to note-on velocity.
+//
+// A key's first contact is its `KeyFunction::Note` channel, the second one a `KeyFunction::SecondContact` channel, see
+// `press_contact` in main.rs. Basic example:
+//    let mut key = velocity::DualContactKey::new();
+//    key.first_contact(Instant::now());                  // First contact falling edge.
+//    if let Some(time) = key.second_contact(Instant::now()) {   // Second contact falling edge.
+//        let velocity = VELOCITY.velocity_for(time);
+//    }
+//    key.release();                                      // First contact rising edge.
+//
+// Premium keybeds with three contacts per key work the same way with `TripleContactKey`, the third contact on a
+// `KeyFunction::ThirdContact` channel.
+
+use embassy_time::{Duration, Instant};
+
+use crate::NUM_KEYS;
+
+/// Response curve from a normalized input (0..=1000) to a normalized output (0..=1000).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    Soft, //Concave: more output for light input, easier to play loud.
+    Hard, //Convex: less output for light input, needs a firm strike to play loud.
+}
+
+impl Curve {
+    pub fn apply(&self, input: u32) -> u32 {
+        let x = input.min(1000);
+        match self {
+            Self::Linear => x,
+            Self::Soft => 1000 - (1000 - x) * (1000 - x) / 1000,
+            Self::Hard => x * x / 1000,
+        }
+    }
+}
+
+/// Tracks the contact timing of one dual-contact key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DualContactKey {
+    first_made: Option<Instant>,
+}
+
+impl DualContactKey {
+    pub const fn new() -> Self {
+        Self { first_made: None }
+    }
+
+    /// Call on the first contact's falling edge.
+    pub fn first_contact(&mut self, at: Instant) {
+        self.first_made = Some(at);
+    }
+
+    /// Call on the second contact's falling edge. Returns the make-to-make time, or None if the first contact wasn't seen.
+    pub fn second_contact(&mut self, at: Instant) -> Option<Duration> {
+        self.first_made.take().map(|first| at.duration_since(first))
+    }
+
+    /// Call when the key is released (first contact's rising edge).
+    pub fn release(&mut self) {
+        self.first_made = None;
+    }
+}
+
+/// Maps the make-to-make time of a dual-contact key to velocity.
+/// Times at or below `min_time` give 127, times at or above `max_time` give `min_velocity`, and the range between
+/// is shaped by `curve` (faster is louder).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DualContactVelocity {
+    pub curve: Curve,
+    pub min_time: Duration,
+    pub max_time: Duration,
+    pub min_velocity: u8,
+}
+
+impl DualContactVelocity {
+    pub const DEFAULT: Self = Self {
+        curve: Curve::Linear,
+        min_time: Duration::from_millis(2),
+        max_time: Duration::from_millis(60),
+        min_velocity: 1,
+    };
+
+    pub fn velocity_for(&self, time: Duration) -> u8 {
+        let min = self.min_time.as_micros();
+        let max = self.max_time.as_micros().max(min + 1);
+        let time = time.as_micros().clamp(min, max);
+        // 1000 is the fastest strike, 0 the slowest.
+        let speed = ((max - time) * 1000 / (max - min)) as u32;
+        let floor = self.min_velocity.clamp(1, 127) as u32;
+        (floor + self.curve.apply(speed) * (127 - floor) / 1000) as u8
+    }
+}
+
+/// The make times of a three-contact key, from the first contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TripleContactTiming {
+    pub first_to_second: Option<Duration>, //None if the middle contact's edge was missed.
+    pub first_to_third: Duration,
+}
+
+/// Tracks the contact timing and bottom contact of one key on a keybed with a third contact at the bottom of the key
+/// travel. The note starts when the third contact makes, with the velocity from the first to third contact time: that
+/// spans the whole key travel, about twice the time between the two contacts of a dual-contact key, so the timing step
+/// of one mux sweep is half as large a part of it and a velocity step covers a finer difference in key speed. A held
+/// key that comes up off the bottom and is pressed back into it counts as pressed into for aftertouch until it comes up
+/// again, which is on or off: contacts can't tell how hard.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TripleContactKey {
+    first_made: Option<Instant>,
+    second_made: Option<Instant>,
+    struck: bool,   //The third contact made since the first one, the note is playing.
+    bottomed: bool, //Pressed back into the bottom since.
+}
+
+impl TripleContactKey {
+    pub const fn new() -> Self {
+        Self { first_made: None, second_made: None, struck: false, bottomed: false }
+    }
+
+    /// Call on the first contact's falling edge.
+    pub fn first_contact(&mut self, at: Instant) {
+        self.first_made = Some(at);
+        self.second_made = None;
+    }
+
+    /// Call on the second contact's falling edge.
+    pub fn second_contact(&mut self, at: Instant) {
+        if self.first_made.is_some() {
+            self.second_made = Some(at);
+        }
+    }
+
+    /// Call on the third contact's falling edge. Returns the timing for the note-on, or None if the first contact
+    /// wasn't seen or the key is being pressed into again without coming up (no new note, see `is_bottomed`).
+    pub fn third_contact(&mut self, at: Instant) -> Option<TripleContactTiming> {
+        let Some(first) = self.first_made.take() else {
+            self.bottomed = self.struck;
+            return None;
+        };
+        self.struck = true;
+        let second = self.second_made.take();
+        Some(TripleContactTiming {
+            first_to_second: second.map(|second| second.duration_since(first)),
+            first_to_third: at.duration_since(first),
+        })
+    }
+
+    /// Call on the third contact's rising edge: the key came up off the bottom, aftertouch ends.
+    pub fn third_release(&mut self) {
+        self.bottomed = false;
+    }
+
+    /// Call when the key is released (first contact's rising edge).
+    pub fn release(&mut self) {
+        *self = Self::new();
+    }
+
+    /// True while a struck key is pressed back into the bottom of its travel, for on/off aftertouch.
+    pub fn is_bottomed(&self) -> bool {
+        self.bottomed
+    }
+}
+
+/// Maps the first to third contact time of a three-contact key to velocity, like `DualContactVelocity` over the whole
+/// key travel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TripleContactVelocity(pub DualContactVelocity);
+
+impl TripleContactVelocity {
+    // Twice the dual-contact range, as the first to third contact time covers about twice the travel.
+    pub const DEFAULT: Self = Self(DualContactVelocity {
+        curve: Curve::Linear,
+        min_time: Duration::from_millis(4),
+        max_time: Duration::from_millis(120),
+        min_velocity: 1,
+    });
+
+    pub fn velocity_for(&self, timing: &TripleContactTiming) -> u8 {
+        self.0.velocity_for(timing.first_to_third)
+    }
+}
+
+/// Records a player's fastest and slowest presses to set `min_time`/`max_time`.
+/// Call `record` for every measured press while calibrating, then `apply` to the velocity settings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VelocityCalibration {
+    fastest: Option<Duration>,
+    slowest: Option<Duration>,
+}
+
+impl VelocityCalibration {
+    pub const fn new() -> Self {
+        Self {
+            fastest: None,
+            slowest: None,
+        }
+    }
+
+    pub fn record(&mut self, time: Duration) {
+        self.fastest = Some(self.fastest.map_or(time, |fastest| fastest.min(time)));
+        self.slowest = Some(self.slowest.map_or(time, |slowest| slowest.max(time)));
+    }
+
+    /// Writes the recorded bounds into `velocity`. Leaves it unchanged unless at least two different times were seen.
+    pub fn apply(&self, velocity: &mut DualContactVelocity) {
+        if let (Some(fastest), Some(slowest)) = (self.fastest, self.slowest) {
+            if fastest < slowest {
+                velocity.min_time = fastest;
+                velocity.max_time = slowest;
+            }
+        }
+    }
+}
+
+/// Per-key velocity trim to even out a keybed whose keys read slightly differently for the same strike.
+/// The final velocity is the computed velocity plus the key's offset, clamped to 1..=127.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VelocityTrim {
+    pub offsets: [i8; NUM_KEYS],
+}
+
+impl VelocityTrim {
+    pub const NONE: Self = Self { offsets: [0; NUM_KEYS] };
+
+    pub fn apply(&self, key: usize, velocity: u8) -> u8 {
+        let offset = self.offsets.get(key).copied().unwrap_or(0) as i16;
+        (velocity as i16 + offset).clamp(1, 127) as u8
+    }
+}
+
+/// A played velocity through the key's trim, then `curve`, then raised to `floor`, so even a very slow strike is heard
+/// whatever the trim and curve made of it. `trim` is None while the trim is being calibrated: keys play untrimmed.
+pub fn shape(velocity: u8, key: usize, trim: Option<&VelocityTrim>, curve: Curve, floor: u8) -> u8 {
+    let velocity = trim.map_or(velocity, |trim| trim.apply(key, velocity));
+    let velocity = match curve {
+        Curve::Linear => velocity,
+        curve => (curve.apply(velocity as u32 * 1000 / 127) * 127 / 1000).clamp(1, 127) as u8,
+    };
+    if velocity > 0 {
+        velocity.max(floor)
+    } else {
+        velocity
+    }
+}
+
+/// The velocity a note plays at in a zone, highest precedence first: 127 while the full velocity key is held, then the
+/// zone's fixed velocity, then the played velocity (already shaped, see `shape_velocity` in main.rs). So a bass zone
+/// can always hit hard while the lead zone stays dynamic, and the override still reaches both.
+pub fn resolve(full_velocity: bool, zone_velocity: Option<u8>, played: u8) -> u8 {
+    match (full_velocity, zone_velocity) {
+        (true, _) => 127,
+        (false, Some(fixed)) => fixed,
+        (false, None) => played,
+    }
+}
+
+/// Finds trim offsets from the player striking every key at the same reference force.
+/// Call `record` with each key's untrimmed velocity while calibrating, then `apply` to the trim table: every recorded
+/// key is trimmed to the average of all recorded keys. Keys that weren't struck keep their offset.
+#[derive(Debug, Clone, Copy)]
+pub struct TrimCalibration {
+    readings: [Option<u8>; NUM_KEYS],
+}
+
+impl TrimCalibration {
+    pub const fn new() -> Self {
+        Self { readings: [None; NUM_KEYS] }
+    }
+
+    pub fn record(&mut self, key: usize, velocity: u8) {
+        if let Some(reading) = self.readings.get_mut(key) {
+            *reading = Some(velocity);
+        }
+    }
+
+    pub fn apply(&self, trim: &mut VelocityTrim) {
+        let recorded = self.readings.iter().flatten();
+        let count = recorded.clone().count() as i32;
+        if count == 0 {
+            return;
+        }
+        let average = recorded.map(|&velocity| velocity as i32).sum::<i32>() / count;
+        for (offset, reading) in trim.offsets.iter_mut().zip(self.readings.iter()) {
+            if let Some(velocity) = reading {
+                *offset = (average - *velocity as i32).clamp(i8::MIN as i32, i8::MAX as i32) as i8;
+            }
+        }
+    }
+}
+
+/// Tiny xorshift PRNG for velocity humanize. Not for anything that needs real randomness.
+#[derive(Debug, Clone, Copy)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    pub const fn new(seed: u32) -> Self {
+        // Xorshift gets stuck at 0.
+        Self {
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Adds a random jitter of up to ±`amount` to a velocity, clamped to 1..=127. An amount of 0 leaves it unchanged.
+    pub fn humanize(&mut self, velocity: u8, amount: u8) -> u8 {
+        if amount == 0 {
+            return velocity;
+        }
+        let span = 2 * amount as u32 + 1;
+        let jitter = (self.next_u32() % span) as i16 - amount as i16;
+        (velocity as i16 + jitter).clamp(1, 127) as u8
+    }
+}
+
+pub const ROUND_ROBIN_STEPS: usize = 4;
+
+/// Round-robin velocity: repeated strikes of the same note cycle through `velocities` instead of all playing alike,
+/// so fast repeats (drum rolls) don't sound like a machine gun. A different note, or the same note not struck for
+/// `reset_after`, starts over from the first velocity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundRobin {
+    pub velocities: [u8; ROUND_ROBIN_STEPS],
+    pub reset_after: Duration,
+    last: Option<(i32, Instant)>, //The note struck last and when.
+    index: usize, //Position in `velocities` of the last strike.
+}
+
+impl RoundRobin {
+    pub const fn new(velocities: [u8; ROUND_ROBIN_STEPS], reset_after: Duration) -> Self {
+        Self { velocities, reset_after, last: None, index: 0 }
+    }
+
+    /// The velocity for a strike of `note`, advancing the cycle on a repeat.
+    pub fn next(&mut self, note: i32, now: Instant) -> u8 {
+        let repeat = self.last.is_some_and(|(last, at)| last == note && now - at < self.reset_after);
+        self.index = if repeat { (self.index + 1) % ROUND_ROBIN_STEPS } else { 0 };
+        self.last = Some((note, now));
+        self.velocities[self.index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn second_contact_times_the_strike() {
+        let mut key = DualContactKey::new();
+        assert_eq!(key.second_contact(Instant::from_millis(5)), None, "no first contact seen");
+        key.first_contact(Instant::from_millis(10));
+        assert_eq!(key.second_contact(Instant::from_millis(14)), Some(ms(4)));
+        assert_eq!(key.second_contact(Instant::from_millis(20)), None, "only once per strike");
+        key.first_contact(Instant::from_millis(30));
+        key.release();
+        assert_eq!(key.second_contact(Instant::from_millis(31)), None);
+    }
+
+    #[test]
+    fn times_past_the_range_clamp() {
+        let velocity = DualContactVelocity { min_velocity: 20, ..DualContactVelocity::DEFAULT };
+        assert_eq!(velocity.velocity_for(ms(2)), 127);
+        assert_eq!(velocity.velocity_for(Duration::from_micros(500)), 127);
+        assert_eq!(velocity.velocity_for(ms(60)), 20);
+        assert_eq!(velocity.velocity_for(ms(500)), 20);
+    }
+
+    #[test]
+    fn curves_shape_the_midpoint() {
+        // 31ms is halfway between 2ms and 60ms.
+        let midpoint = |curve| DualContactVelocity { curve, ..DualContactVelocity::DEFAULT }.velocity_for(ms(31));
+        assert_eq!(midpoint(Curve::Linear), 64);
+        assert_eq!(midpoint(Curve::Soft), 95);
+        assert_eq!(midpoint(Curve::Hard), 32);
+    }
+
+    #[test]
+    fn three_contacts_time_the_whole_travel() {
+        let mut key = TripleContactKey::new();
+        key.first_contact(Instant::from_millis(100));
+        key.second_contact(Instant::from_millis(106));
+        let timing = key.third_contact(Instant::from_millis(112)).unwrap();
+        assert_eq!(timing, TripleContactTiming { first_to_second: Some(ms(6)), first_to_third: ms(12) });
+        // 62ms is halfway between 4ms and 120ms.
+        let timing = TripleContactTiming { first_to_second: None, first_to_third: ms(62) };
+        assert_eq!(TripleContactVelocity::DEFAULT.velocity_for(&timing), 64);
+        let fastest = TripleContactTiming { first_to_third: ms(4), ..timing };
+        assert_eq!(TripleContactVelocity::DEFAULT.velocity_for(&fastest), 127);
+    }
+
+    #[test]
+    fn a_missed_middle_contact_still_plays() {
+        let mut key = TripleContactKey::new();
+        key.first_contact(Instant::from_millis(0));
+        let timing = key.third_contact(Instant::from_millis(20)).unwrap();
+        assert_eq!(timing.first_to_second, None);
+        assert_eq!(timing.first_to_third, ms(20));
+    }
+
+    #[test]
+    fn pressing_back_into_the_bottom_is_aftertouch_not_a_note() {
+        let mut key = TripleContactKey::new();
+        key.first_contact(Instant::from_millis(0));
+        key.second_contact(Instant::from_millis(5));
+        assert!(key.third_contact(Instant::from_millis(10)).is_some());
+        assert!(!key.is_bottomed(), "the strike itself isn't aftertouch");
+        key.third_release();
+        assert_eq!(key.third_contact(Instant::from_millis(300)), None);
+        assert!(key.is_bottomed());
+        key.third_release();
+        assert!(!key.is_bottomed());
+        // Released all the way, the next strike is a new note.
+        key.release();
+        assert_eq!(key.third_contact(Instant::from_millis(400)), None, "no first contact seen");
+        assert!(!key.is_bottomed());
+    }
+
+    #[test]
+    fn calibration_needs_two_different_times() {
+        let mut velocity = DualContactVelocity::DEFAULT;
+        let mut calibration = VelocityCalibration::new();
+        calibration.apply(&mut velocity);
+        calibration.record(ms(10));
+        calibration.record(ms(10));
+        calibration.apply(&mut velocity);
+        assert_eq!(velocity, DualContactVelocity::DEFAULT);
+        for time in [40, 5, 20] {
+            calibration.record(ms(time));
+        }
+        calibration.apply(&mut velocity);
+        assert_eq!((velocity.min_time, velocity.max_time), (ms(5), ms(40)));
+    }
+
+    #[test]
+    fn trim_is_clamped_to_the_velocity_range() {
+        let mut trim = VelocityTrim::NONE;
+        trim.offsets[0] = 20;
+        trim.offsets[1] = -50;
+        assert_eq!(trim.apply(0, 100), 120);
+        assert_eq!(trim.apply(0, 120), 127);
+        assert_eq!(trim.apply(1, 10), 1);
+        assert_eq!(trim.apply(NUM_KEYS, 64), 64, "no key, no trim");
+    }
+
+    #[test]
+    fn trim_calibration_evens_out_the_struck_keys() {
+        let mut trim = VelocityTrim::NONE;
+        trim.offsets[3] = 5;
+        let mut calibration = TrimCalibration::new();
+        for (key, velocity) in [(0, 60), (1, 80), (2, 100)] {
+            calibration.record(key, velocity);
+        }
+        calibration.apply(&mut trim);
+        assert_eq!(trim.offsets[..4], [20, 0, -20, 5], "unstruck keys keep their offset");
+        assert!((0..3).all(|key| trim.apply(key, [60, 80, 100][key]) == 80));
+    }
+
+    #[test]
+    fn humanize_stays_within_the_amount() {
+        let mut rng = XorShift32::new(1234);
+        let (mut lowest, mut highest) = (u8::MAX, 0);
+        for _ in 0..1000 {
+            let velocity = rng.humanize(64, 10);
+            (lowest, highest) = (lowest.min(velocity), highest.max(velocity));
+        }
+        assert!(lowest >= 54 && highest <= 74, "{lowest}..={highest}");
+        assert!(lowest < 60 && highest > 68, "the jitter uses the whole range");
+    }
+
+    #[test]
+    fn humanize_is_clamped_to_the_velocity_range() {
+        let mut rng = XorShift32::new(99);
+        for _ in 0..1000 {
+            assert!((1..=127).contains(&rng.humanize(1, 20)));
+            assert!((1..=127).contains(&rng.humanize(127, 20)));
+        }
+    }
+
+    #[test]
+    fn humanize_of_zero_is_off() {
+        let mut rng = XorShift32::new(5);
+        assert!((1..=127).all(|velocity| rng.humanize(velocity, 0) == velocity));
+    }
+
+    #[test]
+    fn a_zero_seed_still_makes_numbers() {
+        let mut rng = XorShift32::new(0);
+        let (first, second) = (rng.next_u32(), rng.next_u32());
+        assert!(first != 0 && second != 0 && first != second);
+    }
+
+    #[test]
+    fn the_floor_comes_after_trim_and_curve() {
+        let mut trim = VelocityTrim::NONE;
+        trim.offsets[0] = -30;
+        // Trimmed to 10, then the hard curve makes it 1: the floor lifts that, not the 40 played.
+        assert_eq!(shape(40, 0, Some(&trim), Curve::Hard, 20), 20);
+        assert_eq!(shape(40, 0, Some(&trim), Curve::Linear, 1), 10);
+        assert_eq!(shape(40, 0, None, Curve::Linear, 1), 40, "untrimmed while calibrating");
+        assert_eq!(shape(100, 0, Some(&trim), Curve::Soft, 20), 101);
+        assert_eq!(shape(0, 0, None, Curve::Linear, 20), 0);
+    }
+
+    #[test]
+    fn full_velocity_beats_a_zones_fixed_velocity() {
+        assert_eq!(resolve(true, Some(40), 90), 127);
+        assert_eq!(resolve(true, None, 90), 127);
+        assert_eq!(resolve(false, Some(40), 90), 40);
+        assert_eq!(resolve(false, None, 90), 90);
+    }
+}